@@ -0,0 +1,57 @@
+// runs boo code from a rust program: `cargo run --example embed`
+use std::{cell::RefCell, rc::Rc};
+
+use boo_lang::{Engine, NativeParameter, NativeType, Type, Value};
+
+fn main() -> Result<(), boo_lang::Error> {
+    let mut engine = Engine::new();
+
+    // host functions are closures and can keep their own state
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let messages = log.clone();
+    engine.register_function(
+        "log",
+        vec![NativeParameter::required(
+            "message",
+            NativeType::Exact(Type::Str),
+        )],
+        NativeType::Exact(Type::Void),
+        move |args| {
+            messages.borrow_mut().push(args[0].to_string());
+            Ok(Value::Void)
+        },
+    )?;
+
+    // globals set before compiling can be used by the script
+    engine.set_global("limit", Value::from(10.0))?;
+
+    engine.eval(
+        r#"
+        num total = 0;
+        fun add(num a, num b) -> num {
+          return a + b;
+        }
+        for (num i in 0..limit) {
+          total += i;
+        }
+        log("total is {total}");
+        "#,
+    )?;
+
+    let sum = engine.call("add", vec![Value::from(2.0), Value::from(3.0)])?;
+    println!("add(2, 3) = {}", sum);
+    println!(
+        "total = {}",
+        engine.get_global("total").unwrap_or(Value::Void)
+    );
+    println!("log: {:?}", log.borrow());
+
+    // every mistake comes back as diagnostics with a kind, message and span
+    if let Err(error) = engine.eval("add(1, \"two\")") {
+        for diagnostic in &error.diagnostics {
+            println!("{:?} error: {}", diagnostic.kind, diagnostic.message);
+        }
+    }
+
+    Ok(())
+}
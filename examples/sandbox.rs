@@ -0,0 +1,53 @@
+// runs untrusted boo code with limits: `cargo run --example sandbox`
+use std::{thread, time::Duration};
+
+use boo_lang::{DiagnosticKind, Engine, Limits};
+
+fn run(engine: &mut Engine, source: &str) {
+    match engine.eval(source) {
+        Ok(_) => println!("finished"),
+        Err(error) => {
+            for diagnostic in &error.diagnostics {
+                println!("{:?}: {}", diagnostic.kind, diagnostic.message);
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut engine = Engine::new();
+    engine.set_limits(Limits {
+        fuel: Some(1_000_000),
+        max_call_depth: Some(100),
+        max_stack_size: Some(10_000),
+        max_string_bytes: Some(1 << 20),
+    });
+
+    run(&mut engine, "while (true) {}");
+    run(
+        &mut engine,
+        "fun down(num n) -> num { return down(n + 1); } down(0)",
+    );
+    run(&mut engine, "str s = \"boo\"; while (true) { s = s >< s; }");
+    run(&mut engine, "str t = \"boo\".repeat(1000000000);");
+
+    // a limit error leaves the engine usable
+    run(&mut engine, "print(\"still running\")");
+
+    // without fuel a program only stops when the host interrupts it
+    engine.set_limits(Limits::default());
+    let handle = engine.interrupt_handle();
+    let timer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        handle.interrupt();
+    });
+
+    if let Err(error) = engine.eval("while (true) {}") {
+        let interrupted = error
+            .diagnostics
+            .iter()
+            .any(|d| matches!(d.kind, DiagnosticKind::Limit(_)));
+        println!("interrupted: {}", interrupted);
+    }
+    timer.join().expect("timer thread panicked");
+}
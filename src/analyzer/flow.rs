@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+use crate::{
+    lexer::Operator,
+    parser::{ASTNode, ASTNodeKind, Parameter},
+};
+
+// whether execution can never continue after `node`, because every path
+// through it returns, breaks, continues or loops forever
+pub fn terminates(node: &ASTNode) -> bool {
+    match &node.kind {
+        ASTNodeKind::ReturnStatement(_) | ASTNodeKind::Break | ASTNodeKind::Continue => true,
+        ASTNodeKind::IfStatement {
+            then_body,
+            else_body,
+            ..
+        } => {
+            block_terminates(then_body)
+                && else_body
+                    .as_ref()
+                    .is_some_and(|body| block_terminates(body))
+        }
+        // without `_` a value may match none of the arms
+        ASTNodeKind::MatchStatement { arms, .. } => {
+            arms.iter().any(|arm| arm.pattern.is_none())
+                && arms.iter().all(|arm| block_terminates(&arm.body))
+        }
+        // `while (true)` only ends through a `break`
+        ASTNodeKind::WhileStatement { condition, body } => {
+            matches!(condition.kind, ASTNodeKind::BooleanLiteral(true)) && !body.iter().any(breaks)
+        }
+        _ => false,
+    }
+}
+
+pub fn block_terminates(body: &[ASTNode]) -> bool {
+    body.iter().any(terminates)
+}
+
+// whether `node` contains a `break` out of the loop it is in
+fn breaks(node: &ASTNode) -> bool {
+    match &node.kind {
+        ASTNodeKind::Break => true,
+        ASTNodeKind::IfStatement {
+            then_body,
+            else_body,
+            ..
+        } => {
+            then_body.iter().any(breaks)
+                || else_body
+                    .as_ref()
+                    .is_some_and(|body| body.iter().any(breaks))
+        }
+        ASTNodeKind::MatchStatement { arms, .. } => {
+            arms.iter().any(|arm| arm.body.iter().any(breaks))
+        }
+        // a `break` in a nested loop leaves that loop instead
+        _ => false,
+    }
+}
+
+// the variables `=` assigns to anywhere in `body`, outside of nested functions
+pub fn assigned_names(body: &[ASTNode]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit_all(body, Assignments::Body, &mut names);
+    names
+}
+
+// the variables `=` assigns to inside the function literals of `body`, at any
+// depth, a closure can change them whenever it is called
+pub fn closure_assigned_names(body: &[ASTNode]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit_all(body, Assignments::Closures, &mut names);
+    names
+}
+
+// which assignments `collect_assigned` looks for
+#[derive(Clone, Copy, PartialEq)]
+enum Assignments {
+    Body,     // the ones outside of nested functions
+    Closures, // the ones inside function literals, which are searched for
+    Closure,  // every one, inside a function literal
+}
+
+fn visit_all(nodes: &[ASTNode], mode: Assignments, names: &mut HashSet<String>) {
+    for node in nodes {
+        collect_assigned(node, mode, names);
+    }
+}
+
+fn visit_function(
+    parameters: &[Parameter],
+    body: &[ASTNode],
+    mode: Assignments,
+    names: &mut HashSet<String>,
+) {
+    for parameter in parameters {
+        if let Some(default) = &parameter.default {
+            collect_assigned(default, mode, names);
+        }
+    }
+    visit_all(body, mode, names);
+}
+
+fn collect_assigned(node: &ASTNode, mode: Assignments, names: &mut HashSet<String>) {
+    match &node.kind {
+        ASTNodeKind::BinaryOperation { left, op, right } => {
+            if let (Operator::AssignEquals, ASTNodeKind::Identifier(name)) = (op, &left.kind) {
+                if mode != Assignments::Closures {
+                    names.insert(name.clone());
+                }
+            }
+            collect_assigned(left, mode, names);
+            collect_assigned(right, mode, names);
+        }
+        ASTNodeKind::Statement(node)
+        | ASTNodeKind::ReturnStatement(Some(node))
+        | ASTNodeKind::UnaryOperation { operand: node, .. }
+        | ASTNodeKind::FieldAccess { object: node, .. }
+        | ASTNodeKind::VariableDeclaration { value: node, .. } => {
+            collect_assigned(node, mode, names)
+        }
+        ASTNodeKind::Index { object, index } => {
+            collect_assigned(object, mode, names);
+            collect_assigned(index, mode, names);
+        }
+        ASTNodeKind::FunctionCall { arguments, .. } | ASTNodeKind::ArrayLiteral(arguments) => {
+            visit_all(arguments, mode, names)
+        }
+        ASTNodeKind::MethodCall {
+            object: callee,
+            arguments,
+            ..
+        }
+        | ASTNodeKind::Call { callee, arguments } => {
+            collect_assigned(callee, mode, names);
+            visit_all(arguments, mode, names);
+        }
+        ASTNodeKind::IfStatement {
+            condition,
+            then_body,
+            else_body,
+        } => {
+            collect_assigned(condition, mode, names);
+            visit_all(then_body, mode, names);
+            if let Some(else_body) = else_body {
+                visit_all(else_body, mode, names);
+            }
+        }
+        ASTNodeKind::WhileStatement { condition, body } => {
+            collect_assigned(condition, mode, names);
+            visit_all(body, mode, names);
+        }
+        ASTNodeKind::ForStatement { iterable, body, .. } => {
+            collect_assigned(iterable, mode, names);
+            visit_all(body, mode, names);
+        }
+        ASTNodeKind::MatchStatement { subject, arms } => {
+            collect_assigned(subject, mode, names);
+            for arm in arms {
+                visit_all(&arm.body, mode, names);
+            }
+        }
+        ASTNodeKind::Range { start, end, step } => {
+            collect_assigned(start, mode, names);
+            collect_assigned(end, mode, names);
+            if let Some(step) = step {
+                collect_assigned(step, mode, names);
+            }
+        }
+        ASTNodeKind::StructLiteral { fields, .. } => {
+            for (_, value) in fields {
+                collect_assigned(value, mode, names);
+            }
+        }
+        ASTNodeKind::FunctionLiteral {
+            parameters, body, ..
+        } if mode != Assignments::Body => {
+            visit_function(parameters, body, Assignments::Closure, names)
+        }
+        // named functions only matter for the function literals inside them
+        ASTNodeKind::FunctionDeclaration {
+            parameters, body, ..
+        } if mode != Assignments::Body => visit_function(parameters, body, mode, names),
+        ASTNodeKind::StructDeclaration { methods, .. } if mode != Assignments::Body => {
+            visit_all(methods, mode, names)
+        }
+        ASTNodeKind::Test { body, .. } => visit_all(body, mode, names),
+        _ => (),
+    }
+}
+
+// whether running `body` may call boo code, a call of a function `is_native`
+// accepts cannot, every method call may, outside of nested functions
+pub fn calls(body: &[ASTNode], is_native: &dyn Fn(&str) -> bool) -> bool {
+    body.iter().any(|node| node_calls(node, is_native))
+}
+
+fn node_calls(node: &ASTNode, is_native: &dyn Fn(&str) -> bool) -> bool {
+    let call = match &node.kind {
+        ASTNodeKind::FunctionCall { name, .. } => !is_native(name),
+        ASTNodeKind::MethodCall { .. } | ASTNodeKind::Call { .. } => true,
+        _ => false,
+    };
+    call || children(node)
+        .into_iter()
+        .any(|child| node_calls(child, is_native))
+}
+
+// the nodes that run as part of `node`, without the bodies of the functions
+// it declares
+fn children(node: &ASTNode) -> Vec<&ASTNode> {
+    match &node.kind {
+        ASTNodeKind::Program(nodes)
+        | ASTNodeKind::ArrayLiteral(nodes)
+        | ASTNodeKind::FunctionCall {
+            arguments: nodes, ..
+        }
+        | ASTNodeKind::Test { body: nodes, .. } => nodes.iter().collect(),
+        ASTNodeKind::Statement(node)
+        | ASTNodeKind::ReturnStatement(Some(node))
+        | ASTNodeKind::UnaryOperation { operand: node, .. }
+        | ASTNodeKind::FieldAccess { object: node, .. }
+        | ASTNodeKind::VariableDeclaration { value: node, .. } => vec![node],
+        ASTNodeKind::BinaryOperation { left, right, .. }
+        | ASTNodeKind::Index {
+            object: left,
+            index: right,
+        } => vec![left, right],
+        ASTNodeKind::MethodCall {
+            object: first,
+            arguments: rest,
+            ..
+        }
+        | ASTNodeKind::Call {
+            callee: first,
+            arguments: rest,
+        }
+        | ASTNodeKind::WhileStatement {
+            condition: first,
+            body: rest,
+        }
+        | ASTNodeKind::ForStatement {
+            iterable: first,
+            body: rest,
+            ..
+        } => std::iter::once(&**first).chain(rest).collect(),
+        ASTNodeKind::IfStatement {
+            condition,
+            then_body,
+            else_body,
+        } => std::iter::once(&**condition)
+            .chain(then_body)
+            .chain(else_body.iter().flatten())
+            .collect(),
+        ASTNodeKind::MatchStatement { subject, arms } => std::iter::once(&**subject)
+            .chain(
+                arms.iter()
+                    .flat_map(|arm| arm.pattern.iter().chain(&arm.body)),
+            )
+            .collect(),
+        ASTNodeKind::Range { start, end, step } => [&**start, &**end]
+            .into_iter()
+            .chain(step.as_deref())
+            .collect(),
+        ASTNodeKind::StructLiteral { fields, .. } => {
+            fields.iter().map(|(_, value)| value).collect()
+        }
+        _ => Vec::new(),
+    }
+}
@@ -1,3 +1,5 @@
+pub mod flow;
 pub mod typechecker;
 
+pub use flow::block_terminates;
 pub use typechecker::TypeChecker;
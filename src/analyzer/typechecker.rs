@@ -1,67 +1,70 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 use crate::{
+    analyzer::flow::{assigned_names, block_terminates, calls, closure_assigned_names, terminates},
+    diagnostics::{Diagnostic, DiagnosticKind},
     lexer::{Operator, Type},
-    parser::{ASTNode, Parameter},
-    stdlib::stdlib::register_stdlib_types,
+    parser::{method_function_name, ASTNode, ASTNodeKind, MatchArm, Parameter, StructField},
+    stdlib::natives::{NativeSignature, NativeType, Natives, Receiver},
 };
 
+#[derive(Clone)]
 pub struct FunctionSignature {
     parameters: Vec<Parameter>,
     return_type: Option<Type>,
     is_native: bool,
 }
 
+#[derive(Clone)]
+pub struct StructDefinition {
+    fields: Vec<StructField>,
+}
+
+#[derive(Clone)]
 pub struct TypeChecker {
     program: ASTNode,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
     variables: Vec<HashMap<String, Type>>,
+    // optional variables known not to be none at the node being checked, with
+    // the scope they are declared in and the type without the `?`
+    narrowed: HashMap<String, (usize, Type)>,
+    // variables function literals assign to, a call can make them none at any
+    // time so checks never narrow them
+    closure_assigned: HashSet<String>,
     functions: HashMap<String, FunctionSignature>,
-
-    // stdlib
-    native_function_types: HashMap<String, Type>,
-    string_method_types: HashMap<String, Type>,
-    number_method_types: HashMap<String, Type>,
-    boolean_method_types: HashMap<String, Type>,
+    structs: HashMap<String, StructDefinition>,
+    tests: HashSet<String>, // names of the declared tests, `boo test` runs them by name
+    loop_depth: usize,      // loops around the node being checked, inside the current function
+    function: Option<(String, Type)>, // name and return type of the function being checked
+    natives: Rc<Natives>,
 }
 
 impl TypeChecker {
     pub fn new(program: ASTNode) -> Self {
-        let mut checker = Self {
+        Self::with_natives(program, Rc::new(Natives::new()))
+    }
+
+    // checks calls against the natives the program will run with
+    pub fn with_natives(program: ASTNode, natives: Rc<Natives>) -> Self {
+        Self {
             program,
+            errors: Vec::new(),
+            warnings: Vec::new(),
             variables: Vec::new(),
+            narrowed: HashMap::new(),
+            closure_assigned: HashSet::new(),
             functions: HashMap::new(),
+            structs: HashMap::new(),
+            tests: HashSet::new(),
+            loop_depth: 0,
+            function: None,
 
-            // stdlib
-            native_function_types: HashMap::new(),
-            string_method_types: HashMap::new(),
-            number_method_types: HashMap::new(),
-            boolean_method_types: HashMap::new(),
-        };
-
-        // register stdlib
-        register_stdlib_types(&mut checker);
-
-        checker
-    }
-
-    pub fn register_native_function_type(&mut self, name: &str, return_type: Type) {
-        self.native_function_types
-            .insert(name.to_string(), return_type);
-    }
-
-    pub fn register_string_method_type(&mut self, name: &str, return_type: Type) {
-        self.string_method_types
-            .insert(name.to_string(), return_type);
-    }
-
-    pub fn register_number_method_type(&mut self, name: &str, return_type: Type) {
-        self.number_method_types
-            .insert(name.to_string(), return_type);
-    }
-
-    pub fn register_boolean_method_type(&mut self, name: &str, return_type: Type) {
-        self.boolean_method_types
-            .insert(name.to_string(), return_type);
+            natives,
+        }
     }
 
     fn enter_scope(&mut self) {
@@ -84,28 +87,135 @@ impl TypeChecker {
         self.variables.last_mut().unwrap()
     }
 
-    pub fn check_program(&mut self) -> Result<(), String> {
+    pub fn check_program(&mut self) -> Result<(), Vec<Diagnostic>> {
         let program = self.program.clone();
-        match program {
-            ASTNode::Program(nodes) => Ok(for node in nodes {
-                self.check_node(node)?;
-            }),
+        match program.kind {
+            ASTNodeKind::Program(nodes) => {
+                self.closure_assigned.extend(closure_assigned_names(&nodes));
+                self.check_block(nodes);
+                self.take_errors()
+            }
             _ => panic!("Unexpected node type, expected program"),
         }
     }
 
-    fn check_node(&mut self, node: ASTNode) -> Result<Type, String> {
-        match node {
-            ASTNode::Statement(expr) => self.check_node(*expr),
-            ASTNode::ReturnStatement(expr) => self.check_node(*expr),
-            ASTNode::BinaryOperation { left, op, right } => {
+    // checks top level statements against everything checked before them and
+    // returns the type of the last one
+    pub fn check_statements(&mut self, nodes: Vec<ASTNode>) -> Result<Type, Vec<Diagnostic>> {
+        self.closure_assigned.extend(closure_assigned_names(&nodes));
+        let last_type = self.check_block(nodes);
+        self.take_errors().map(|_| last_type)
+    }
+
+    pub fn set_natives(&mut self, natives: Rc<Natives>) {
+        self.natives = natives;
+    }
+
+    // declares a global set by the host, an existing global keeps its type
+    pub fn declare_global(&mut self, name: &str, global_type: Type) -> Result<(), String> {
+        self.check_type(&global_type)?;
+
+        let globals = match self.variables.first_mut() {
+            Some(globals) => globals,
+            None => self.get_current_scope(),
+        };
+
+        match globals.get(name) {
+            Some(existing) if !is_assignable(existing, &global_type) => Err(format!(
+                "Type mismatch: global '{}' has type '{:?}', found '{:?}'",
+                name, existing, global_type
+            )),
+            Some(_) => Ok(()),
+            None => {
+                globals.insert(name.to_string(), global_type);
+                Ok(())
+            }
+        }
+    }
+
+    // checks a call made by the host against the signature of a declared function
+    pub fn check_call(&self, name: &str, arg_types: &[Type]) -> Result<Type, String> {
+        let signature = match self.functions.get(name) {
+            Some(signature) => signature,
+            None => return Err(format!("Unknown function '{}'", name)),
+        };
+
+        let callee = format!("Function '{}'", name);
+        check_argument_count(&callee, &signature.parameters, arg_types.len())?;
+
+        for (param, arg_type) in signature.parameters.iter().zip(arg_types) {
+            if !is_assignable(&param.param_type, arg_type) {
+                return Err(format!(
+                    "Argument '{}' of function '{}' has type mismatch: expected type '{:?}', got '{:?}'",
+                    param.name, name, param.param_type, arg_type
+                ));
+            }
+        }
+
+        Ok(signature.return_type.clone().unwrap_or(Type::Void))
+    }
+
+    // functions declared by boo code, not natives
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    // structs declared so far, source checked later can use them before the
+    // parser sees their declaration
+    pub fn struct_names(&self) -> impl Iterator<Item = &str> {
+        self.structs.keys().map(String::as_str)
+    }
+
+    // warnings found since the last call, they never stop a program from running
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn take_errors(&mut self) -> Result<(), Vec<Diagnostic>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn check_node(&mut self, node: ASTNode) -> Type {
+        // errors are recorded on the node that caused them and checking carries on
+        // with an error type, so every independent mistake gets reported
+        match self.check_node_kind(node.kind) {
+            Ok(node_type) => node_type,
+            Err(message) => {
+                self.errors.push(Diagnostic::new(
+                    DiagnosticKind::Type,
+                    message,
+                    Some(node.span),
+                ));
+                Type::Error
+            }
+        }
+    }
+
+    fn check_node_kind(&mut self, kind: ASTNodeKind) -> Result<Type, String> {
+        match kind {
+            ASTNodeKind::Statement(expr) => Ok(self.check_node(*expr)),
+            ASTNodeKind::ReturnStatement(expr) => self.check_return_statement(expr.map(|e| *e)),
+            ASTNodeKind::Break | ASTNodeKind::Continue if self.loop_depth == 0 => {
+                let keyword = match kind {
+                    ASTNodeKind::Break => "break",
+                    _ => "continue",
+                };
+                Err(format!("'{}' can only be used inside a loop", keyword))
+            }
+            ASTNodeKind::Break | ASTNodeKind::Continue => Ok(Type::Void),
+            ASTNodeKind::Range { .. } => Err("Ranges can only be used in a for loop".to_string()),
+            ASTNodeKind::BinaryOperation { left, op, right } => {
                 self.check_binary_operation(*left, op, *right)
             }
-            ASTNode::UnaryOperation { op, operand } => {
-                let operand_type = self.check_node(*operand)?;
+            ASTNodeKind::UnaryOperation { op, operand } => {
+                let operand_type = self.check_node(*operand);
                 match op {
                     Operator::UnaryMinus => {
-                        if operand_type != Type::Num {
+                        if !is_assignable(&Type::Num, &operand_type) {
                             return Err(format!(
                                 "Type mismatch: expected 'Num', found '{:?}'",
                                 operand_type
@@ -114,7 +224,7 @@ impl TypeChecker {
                         Ok(Type::Num)
                     }
                     Operator::LogicalNot => {
-                        if operand_type != Type::Bool {
+                        if !is_assignable(&Type::Bool, &operand_type) {
                             return Err(format!(
                                 "Type mismatch: expected 'Bool', found '{:?}'",
                                 operand_type
@@ -125,35 +235,73 @@ impl TypeChecker {
                     _ => Err(format!("Unsupported unary operator: {:?}", op)),
                 }
             }
-            ASTNode::FunctionDeclaration {
+            ASTNodeKind::FunctionDeclaration {
                 name,
                 parameters,
                 return_type,
                 body,
             } => self.check_function_declaration(name, parameters, return_type, body),
-            ASTNode::FunctionCall { name, arguments } => self.check_function_call(name, arguments),
-            ASTNode::MethodCall {
+            ASTNodeKind::FunctionCall { name, arguments } => {
+                let native = self.is_native_call(&name);
+                let result = self.check_function_call(name, arguments);
+                if !native {
+                    self.forget_global_narrowings();
+                }
+                result
+            }
+            ASTNodeKind::FunctionLiteral {
+                parameters,
+                return_type,
+                body,
+            } => self.check_function_literal(parameters, return_type, body),
+            ASTNodeKind::Call { callee, arguments } => {
+                let callee_type = self.check_node(*callee);
+                let result = self.check_value_call("Function value", callee_type, arguments);
+                self.forget_global_narrowings();
+                result
+            }
+            ASTNodeKind::MethodCall {
                 object,
                 method,
                 arguments,
             } => self.check_method_call(*object, method, arguments),
-            ASTNode::IfStatement {
+            ASTNodeKind::IfStatement {
                 condition,
                 then_body,
                 else_body,
             } => self.check_if_statement(*condition, then_body, else_body),
-            ASTNode::WhileStatement { condition, body } => {
+            ASTNodeKind::WhileStatement { condition, body } => {
                 self.check_while_statement(*condition, body)
             }
-            ASTNode::VariableDeclaration {
+            ASTNodeKind::MatchStatement { subject, arms } => {
+                self.check_match_statement(*subject, arms)
+            }
+            ASTNodeKind::VariableDeclaration {
                 var_type,
                 name,
                 value,
             } => self.check_variable_declaration(var_type, name, *value),
-            ASTNode::Identifier(name) => self.check_identifier(name),
-            ASTNode::BooleanLiteral(_) => Ok(Type::Bool),
-            ASTNode::NumberLiteral(_) => Ok(Type::Num),
-            ASTNode::StringLiteral(_) => Ok(Type::Str),
+            ASTNodeKind::ForStatement {
+                var_type,
+                name,
+                iterable,
+                body,
+            } => self.check_for_statement(var_type, name, *iterable, body),
+            ASTNodeKind::Index { object, index } => self.check_index(*object, *index),
+            ASTNodeKind::ArrayLiteral(elements) => self.check_array_literal(elements),
+            ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            } => self.check_struct_declaration(name, fields, methods),
+            ASTNodeKind::StructLiteral { name, fields } => self.check_struct_literal(name, fields),
+            ASTNodeKind::Test { name, body } => self.check_test(name, body),
+            ASTNodeKind::FieldAccess { object, field } => self.check_field_access(*object, field),
+            ASTNodeKind::Identifier(name) => self.check_identifier(name),
+            ASTNodeKind::BooleanLiteral(_) => Ok(Type::Bool),
+            ASTNodeKind::NumberLiteral(_) => Ok(Type::Num),
+            ASTNodeKind::StringLiteral(_) => Ok(Type::Str),
+            ASTNodeKind::NoneLiteral => Ok(Type::Optional(Box::new(Type::Void))),
             _ => unimplemented!("Unimplemented node type"),
         }
     }
@@ -164,26 +312,145 @@ impl TypeChecker {
         then_body: Vec<ASTNode>,
         else_body: Option<Vec<ASTNode>>,
     ) -> Result<Type, String> {
-        let condition_type = self.check_node(condition)?;
+        let then_narrowings = self.narrowings(&condition, true);
+        let else_narrowings = self.narrowings(&condition, false);
+        let condition_type = self.check_node(condition);
 
-        if condition_type != Type::Bool {
+        if !is_assignable(&Type::Bool, &condition_type) {
             return Err(format!(
                 "Type mismatch: expected 'Bool', found '{:?}'",
                 condition_type
             ));
         }
 
-        for node in then_body {
-            self.check_node(node)?;
+        let then_terminates = block_terminates(&then_body);
+        let then_narrowings = self.check_narrowed_block(then_body, then_narrowings);
+
+        let else_terminates = else_body
+            .as_ref()
+            .is_some_and(|body| block_terminates(body));
+        let else_narrowings =
+            self.check_narrowed_block(else_body.unwrap_or_default(), else_narrowings);
+
+        // after `if (x == none) { return; }` the rest of the block only runs
+        // when the condition was false
+        if then_terminates {
+            self.narrowed.extend(else_narrowings);
         }
+        if else_terminates {
+            self.narrowed.extend(then_narrowings);
+        }
+
+        Ok(Type::Void)
+    }
 
-        if let Some(else_body) = else_body {
-            for node in else_body {
-                self.check_node(node)?;
+    // checks a block knowing `narrowings` hold at its start and returns the
+    // ones that still hold at its end
+    fn check_narrowed_block(
+        &mut self,
+        body: Vec<ASTNode>,
+        narrowings: Vec<(String, (usize, Type))>,
+    ) -> Vec<(String, (usize, Type))> {
+        let saved = self.narrowed.clone();
+        self.narrowed.extend(narrowings.clone());
+        self.check_block(body);
+
+        let kept = narrowings
+            .into_iter()
+            .filter(|(name, narrowing)| self.narrowed.get(name) == Some(narrowing))
+            .collect();
+        self.restore_narrowed(saved);
+        kept
+    }
+
+    // goes back to the narrowings from before a block, without the ones an
+    // assignment inside it undid
+    fn restore_narrowed(&mut self, mut saved: HashMap<String, (usize, Type)>) {
+        saved.retain(|name, narrowing| self.narrowed.get(name) == Some(narrowing));
+        self.narrowed = saved;
+    }
+
+    // the optional variables `condition` proves are not none when it evaluates
+    // to `when`, like `x` in `x != none && y > 0`
+    fn narrowings(&self, condition: &ASTNode, when: bool) -> Vec<(String, (usize, Type))> {
+        match &condition.kind {
+            ASTNodeKind::BinaryOperation {
+                left,
+                op: op @ (Operator::Equals | Operator::NotEquals),
+                right,
+            } => {
+                let name = match (&left.kind, &right.kind) {
+                    (ASTNodeKind::Identifier(name), ASTNodeKind::NoneLiteral)
+                    | (ASTNodeKind::NoneLiteral, ASTNodeKind::Identifier(name)) => name,
+                    _ => return Vec::new(),
+                };
+                if (*op == Operator::NotEquals) != when || self.closure_assigned.contains(name) {
+                    return Vec::new();
+                }
+
+                let scope = match self
+                    .variables
+                    .iter()
+                    .rposition(|scope| scope.contains_key(name))
+                {
+                    Some(scope) => scope,
+                    None => return Vec::new(),
+                };
+                match &self.variables[scope][name] {
+                    Type::Optional(inner) if **inner != Type::Void => {
+                        vec![(name.clone(), (scope, (**inner).clone()))]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            ASTNodeKind::BinaryOperation {
+                left,
+                op: Operator::LogicalAnd,
+                right,
+            } if when => {
+                let mut narrowings = self.narrowings(left, true);
+                narrowings.extend(self.narrowings(right, true));
+                narrowings
             }
+            ASTNodeKind::BinaryOperation {
+                left,
+                op: Operator::LogicalOr,
+                right,
+            } if !when => {
+                let mut narrowings = self.narrowings(left, false);
+                narrowings.extend(self.narrowings(right, false));
+                narrowings
+            }
+            ASTNodeKind::UnaryOperation {
+                op: Operator::LogicalNot,
+                operand,
+            } => self.narrowings(operand, !when),
+            _ => Vec::new(),
         }
+    }
 
-        Ok(Type::Void)
+    // variables assigned in a loop body may be none again on the next
+    // iteration, like globals when it calls a function
+    fn forget_narrowings(&mut self, body: &[ASTNode]) {
+        let assigned = assigned_names(body);
+        self.narrowed.retain(|name, _| !assigned.contains(name));
+
+        let is_native = |name: &str| self.is_native_call(name);
+        if calls(body, &is_native) {
+            self.forget_global_narrowings();
+        }
+    }
+
+    // whether calling `name` runs a native, natives never assign the variables
+    // of the program
+    fn is_native_call(&self, name: &str) -> bool {
+        !matches!(self.variable_type(name), Some(Type::Function(..)))
+            && self.natives.function(name).is_some()
+    }
+
+    // the function a call runs may assign any global
+    fn forget_global_narrowings(&mut self) {
+        self.narrowed.retain(|_, (scope, _)| *scope != 0);
     }
 
     fn check_while_statement(
@@ -191,51 +458,445 @@ impl TypeChecker {
         condition: ASTNode,
         body: Vec<ASTNode>,
     ) -> Result<Type, String> {
-        let condition_type = self.check_node(condition)?;
+        self.forget_narrowings(&body);
+        self.forget_narrowings(std::slice::from_ref(&condition));
 
-        if condition_type != Type::Bool {
+        let narrowings = self.narrowings(&condition, true);
+        let condition_type = self.check_node(condition);
+
+        if !is_assignable(&Type::Bool, &condition_type) {
             return Err(format!(
                 "Type mismatch: expected 'Bool', found '{:?}'",
                 condition_type
             ));
         }
 
+        self.loop_depth += 1;
+        self.check_narrowed_block(body, narrowings);
+        self.loop_depth -= 1;
+        Ok(Type::Void)
+    }
+
+    fn check_match_statement(
+        &mut self,
+        subject: ASTNode,
+        arms: Vec<MatchArm>,
+    ) -> Result<Type, String> {
+        let subject_type = self.check_node(subject);
+
+        if !matches!(
+            subject_type,
+            Type::Num | Type::Str | Type::Bool | Type::Error
+        ) {
+            return Err(format!(
+                "Cannot match on '{:?}', expected 'Num', 'Str' or 'Bool'",
+                subject_type
+            ));
+        }
+
+        // arm errors point at the pattern and the remaining arms are still checked
+        let mut matched: Vec<ASTNodeKind> = Vec::new();
+        let mut has_wildcard = false;
+
+        for arm in arms {
+            let error = if has_wildcard {
+                Some("Unreachable match arm, '_' above already matches every value".to_string())
+            } else {
+                match arm.pattern {
+                    Some(pattern) => {
+                        let pattern_type = self.check_node(pattern.clone());
+                        if !is_assignable(&subject_type, &pattern_type) {
+                            Some(format!(
+                                "Type mismatch: expected '{:?}', found '{:?}'",
+                                subject_type, pattern_type
+                            ))
+                        } else if matched.iter().any(|kind| same_literal(kind, &pattern.kind)) {
+                            Some(
+                                "Duplicate match arm, this value is already matched above"
+                                    .to_string(),
+                            )
+                        } else {
+                            matched.push(pattern.kind);
+                            None
+                        }
+                    }
+                    None => {
+                        has_wildcard = true;
+                        None
+                    }
+                }
+            };
+
+            if let Some(message) = error {
+                self.errors.push(Diagnostic::new(
+                    DiagnosticKind::Type,
+                    message,
+                    Some(arm.span),
+                ));
+            }
+
+            self.enter_scope();
+            self.check_block(arm.body);
+            self.exit_scope();
+        }
+
+        Ok(Type::Void)
+    }
+
+    fn check_test(&mut self, name: String, body: Vec<ASTNode>) -> Result<Type, String> {
+        self.enter_scope();
+        self.check_block(body);
+        self.exit_scope();
+
+        if !self.tests.insert(name.clone()) {
+            return Err(format!("Test '{}' is already declared", name));
+        }
+
+        Ok(Type::Void)
+    }
+
+    fn check_loop_body(&mut self, body: Vec<ASTNode>) {
+        self.loop_depth += 1;
+        self.check_block(body);
+        self.loop_depth -= 1;
+    }
+
+    // checks the statements of a block in order, warning once about the
+    // statements after one that never lets execution continue
+    fn check_block(&mut self, body: Vec<ASTNode>) -> Type {
+        let narrowed = self.narrowed.clone();
+        let mut last_type = Type::Void;
+        let mut terminated = false;
+        let mut reported = false;
         for node in body {
-            self.check_node(node)?;
+            if terminated && !reported {
+                self.warnings.push(Diagnostic::warning(
+                    DiagnosticKind::Type,
+                    "Unreachable code".to_string(),
+                    Some(node.span),
+                ));
+                reported = true;
+            }
+            terminated = terminated || terminates(&node);
+            last_type = self.check_node(node);
+        }
+        self.restore_narrowed(narrowed);
+        last_type
+    }
+
+    // makes sure every struct named by the type has been declared
+    fn check_type(&self, t: &Type) -> Result<(), String> {
+        match t {
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                Err(format!("Unknown type '{}'", name))
+            }
+            Type::Array(element_type) | Type::Optional(element_type) => {
+                self.check_type(element_type)
+            }
+            Type::Function(parameters, return_type) => {
+                for parameter in parameters {
+                    self.check_type(parameter)?;
+                }
+                self.check_type(return_type)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_struct_declaration(
+        &mut self,
+        name: String,
+        fields: Vec<StructField>,
+        methods: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        if self.structs.contains_key(&name) {
+            return Err(format!("Struct '{}' already declared", name));
+        }
+
+        // register the struct first so fields and methods can refer to it
+        self.structs.insert(
+            name.clone(),
+            StructDefinition {
+                fields: fields.clone(),
+            },
+        );
+
+        for (i, field) in fields.iter().enumerate() {
+            self.check_type(&field.field_type)?;
+
+            if fields[..i].iter().any(|f| f.name == field.name) {
+                return Err(format!(
+                    "Field '{}' already declared in struct '{}'",
+                    field.name, name
+                ));
+            }
+        }
+
+        for method in methods {
+            if let ASTNodeKind::FunctionDeclaration {
+                name: method_name, ..
+            } = &method.kind
+            {
+                if fields.iter().any(|f| &f.name == method_name) {
+                    return Err(format!(
+                        "Method '{}' conflicts with a field of struct '{}'",
+                        method_name, name
+                    ));
+                }
+
+                if self
+                    .functions
+                    .contains_key(&method_function_name(&name, method_name))
+                {
+                    return Err(format!(
+                        "Method '{}' already declared in struct '{}'",
+                        method_name, name
+                    ));
+                }
+            }
+
+            self.check_node(method.into_method_function(&name));
         }
 
         Ok(Type::Void)
     }
 
+    fn check_struct_literal(
+        &mut self,
+        name: String,
+        values: Vec<(String, ASTNode)>,
+    ) -> Result<Type, String> {
+        let fields = match self.structs.get(&name) {
+            Some(definition) => definition.fields.clone(),
+            None => return Err(format!("Unknown type '{}'", name)),
+        };
+
+        for (i, (field_name, value)) in values.iter().enumerate() {
+            let field = match fields.iter().find(|f| &f.name == field_name) {
+                Some(field) => field,
+                None => {
+                    return Err(format!(
+                        "Struct '{}' has no field named '{}'",
+                        name, field_name
+                    ))
+                }
+            };
+
+            if values[..i].iter().any(|(other, _)| other == field_name) {
+                return Err(format!("Field '{}' specified more than once", field_name));
+            }
+
+            let value_type = self.check_node(value.clone());
+            if !is_assignable(&field.field_type, &value_type) {
+                return Err(format!(
+                    "Field '{}' of struct '{}' has type mismatch: expected type '{:?}', got '{:?}'",
+                    field_name, name, field.field_type, value_type
+                ));
+            }
+        }
+
+        if let Some(missing) = fields
+            .iter()
+            .find(|f| !values.iter().any(|(field_name, _)| field_name == &f.name))
+        {
+            return Err(format!(
+                "Missing field '{}' in initializer of struct '{}'",
+                missing.name, name
+            ));
+        }
+
+        Ok(Type::Struct(name))
+    }
+
+    fn check_field_access(&mut self, object: ASTNode, field: String) -> Result<Type, String> {
+        let object_type = self.check_node(object);
+
+        let struct_name = match &object_type {
+            Type::Struct(name) => name,
+            Type::Error => return Ok(Type::Error),
+            Type::Optional(_) => return Err(may_be_none(&object_type)),
+            _ => {
+                return Err(format!(
+                    "Type '{:?}' has no field named '{}'",
+                    object_type, field
+                ))
+            }
+        };
+
+        self.structs
+            .get(struct_name)
+            .and_then(|definition| definition.fields.iter().find(|f| f.name == field))
+            .map(|f| f.field_type.clone())
+            .ok_or_else(|| format!("Struct '{}' has no field named '{}'", struct_name, field))
+    }
+
+    fn check_for_statement(
+        &mut self,
+        var_type: Type,
+        name: String,
+        iterable: ASTNode,
+        body: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        self.check_type(&var_type)?;
+
+        let element_type = match iterable.kind {
+            ASTNodeKind::Range { start, end, step } => self.check_range(*start, *end, step)?,
+            _ => self.check_iterable(iterable)?,
+        };
+
+        if !is_assignable(&var_type, &element_type) {
+            return Err(format!(
+                "Type mismatch: expected '{:?}', found '{:?}'",
+                var_type, element_type
+            ));
+        }
+
+        // the loop variable lives in its own scope
+        self.forget_narrowings(&body);
+        self.enter_scope();
+        self.get_current_scope().insert(name, var_type);
+        self.check_loop_body(body);
+        self.exit_scope();
+
+        Ok(Type::Void)
+    }
+
+    // returns the type of the elements the loop goes through
+    fn check_iterable(&mut self, iterable: ASTNode) -> Result<Type, String> {
+        let iterable_type = self.check_node(iterable);
+
+        match iterable_type {
+            Type::Array(element_type) => Ok(*element_type),
+            Type::Error => Ok(Type::Error),
+            _ => Err(format!(
+                "Type mismatch: expected 'Array', found '{:?}'",
+                iterable_type
+            )),
+        }
+    }
+
+    fn check_range(
+        &mut self,
+        start: ASTNode,
+        end: ASTNode,
+        step: Option<Box<ASTNode>>,
+    ) -> Result<Type, String> {
+        let mut bounds = vec![start, end];
+        if let Some(step) = step {
+            // a zero step would never reach the end
+            if let ASTNodeKind::NumberLiteral(value) = step.kind {
+                if value == 0.0 {
+                    return Err("Range step cannot be zero".to_string());
+                }
+            }
+            bounds.push(*step);
+        }
+
+        for bound in bounds {
+            let bound_type = self.check_node(bound);
+            if !is_assignable(&Type::Num, &bound_type) {
+                return Err(format!(
+                    "Type mismatch: expected 'Num', found '{:?}'",
+                    bound_type
+                ));
+            }
+        }
+
+        Ok(Type::Num)
+    }
+
+    fn check_index(&mut self, object: ASTNode, index: ASTNode) -> Result<Type, String> {
+        let object_type = self.check_node(object);
+        let index_type = self.check_node(index);
+
+        if !is_assignable(&Type::Num, &index_type) {
+            return Err(format!(
+                "Array index must be 'Num', found '{:?}'",
+                index_type
+            ));
+        }
+
+        match object_type {
+            Type::Array(element_type) => Ok(*element_type),
+            Type::Error => Ok(Type::Error),
+            Type::Optional(_) => Err(may_be_none(&object_type)),
+            _ => Err(format!("Cannot index into type '{:?}'", object_type)),
+        }
+    }
+
+    fn check_array_literal(&mut self, elements: Vec<ASTNode>) -> Result<Type, String> {
+        // an empty literal has no element type yet, it is assignable to any array
+        let mut element_type = Type::Void;
+
+        for (i, element) in elements.into_iter().enumerate() {
+            let current_type = self.check_node(element);
+
+            if current_type == Type::Void {
+                return Err("Array elements cannot be void".to_string());
+            }
+
+            if i == 0 {
+                element_type = current_type;
+            } else {
+                element_type = common_type(&element_type, &current_type).ok_or_else(|| {
+                    format!(
+                        "Array element type mismatch: expected '{:?}', found '{:?}'",
+                        element_type, current_type
+                    )
+                })?;
+            }
+        }
+
+        Ok(Type::Array(Box::new(element_type)))
+    }
+
     fn check_binary_operation(
         &mut self,
         left: ASTNode,
         op: Operator,
         right: ASTNode,
     ) -> Result<Type, String> {
-        if let ASTNode::Identifier(name) = &left {
-            self.verify_optional_parameter_usage(&name)?;
-        }
-
-        if let ASTNode::Identifier(name) = &right {
-            self.verify_optional_parameter_usage(&name)?;
-        }
+        // a narrowed variable can be assigned anything its declared type allows
+        let assigned = match (&op, &left.kind) {
+            (Operator::AssignEquals, ASTNodeKind::Identifier(name)) => self
+                .variable_type(name)
+                .cloned()
+                .map(|var_type| (name.clone(), var_type)),
+            _ => None,
+        };
+        let narrowings = self.narrowings(&left, op == Operator::LogicalAnd);
 
-        let left_type = self.check_node(left)?;
+        let left_type = match &assigned {
+            Some((_, var_type)) => var_type.clone(),
+            None => self.check_node(left),
+        };
 
         let right_type = match op {
             Operator::LogicalAnd | Operator::LogicalOr => {
-                if left_type != Type::Bool {
+                if !is_assignable(&Type::Bool, &left_type) {
                     return Err(format!(
                         "Type mismatch: expected 'Bool', found '{:?}'",
                         left_type
                     ));
                 }
-                self.check_node(right)?
+
+                // the right side only runs when the left side did not decide the result
+                let narrowed = self.narrowed.clone();
+                self.narrowed.extend(narrowings);
+                let right_type = self.check_node(right);
+                self.restore_narrowed(narrowed);
+                right_type
             }
-            _ => self.check_node(right)?,
+            _ => self.check_node(right),
         };
 
+        // a value that may be none undoes the narrowing of the variable
+        if let Some((name, _)) = &assigned {
+            if matches!(right_type, Type::Optional(_) | Type::Error) {
+                self.narrowed.remove(name);
+            }
+        }
+
         match op {
             Operator::Plus
             | Operator::Minus
@@ -243,14 +904,14 @@ impl TypeChecker {
             | Operator::Divide
             | Operator::Power
             | Operator::Modulo => {
-                if left_type != Type::Num {
+                if !is_assignable(&Type::Num, &left_type) {
                     return Err(format!(
                         "Type mismatch: expected 'Num', found '{:?}'",
                         left_type
                     ));
                 }
 
-                if right_type != Type::Num {
+                if !is_assignable(&Type::Num, &right_type) {
                     return Err(format!(
                         "Type mismatch: expected 'Num', found '{:?}'",
                         right_type
@@ -260,7 +921,7 @@ impl TypeChecker {
                 Ok(Type::Num)
             }
             Operator::LogicalAnd | Operator::LogicalOr => {
-                if right_type != Type::Bool {
+                if !is_assignable(&Type::Bool, &right_type) {
                     return Err(format!(
                         "Type mismatch: expected 'Bool', found '{:?}'",
                         right_type
@@ -277,7 +938,9 @@ impl TypeChecker {
                 Ok(Type::Str)
             }
             Operator::Equals | Operator::NotEquals => {
-                if left_type != right_type {
+                if !is_assignable(&left_type, &right_type)
+                    && !is_assignable(&right_type, &left_type)
+                {
                     return Err(format!(
                         "Type mismatch: expected '{:?}', found '{:?}'",
                         left_type, right_type
@@ -296,7 +959,8 @@ impl TypeChecker {
             | Operator::SubAssign
             | Operator::ModAssign
             | Operator::PowAssign => {
-                if left_type != Type::Num || right_type != Type::Num {
+                if !is_assignable(&Type::Num, &left_type) || !is_assignable(&Type::Num, &right_type)
+                {
                     return Err(format!(
                         "Type mismatch: expected 'Num' and 'Num', found '{:?}' and '{:?}'",
                         left_type, right_type
@@ -306,7 +970,7 @@ impl TypeChecker {
                 Ok(Type::Bool)
             }
             Operator::AssignEquals => {
-                if left_type != right_type {
+                if !is_assignable(&left_type, &right_type) {
                     return Err(format!(
                         "Type mismatch: expected '{:?}', found '{:?}'",
                         left_type, right_type
@@ -315,42 +979,41 @@ impl TypeChecker {
 
                 Ok(Type::Void)
             }
+            Operator::Coalesce => match left_type {
+                // `none ?? value`
+                Type::Optional(inner) if *inner == Type::Void => Ok(right_type),
+                Type::Optional(inner) if is_assignable(&inner, &right_type) => Ok(*inner),
+                // the default may be none as well
+                Type::Optional(inner)
+                    if is_assignable(&Type::Optional(inner.clone()), &right_type) =>
+                {
+                    Ok(Type::Optional(inner))
+                }
+                Type::Optional(inner) => Err(format!(
+                    "Type mismatch: expected '{:?}', found '{:?}'",
+                    inner, right_type
+                )),
+                Type::Error => Ok(Type::Error),
+                _ => Err(format!(
+                    "Left side of '??' has type '{:?}', which is never none",
+                    left_type
+                )),
+            },
             Operator::UnaryMinus | Operator::LogicalNot => {
                 unreachable!("{:?} is not a binary operator", op)
             }
         }
     }
 
-    fn verify_optional_parameter_usage(&self, name: &str) -> Result<(), String> {
-        for signature in self.functions.values() {
-            if let Some(param) = signature
-                .parameters
-                .iter()
-                .find(|p| p.name == name && p.optional)
-            {
-                return Err(format!(
-                    "Warning: Operation uses optional parameter '{}' without null check",
-                    param.name
-                ));
-            }
-        }
-        Ok(())
-    }
-
     fn check_variable_declaration(
         &mut self,
         var_type: Type,
         name: String,
         value: ASTNode,
     ) -> Result<Type, String> {
-        let value_type = self.check_node(value)?;
+        self.check_type(&var_type)?;
 
-        if value_type != var_type {
-            return Err(format!(
-                "Type mismatch: expected '{:?}', found '{:?}'",
-                var_type, value_type
-            ));
-        }
+        let value_type = self.check_node(value);
 
         // get the current scope
         let current_scope = self.get_current_scope();
@@ -363,16 +1026,60 @@ impl TypeChecker {
             ));
         }
 
-        current_scope.insert(name, var_type);
+        // the variable is declared even when its value is wrong, so later uses of
+        // it are not reported as unknown identifiers
+        current_scope.insert(name, var_type.clone());
+
+        if !is_assignable(&var_type, &value_type) {
+            return Err(format!(
+                "Type mismatch: expected '{:?}', found '{:?}'",
+                var_type, value_type
+            ));
+        }
+
         Ok(Type::Void)
     }
 
-    fn check_identifier(&mut self, name: String) -> Result<Type, String> {
-        for scope in self.variables.iter().rev() {
-            if let Some(var_type) = scope.get(&name) {
-                return Ok(*var_type);
+    fn variable_type(&self, name: &str) -> Option<&Type> {
+        self.variables
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+    }
+
+    // the type of a variable where it is used, without the `?` when a check
+    // above proved it is not none
+    fn narrowed_type(&self, name: &str) -> Option<&Type> {
+        let scope = self
+            .variables
+            .iter()
+            .rposition(|scope| scope.contains_key(name))?;
+
+        match self.narrowed.get(name) {
+            Some((narrowed_scope, narrowed_type)) if *narrowed_scope == scope => {
+                Some(narrowed_type)
             }
+            _ => self.variables[scope].get(name),
+        }
+    }
+
+    fn check_identifier(&mut self, name: String) -> Result<Type, String> {
+        if let Some(var_type) = self.narrowed_type(&name) {
+            return Ok(var_type.clone());
+        }
+
+        // a named function used as a value
+        if let Some(signature) = self.functions.get(&name) {
+            return Ok(function_type(&signature.parameters, &signature.return_type));
         }
+
+        if self.natives.function(&name).is_some() {
+            return Err(format!(
+                "Built-in function '{}' cannot be used as a value",
+                name
+            ));
+        }
+
         Err(format!("Unknown identifier '{}'", name))
     }
 
@@ -383,47 +1090,141 @@ impl TypeChecker {
         return_type: Option<Type>,
         body: Vec<ASTNode>,
     ) -> Result<Type, String> {
-        let param_types: Vec<(String, Type)> = parameters
-            .iter()
-            .map(|p| (p.name.clone(), p.param_type.clone()))
-            .collect();
+        // calls resolve to natives first, a function with the same name could never be called
+        if self.natives.function(&name).is_some() {
+            return Err(format!(
+                "Function '{}' is a built-in function and cannot be redeclared",
+                name
+            ));
+        }
+
+        check_parameters(&parameters)?;
+        for param in &parameters {
+            self.check_type(&param.param_type)?;
+        }
+
+        if let Some(return_type) = &return_type {
+            self.check_type(return_type)?;
+        }
 
         self.functions.insert(
             name.to_string(),
             FunctionSignature {
-                parameters,
-                return_type,
+                parameters: parameters.clone(),
+                return_type: return_type.clone(),
                 is_native: false,
             },
         );
 
-        // enter a new scope for function body
-        self.enter_scope();
+        // a function that returns a value must not reach the end of its body
+        let return_type = return_type.unwrap_or(Type::Void);
+        if !self.check_function_body(&name, parameters, return_type.clone(), body) {
+            return Err(format!(
+                "Function '{}' does not return a value on every path, expected type '{:?}'",
+                name, return_type
+            ));
+        }
+
+        Ok(Type::Void)
+    }
 
-        // add parameters to the current scope
-        for (param_name, param_type) in param_types {
-            self.get_current_scope().insert(param_name, param_type);
+    fn check_function_literal(
+        &mut self,
+        parameters: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        check_parameters(&parameters)?;
+        for param in &parameters {
+            self.check_type(&param.param_type)?;
         }
 
-        // check function body
-        let mut last_type = Type::Void;
-        for stmt in body {
-            last_type = self.check_node(stmt)?;
+        if let Some(return_type) = &return_type {
+            self.check_type(return_type)?;
         }
 
-        // verify return type matches declaration
-        if let Some(expected_return_type) = return_type {
-            if last_type != expected_return_type {
+        let literal_type = function_type(&parameters, &return_type);
+        let return_type = return_type.unwrap_or(Type::Void);
+        if !self.check_function_body("anonymous", parameters, return_type.clone(), body) {
+            return Err(format!(
+                "Function literal does not return a value on every path, expected type '{:?}'",
+                return_type
+            ));
+        }
+
+        Ok(literal_type)
+    }
+
+    // checks a body with its parameters in scope and returns whether it ends
+    // with a value on every path, the variables around it stay visible
+    fn check_function_body(
+        &mut self,
+        name: &str,
+        parameters: Vec<Parameter>,
+        return_type: Type,
+        body: Vec<ASTNode>,
+    ) -> bool {
+        // enter a new scope for function body, the body may run long after the
+        // checks around it
+        self.enter_scope();
+        let narrowed = std::mem::take(&mut self.narrowed);
+
+        // add parameters to the current scope, a default value sees the
+        // parameters before it
+        for param in parameters {
+            if let Some(default) = param.default {
+                let span = default.span;
+                let default_type = self.check_node(default);
+                if !is_assignable(&param.param_type, &default_type) {
+                    self.errors.push(Diagnostic::new(
+                        DiagnosticKind::Type,
+                        format!(
+                            "Default value of parameter '{}' has type mismatch: expected type '{:?}', got '{:?}'",
+                            param.name, param.param_type, default_type
+                        ),
+                        Some(span),
+                    ));
+                }
+            }
+            self.get_current_scope()
+                .insert(param.name, param.param_type);
+        }
+
+        // check function body, loops around the declaration do not reach into it
+        let returns = block_terminates(&body);
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        let function = self
+            .function
+            .replace((name.to_string(), return_type.clone()));
+        self.check_block(body);
+        self.function = function;
+        self.loop_depth = loop_depth;
+
+        // exit the scope
+        self.narrowed = narrowed;
+        self.exit_scope();
+
+        return_type == Type::Void || returns
+    }
+
+    // a bare `return` returns void
+    fn check_return_statement(&mut self, value: Option<ASTNode>) -> Result<Type, String> {
+        let value_type = match value {
+            Some(value) => self.check_node(value),
+            None => Type::Void,
+        };
+
+        // outside of a function `return` ends the program
+        if let Some((name, return_type)) = &self.function {
+            if !is_assignable(return_type, &value_type) {
                 return Err(format!(
                     "Function '{}' return type mismatch, expected type '{:?}', got '{:?}'",
-                    name, expected_return_type, last_type
+                    name, return_type, value_type
                 ));
             }
         }
 
-        // exit the scope
-        self.exit_scope();
-        Ok(Type::Void)
+        Ok(value_type)
     }
 
     fn check_function_call(
@@ -431,22 +1232,15 @@ impl TypeChecker {
         name: String,
         arguments: Vec<ASTNode>,
     ) -> Result<Type, String> {
-        // first check for native functions
-        if self.native_function_types.contains_key(&name) {
-            for arg in &arguments {
-                let arg_type = self.check_node(arg.clone())?;
-                if arg_type == Type::Void {
-                    return Err(format!(
-                        "Native function '{}' requires a non-void argument",
-                        name
-                    ));
-                }
-            }
+        // a variable holding a function hides functions with the same name
+        if let Some(var_type @ Type::Function(..)) = self.variable_type(&name) {
+            let var_type = var_type.clone();
+            return self.check_value_call(&format!("Function '{}'", name), var_type, arguments);
+        }
 
-            return match self.native_function_types.get(&name) {
-                Some(return_type) => Ok(*return_type),
-                None => Ok(Type::Void),
-            };
+        // first check for native functions
+        if let Some(native) = self.natives.function(&name).cloned() {
+            return self.check_native_call("Function", &name, &native.signature, None, arguments);
         }
 
         let signature = match self.functions.get(&name) {
@@ -458,27 +1252,14 @@ impl TypeChecker {
             _ => return Err(format!("Unknown function '{}'", name)),
         };
 
-        // check argument count (and for optional arguments)
-        let required_parameters_count = signature
-            .parameters
-            .iter()
-            .filter(|p| p.optional == false)
-            .count();
-
-        if arguments.len() < required_parameters_count {
-            return Err(format!(
-                "Function '{}' expects at least {} arguments, got {}",
-                name,
-                required_parameters_count,
-                arguments.len()
-            ));
-        }
+        let callee = format!("Function '{}'", name);
+        check_argument_count(&callee, &signature.parameters, arguments.len())?;
 
         // check argument types
         for (i, arg) in arguments.iter().enumerate() {
-            let arg_type = self.check_node(arg.clone())?;
+            let arg_type = self.check_node(arg.clone());
             let param_type = &signature.parameters[i].param_type;
-            if arg_type != *param_type {
+            if !is_assignable(param_type, &arg_type) {
                 return Err(format!(
                     "Argument '{}' of function '{}' has type mismatch: expected type '{:?}', got '{:?}'",
                     &signature.parameters[i].name, name, param_type, arg_type
@@ -486,57 +1267,402 @@ impl TypeChecker {
             }
         }
 
-        Ok(signature.return_type.clone().unwrap_or(Type::Void))
+        Ok(signature.return_type.unwrap_or(Type::Void))
     }
 
-    fn check_method_call(
+    // checks a call through a function value, every parameter is required
+    fn check_value_call(
         &mut self,
-        object: ASTNode,
-        method_name: String,
+        description: &str,
+        callee_type: Type,
         arguments: Vec<ASTNode>,
     ) -> Result<Type, String> {
-        let object_type = self.check_node(object)?;
-
-        // check if the method exists for this type
-        let method_exists = match object_type {
-            Type::Str => self.string_method_types.contains_key(&method_name),
-            Type::Num => self.number_method_types.contains_key(&method_name),
-            Type::Bool => self.boolean_method_types.contains_key(&method_name),
-            _ => false,
+        let (parameters, return_type) = match callee_type {
+            Type::Function(parameters, return_type) => (parameters, *return_type),
+            Type::Error => {
+                for arg in arguments {
+                    self.check_node(arg);
+                }
+                return Ok(Type::Error);
+            }
+            _ => return Err(format!("Cannot call a value of type '{:?}'", callee_type)),
         };
 
-        if !method_exists {
+        if arguments.len() != parameters.len() {
             return Err(format!(
-                "Method '{}' does not exist for type '{:?}'",
-                method_name, object_type
+                "{} expects {} arguments, got {}",
+                description,
+                parameters.len(),
+                arguments.len()
             ));
         }
 
-        // check arguments
-        for arg in arguments {
-            let arg_type = self.check_node(arg.clone())?;
-            if arg_type == Type::Void {
+        for (i, (arg, param_type)) in arguments.into_iter().zip(parameters).enumerate() {
+            let arg_type = self.check_node(arg);
+            if !is_assignable(&param_type, &arg_type) {
                 return Err(format!(
-                    "Method '{}' requires a non-void argument",
-                    method_name
+                    "Argument {} of {} has type mismatch: expected type '{:?}', got '{:?}'",
+                    i + 1,
+                    description.to_lowercase(),
+                    param_type,
+                    arg_type
                 ));
             }
         }
 
-        match object_type {
-            Type::Str => match self.string_method_types.get(&method_name) {
-                Some(return_type) => Ok(*return_type),
-                None => Ok(Type::Void),
+        Ok(return_type)
+    }
+
+    // `map`, `filter` and `reduce` take a function, which natives cannot call
+    fn check_array_function_call(
+        &mut self,
+        element_type: Type,
+        method_name: &str,
+        arguments: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        let expected = if method_name == "reduce" { 2 } else { 1 };
+        if arguments.len() != expected {
+            return Err(format!(
+                "Method '{}' expects {} arguments, got {}",
+                method_name,
+                expected,
+                arguments.len()
+            ));
+        }
+
+        let mut arguments = arguments.into_iter();
+        let function_type = self.check_node(arguments.next().expect("count was checked"));
+        let (parameters, return_type) = match &function_type {
+            Type::Function(parameters, return_type) => (parameters.clone(), *return_type.clone()),
+            Type::Error => return Ok(Type::Error),
+            _ => {
+                return Err(format!(
+                    "Argument 'function' of method '{}' has type mismatch: expected a function, got '{:?}'",
+                    method_name, function_type
+                ))
+            }
+        };
+
+        // elements of an empty literal (`[]`) have no type yet
+        let takes = |parameter: &Type| {
+            element_type == Type::Void || is_assignable(parameter, &element_type)
+        };
+        let mismatch = |expected: String| {
+            Err(format!(
+                "Argument 'function' of method '{}' has type mismatch: expected type '{}', got '{:?}'",
+                method_name, expected, function_type
+            ))
+        };
+
+        match method_name {
+            "map" => match parameters.as_slice() {
+                [parameter] if takes(parameter) && return_type != Type::Void => {
+                    Ok(Type::Array(Box::new(return_type)))
+                }
+                _ => mismatch(format!("fun({:?}) -> T", element_type)),
             },
-            Type::Num => match self.number_method_types.get(&method_name) {
-                Some(return_type) => Ok(*return_type),
-                None => Ok(Type::Void),
+            "filter" => match parameters.as_slice() {
+                [parameter] if takes(parameter) && return_type == Type::Bool => {
+                    Ok(Type::Array(Box::new(element_type)))
+                }
+                _ => mismatch(format!("fun({:?}) -> Bool", element_type)),
             },
-            Type::Bool => match self.boolean_method_types.get(&method_name) {
-                Some(return_type) => Ok(*return_type),
-                None => Ok(Type::Void),
+            _ => match parameters.as_slice() {
+                [accumulator, parameter]
+                    if takes(parameter) && is_assignable(accumulator, &return_type) =>
+                {
+                    let initial_type =
+                        self.check_node(arguments.next().expect("count was checked"));
+                    if !is_assignable(accumulator, &initial_type) {
+                        return Err(format!(
+                            "Argument 'initial' of method 'reduce' has type mismatch: expected type '{:?}', got '{:?}'",
+                            accumulator, initial_type
+                        ));
+                    }
+                    Ok(return_type)
+                }
+                _ => mismatch(format!("fun(T, {:?}) -> T", element_type)),
             },
-            _ => Ok(Type::Void),
         }
     }
+
+    // checks a call to a native function or method, `element_type` is the
+    // element type of the array a method is called on
+    fn check_native_call(
+        &mut self,
+        kind: &str,
+        name: &str,
+        signature: &NativeSignature,
+        element_type: Option<Type>,
+        arguments: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        let required = signature.required_count();
+        let count = arguments.len();
+
+        match signature.max_count() {
+            Some(max) if max == required && count != max => {
+                return Err(format!(
+                    "{} '{}' expects {} arguments, got {}",
+                    kind, name, max, count
+                ))
+            }
+            Some(max) if count > max => {
+                return Err(format!(
+                    "{} '{}' expects at most {} arguments, got {}",
+                    kind, name, max, count
+                ))
+            }
+            _ if count < required => {
+                return Err(format!(
+                    "{} '{}' expects at least {} arguments, got {}",
+                    kind, name, required, count
+                ))
+            }
+            _ => (),
+        }
+
+        for (i, arg) in arguments.into_iter().enumerate() {
+            let arg_type = self.check_node(arg);
+            let param = signature
+                .parameter(i)
+                .expect("argument count was checked against the signature");
+
+            match native_type(&param.param_type, &element_type) {
+                Some(param_type) if !is_assignable(&param_type, &arg_type) => {
+                    return Err(format!(
+                        "Argument '{}' of {} '{}' has type mismatch: expected type '{:?}', got '{:?}'",
+                        param.name,
+                        kind.to_lowercase(),
+                        name,
+                        param_type,
+                        arg_type
+                    ));
+                }
+                None if arg_type == Type::Void => {
+                    return Err(format!(
+                        "Argument '{}' of {} '{}' requires a non-void value",
+                        param.name,
+                        kind.to_lowercase(),
+                        name
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        // natives never return `Any`, it only describes parameters
+        Ok(native_type(&signature.return_type, &element_type).unwrap_or(Type::Void))
+    }
+
+    fn check_struct_method_call(
+        &mut self,
+        struct_name: String,
+        method_name: String,
+        arguments: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        let (parameters, return_type) = match self
+            .functions
+            .get(&method_function_name(&struct_name, &method_name))
+        {
+            // skip `self`, it is bound to the object
+            Some(signature) => (
+                signature.parameters[1..].to_vec(),
+                signature.return_type.clone(),
+            ),
+            None => {
+                return Err(format!(
+                    "Method '{}' does not exist for type '{:?}'",
+                    method_name,
+                    Type::Struct(struct_name)
+                ))
+            }
+        };
+
+        let callee = format!("Method '{}'", method_name);
+        check_argument_count(&callee, &parameters, arguments.len())?;
+
+        for (arg, param) in arguments.into_iter().zip(parameters) {
+            let arg_type = self.check_node(arg);
+            if !is_assignable(&param.param_type, &arg_type) {
+                return Err(format!(
+                    "Argument '{}' of method '{}' has type mismatch: expected type '{:?}', got '{:?}'",
+                    param.name, method_name, param.param_type, arg_type
+                ));
+            }
+        }
+
+        Ok(return_type.unwrap_or(Type::Void))
+    }
+
+    fn check_method_call(
+        &mut self,
+        object: ASTNode,
+        method_name: String,
+        arguments: Vec<ASTNode>,
+    ) -> Result<Type, String> {
+        let object_type = self.check_node(object);
+
+        if object_type == Type::Error {
+            // still look at the arguments so their own mistakes are reported
+            for arg in arguments {
+                self.check_node(arg);
+            }
+            return Ok(Type::Error);
+        }
+
+        if let Type::Struct(struct_name) = object_type {
+            let result = self.check_struct_method_call(struct_name, method_name, arguments);
+            self.forget_global_narrowings();
+            return result;
+        }
+
+        if let Type::Optional(_) = object_type {
+            return Err(may_be_none(&object_type));
+        }
+
+        let native = Receiver::of_type(&object_type)
+            .and_then(|receiver| self.natives.method(receiver, &method_name))
+            .cloned();
+
+        let native = match native {
+            Some(native) => native,
+            None => {
+                return Err(format!(
+                    "Method '{}' does not exist for type '{:?}'",
+                    method_name, object_type
+                ))
+            }
+        };
+
+        let element_type = match object_type {
+            Type::Array(element_type) => Some(*element_type),
+            _ => None,
+        };
+
+        if let (true, Some(element_type)) = (native.calls_back, &element_type) {
+            let element_type = element_type.clone();
+            let result = self.check_array_function_call(element_type, &method_name, arguments);
+            self.forget_global_narrowings();
+            return result;
+        }
+
+        self.check_native_call(
+            "Method",
+            &method_name,
+            &native.signature,
+            element_type,
+            arguments,
+        )
+    }
+}
+
+// the concrete type of a native parameter or return type, `None` for `Any`
+fn native_type(native_type: &NativeType, element_type: &Option<Type>) -> Option<Type> {
+    match native_type {
+        NativeType::Exact(t) => Some(t.clone()),
+        NativeType::Any => None,
+        NativeType::Element => Some(element_type.clone().unwrap_or(Type::Error)),
+    }
+}
+
+// the type of a function value, `fun(num, str) -> bool`
+fn function_type(parameters: &[Parameter], return_type: &Option<Type>) -> Type {
+    Type::Function(
+        parameters.iter().map(|p| p.param_type.clone()).collect(),
+        Box::new(return_type.clone().unwrap_or(Type::Void)),
+    )
+}
+
+// whether two match patterns match the same value
+fn same_literal(a: &ASTNodeKind, b: &ASTNodeKind) -> bool {
+    match (a, b) {
+        (ASTNodeKind::NumberLiteral(a), ASTNodeKind::NumberLiteral(b)) => a == b,
+        (ASTNodeKind::StringLiteral(a), ASTNodeKind::StringLiteral(b)) => a == b,
+        (ASTNodeKind::BooleanLiteral(a), ASTNodeKind::BooleanLiteral(b)) => a == b,
+        _ => false,
+    }
+}
+
+// parameters with a default value come last, so arguments fill the others first
+fn check_parameters(parameters: &[Parameter]) -> Result<(), String> {
+    let mut defaults = parameters.iter().skip_while(|p| p.default.is_none());
+    match defaults.find(|p| p.default.is_none()) {
+        Some(param) => Err(format!(
+            "Parameter '{}' needs a default value, it comes after a parameter with one",
+            param.name
+        )),
+        None => Ok(()),
+    }
+}
+
+// the number of arguments a call cannot leave out
+fn required_count(parameters: &[Parameter]) -> usize {
+    parameters.iter().filter(|p| p.default.is_none()).count()
+}
+
+// parameters with a default value can be left out, `callee` is like
+// "Function 'name'"
+fn check_argument_count(
+    callee: &str,
+    parameters: &[Parameter],
+    given: usize,
+) -> Result<(), String> {
+    let required = required_count(parameters);
+    if given < required {
+        return Err(format!(
+            "{} expects at least {} arguments, got {}",
+            callee, required, given
+        ));
+    }
+
+    if given > parameters.len() {
+        return Err(format!(
+            "{} expects at most {} arguments, got {}",
+            callee,
+            parameters.len(),
+            given
+        ));
+    }
+
+    Ok(())
+}
+
+fn may_be_none(value_type: &Type) -> String {
+    format!(
+        "Value of type '{:?}' may be none, check it with '!= none' first",
+        value_type
+    )
+}
+
+// the type values of both types fit in, `[1, none]` is a `[num?]`
+fn common_type(a: &Type, b: &Type) -> Option<Type> {
+    if is_assignable(a, b) {
+        return Some(a.clone());
+    }
+    if is_assignable(b, a) {
+        return Some(b.clone());
+    }
+
+    match (a, b) {
+        (Type::Optional(_), value) | (value, Type::Optional(_)) => {
+            let optional = Type::Optional(Box::new(value.clone()));
+            (is_assignable(&optional, a) && is_assignable(&optional, b)).then_some(optional)
+        }
+        _ => None,
+    }
+}
+
+// checks if a value of type `value` can be stored where `target` is expected,
+// empty array literals (`[]`) have a void element type and fit any array,
+// `none` is an optional void and fits any optional
+pub(crate) fn is_assignable(target: &Type, value: &Type) -> bool {
+    match (target, value) {
+        (Type::Error, _) | (_, Type::Error) => true,
+        (Type::Array(_), Type::Array(element)) if **element == Type::Void => true,
+        (Type::Array(target), Type::Array(value)) => is_assignable(target, value),
+        (Type::Optional(_), Type::Optional(inner)) if **inner == Type::Void => true,
+        (Type::Optional(target), Type::Optional(value)) => is_assignable(target, value),
+        (Type::Optional(target), value) => is_assignable(target, value),
+        _ => target == value,
+    }
 }
@@ -1,10 +1,34 @@
 use std::collections::HashMap;
 
+use super::{
+    captures::captured_names,
+    optimizer::{optimize, OptLevel},
+    resolver::{Resolver, Slot},
+    DebugInfo, Labels,
+};
 use crate::{
+    analyzer::block_terminates,
+    diagnostics::{Diagnostic, DiagnosticKind, Span},
     lexer::{Operator, Type},
-    parser::{ASTNode, Parameter},
+    parser::{ASTNode, ASTNodeKind, Parameter},
 };
 
+// a variable a closure captures when it is created
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    Cell(u16),       // the cell of a local of the running function
+    Upvalue(u16),    // a variable the running closure captured itself
+    GlobalCell(u32), // the cell of a global declared in a block
+}
+
+// a parameter of a declared function, default values are compiled into its body
+#[derive(Clone, Debug)]
+pub struct FunctionParameter {
+    pub name: String,
+    pub param_type: Type,
+    pub optional: bool, // has a default value, so callers may leave it out
+}
+
 #[derive(Clone, Debug)]
 pub enum Instruction {
     // stack operations
@@ -12,14 +36,28 @@ pub enum Instruction {
     PushString(String),
     PushBoolean(bool),
     PushVoid,
+    PushNone,
     Pop,
+    Duplicate(usize), // duplicates the top n values
     Negate,
     LogicalNot,
 
-    // variables
-    LoadVariable(String),
-    StoreVariable(String),
-    DeclareVariable(String, Type),
+    // variables, stores leave the value on the stack
+    LoadLocal(u16), // slot relative to the current frame
+    StoreLocal(u16),
+    LoadGlobal(u32), // index into the global table
+    StoreGlobal(u32),
+
+    // arrays
+    MakeArray(usize), // element count
+    LoadIndex,
+    StoreIndex,
+
+    // structs
+    DeclareStruct(String, Vec<String>), // struct name, field names
+    MakeStruct(String, Vec<String>),    // struct name, initialized field names
+    LoadField(String),
+    StoreField(String),
 
     // math
     Add,
@@ -41,43 +79,96 @@ pub enum Instruction {
     LessThanOrEqual,
 
     // control flow
-    Jump(usize),        // jump to ix index
-    JumpIfFalse(usize), // conditional jump
-    JumpIfTrue(usize),  // conditional jump if true
+    Jump(usize),          // jump to ix index
+    JumpIfFalse(usize),   // conditional jump
+    JumpIfTrue(usize),    // conditional jump if true
+    JumpIfNotVoid(usize), // skips the default of a parameter, left out arguments are void
 
     // functions
-    DeclareFunction(String, Vec<Parameter>, Option<Type>),
-    Call(String, usize),       // function name, arg count
-    CallMethod(String, usize), // method name, arg count
+    DeclareFunction(String, Vec<FunctionParameter>, usize), // name, parameters, frame slot count
+    Call(String, usize),                                    // function name, arg count
+    CallMethod(String, usize),                              // method name, arg count
     Return,
 
-    // environment
-    EnterScope,
-    ExitScope,
+    // closures
+    MakeCell(u16), // moves the value on top of the stack into a new cell for the slot
+    LoadCell(u16),
+    StoreCell(u16),
+    LoadUpvalue(u16), // index into the captures of the running closure
+    StoreUpvalue(u16),
+    MakeClosure(String, Type, Vec<Capture>), // function name, its type, captured variables
+    CallValue(usize),                        // arg count, the function is below the arguments
+    MakeGlobalCell(u32),                     // like `MakeCell` for a global declared in a block
+    LoadGlobalCell(u32),
+    StoreGlobalCell(u32),
 
     // end of program
     End,
 }
 
+#[derive(Clone)]
 pub struct Bytecode {
-    program: ASTNode,
     instructions: Vec<Instruction>,
+    debug_info: DebugInfo, // source span of every emitted instruction
+    current_span: Span,
     jump_points: Vec<(usize, String)>,
+    jump_labels: HashMap<usize, String>, // resolved jumps, kept for the disassembler
     labels: HashMap<String, usize>,
     label_counter: usize,
+    resolver: Resolver,
+    loops: Vec<(String, String)>, // break and continue labels of the enclosing loops
+    test: Option<String>,         // the test compiled into the program, others are left out
+    function_types: HashMap<String, Type>, // named functions can be used as values
+    opt_level: OptLevel,
 }
 
 impl Bytecode {
-    pub fn new(program: ASTNode) -> Self {
+    pub fn new() -> Self {
         Self {
-            program,
             instructions: Vec::new(),
+            debug_info: DebugInfo::default(),
+            current_span: Span::default(),
             jump_points: Vec::new(),
+            jump_labels: HashMap::new(),
             labels: HashMap::new(),
             label_counter: 0,
+            resolver: Resolver::new(),
+            loops: Vec::new(),
+            test: None,
+            function_types: HashMap::new(),
+            opt_level: OptLevel::default(),
+        }
+    }
+
+    pub fn set_test(&mut self, test: Option<String>) {
+        self.test = test;
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    pub fn debug_info(&self) -> DebugInfo {
+        self.debug_info.clone()
+    }
+
+    pub fn labels(&self) -> Labels {
+        let mut definitions: HashMap<usize, Vec<String>> = HashMap::new();
+        for (label, &address) in &self.labels {
+            definitions.entry(address).or_default().push(label.clone());
+        }
+
+        Labels {
+            definitions,
+            jumps: self.jump_labels.clone(),
         }
     }
 
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+        self.debug_info.push(self.current_span);
+    }
+
     fn generate_label(&mut self, prefix: &str) -> String {
         let label = format!("{}_{}", prefix, self.label_counter);
         self.label_counter += 1;
@@ -91,7 +182,7 @@ impl Bytecode {
 
     fn add_jump(&mut self, instruction: Instruction, label: &str) {
         let pos = self.instructions.len();
-        self.instructions.push(instruction);
+        self.emit(instruction);
         self.jump_points.push((pos, label.to_string()));
     }
 
@@ -108,194 +199,475 @@ impl Bytecode {
                     Instruction::JumpIfTrue(_) => {
                         self.instructions[pos] = Instruction::JumpIfTrue(target);
                     }
+                    Instruction::JumpIfNotVoid(_) => {
+                        self.instructions[pos] = Instruction::JumpIfNotVoid(target);
+                    }
                     _ => panic!("Non jump instruction in jump points"),
                 }
             } else {
                 panic!("Unresolved label: {}", label);
             }
         }
+
+        // resolved jumps must not be patched again by a later `append`
+        self.jump_labels.extend(self.jump_points.drain(..));
     }
 
-    pub fn compile(&mut self) -> Result<Vec<Instruction>, String> {
-        let program = self.program.clone();
+    // compiles another program after the code compiled so far and returns the
+    // address where the new code starts, so the REPL can keep earlier functions
+    // and variables alive across inputs
+    pub fn append(&mut self, program: ASTNode) -> Result<usize, Diagnostic> {
+        // the new code replaces the previous end of program
+        if let Some(Instruction::End) = self.instructions.last() {
+            self.instructions.pop();
+            self.debug_info.pop();
+        }
+
+        let start = self.instructions.len();
+        let resolver = self.resolver.clone();
+
+        match program.kind {
+            ASTNodeKind::Program(mut statements) => {
+                if self.opt_level == OptLevel::O1 {
+                    statements = optimize(statements);
+                }
+                self.resolver.set_captured(captured_names(&statements));
 
-        match program {
-            ASTNode::Program(statements) => {
                 for stmt in statements {
-                    self.compile_node(stmt)?;
+                    if let Err(message) = self.compile_node(stmt) {
+                        let span = self.current_span;
+
+                        // drop the partially compiled code
+                        self.instructions.truncate(start);
+                        self.debug_info.truncate(start);
+                        self.jump_points.clear();
+                        self.labels.retain(|_, address| *address < start);
+                        self.resolver = resolver;
+                        self.loops.clear();
+                        self.current_span = Span::default();
+                        self.emit(Instruction::End);
+
+                        return Err(Diagnostic::new(
+                            DiagnosticKind::Compiler,
+                            message,
+                            Some(span),
+                        ));
+                    }
                 }
 
-                self.instructions.push(Instruction::End);
+                self.emit(Instruction::End);
 
                 self.resolve_jumps();
             }
             _ => unreachable!("Unexpected node type, expected program"),
         }
 
-        Ok(self.instructions.clone())
+        Ok(start)
     }
 
-    fn is_return_statement(&self, node: &ASTNode) -> bool {
-        match node {
-            ASTNode::ReturnStatement(_) => true,
-            ASTNode::IfStatement {
-                then_body,
-                else_body,
-                ..
-            } => {
-                let then_returns = !then_body.is_empty()
-                    && self.is_return_statement(&then_body[then_body.len() - 1]);
+    // index of a global declared by the compiled code or the host
+    pub fn global_slot(&self, name: &str) -> Option<u32> {
+        self.resolver.global(name)
+    }
 
-                match else_body {
-                    Some(else_body) => {
-                        let else_returns = !else_body.is_empty()
-                            && self.is_return_statement(&else_body[else_body.len() - 1]);
+    // `callable` globals hold functions, calls by their name call the value
+    pub fn declare_global(&mut self, name: &str, callable: bool) -> Result<u32, String> {
+        match self.resolver.declare(name, callable)? {
+            Slot::Global(index) | Slot::GlobalCell(index) => Ok(index),
+            _ => unreachable!("declare_global called while compiling a function"),
+        }
+    }
 
-                        then_returns && else_returns
-                    }
-                    None => then_returns,
-                }
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.instructions.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    fn emit_load(&mut self, name: &str) -> Result<(), String> {
+        match self.resolver.resolve(name)? {
+            Slot::Local(slot) => self.emit(Instruction::LoadLocal(slot)),
+            Slot::Global(index) => self.emit(Instruction::LoadGlobal(index)),
+            Slot::Cell(slot) => self.emit(Instruction::LoadCell(slot)),
+            Slot::Upvalue(index) => self.emit(Instruction::LoadUpvalue(index)),
+            Slot::GlobalCell(index) => self.emit(Instruction::LoadGlobalCell(index)),
+        }
+        Ok(())
+    }
+
+    fn emit_store(&mut self, name: &str) -> Result<(), String> {
+        let slot = self.resolver.resolve(name)?;
+        self.emit_store_slot(slot);
+        Ok(())
+    }
+
+    fn emit_store_slot(&mut self, slot: Slot) {
+        match slot {
+            Slot::Local(slot) => self.emit(Instruction::StoreLocal(slot)),
+            Slot::Global(index) => self.emit(Instruction::StoreGlobal(index)),
+            Slot::Cell(slot) => self.emit(Instruction::StoreCell(slot)),
+            Slot::Upvalue(index) => self.emit(Instruction::StoreUpvalue(index)),
+            Slot::GlobalCell(index) => self.emit(Instruction::StoreGlobalCell(index)),
+        }
+    }
+
+    // declares a variable initialized with the value on top of the stack, a
+    // `callable` variable holds a function
+    fn emit_declare(&mut self, name: &str, callable: bool) -> Result<(), String> {
+        match self.resolver.declare(name, callable)? {
+            // every declaration gets a new cell, closures created in a loop
+            // each keep their own
+            Slot::Cell(slot) => self.emit(Instruction::MakeCell(slot)),
+            Slot::GlobalCell(index) => self.emit(Instruction::MakeGlobalCell(index)),
+            slot => {
+                self.emit_store_slot(slot);
+                // stores leave the value on the stack, declarations are statements
+                self.emit(Instruction::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    // compiles the body of a function or a function literal at the current
+    // address, returning its frame slot count and the variables it captures
+    fn compile_function_body(
+        &mut self,
+        parameters: &[Parameter],
+        body: Vec<ASTNode>,
+        closure: bool,
+    ) -> Result<(usize, Vec<Capture>), String> {
+        // closures in default values can use the parameters before them
+        let mut captured = captured_names(&body);
+        let defaults: Vec<ASTNode> = parameters
+            .iter()
+            .filter_map(|p| p.default.clone())
+            .collect();
+        captured.extend(captured_names(&defaults));
+
+        // parameters take the first slots of the frame, in order
+        self.resolver.enter_function(captured, closure);
+        for (index, param) in parameters.iter().enumerate() {
+            // a default only sees the parameters before it
+            if let Some(default) = &param.default {
+                let slot = index as u16;
+                let skip_label = self.generate_label("default_skip");
+                self.emit(Instruction::LoadLocal(slot));
+                self.add_jump(Instruction::JumpIfNotVoid(0), &skip_label);
+                self.compile_node(default.clone())?;
+                self.emit(Instruction::StoreLocal(slot));
+                self.emit(Instruction::Pop);
+                self.create_label(&skip_label);
+            }
+
+            let callable = matches!(param.param_type, Type::Function(..));
+            if let Slot::Cell(slot) = self.resolver.declare(&param.name, callable)? {
+                self.emit(Instruction::LoadLocal(slot));
+                self.emit(Instruction::MakeCell(slot));
+            }
+        }
+
+        // check if every path through the body already returns
+        let has_explicit_return = block_terminates(&body);
+
+        // compile function body, `break` cannot leave the function
+        let loops = std::mem::take(&mut self.loops);
+        for stmt in body {
+            self.compile_node(stmt)?;
+        }
+        self.loops = loops;
+
+        // if no explicit return, return void
+        if !has_explicit_return {
+            self.emit(Instruction::PushVoid);
+            self.emit(Instruction::Return);
+        }
+
+        Ok(self.resolver.exit_function())
+    }
+
+    // compiles a block in its own variable scope
+    fn compile_block(&mut self, body: Vec<ASTNode>) -> Result<(), String> {
+        self.resolver.enter_scope();
+        for stmt in body {
+            self.compile_node(stmt)?;
+        }
+        self.resolver.exit_scope();
+        Ok(())
+    }
+
+    // compiles `for (type name in array) { body }`
+    fn compile_array_loop(
+        &mut self,
+        name: &str,
+        callable: bool,
+        iterable: ASTNode,
+        body: Vec<ASTNode>,
+    ) -> Result<(), String> {
+        let start_label = self.generate_label("for_start");
+        let continue_label = self.generate_label("for_next");
+        let end_label = self.generate_label("for_end");
+
+        // hidden loop state, `$` keeps them out of reach of user code
+        let array_name = format!("${}", self.generate_label("for_array"));
+        let index_name = format!("${}", self.generate_label("for_index"));
+
+        // scope for the loop state
+        self.resolver.enter_scope();
+
+        // evaluate the iterable once
+        self.compile_node(iterable)?;
+        self.emit_declare(&array_name, false)?;
+
+        // start at the first element
+        self.emit(Instruction::PushNumber(0.0));
+        self.emit_declare(&index_name, false)?;
+
+        // create start label before the condition
+        self.create_label(&start_label);
+
+        // index < array.len()
+        self.emit_load(&index_name)?;
+        self.emit_load(&array_name)?;
+        self.emit(Instruction::CallMethod("len".to_string(), 0));
+        self.emit(Instruction::LessThan);
+
+        // jump to end of for statement if there are no elements left
+        self.add_jump(Instruction::JumpIfFalse(0), &end_label);
+
+        // scope for the body
+        self.resolver.enter_scope();
+
+        // bind the current element to the loop variable
+        self.emit_load(&array_name)?;
+        self.emit_load(&index_name)?;
+        self.emit(Instruction::LoadIndex);
+        self.emit_declare(name, callable)?;
+
+        // compile body
+        self.loops.push((end_label.clone(), continue_label.clone()));
+        for stmt in body {
+            self.compile_node(stmt)?;
+        }
+        self.loops.pop();
+
+        self.resolver.exit_scope();
+
+        // move to the next element, `continue` jumps here
+        self.create_label(&continue_label);
+        self.emit_load(&index_name)?;
+        self.emit(Instruction::PushNumber(1.0));
+        self.emit(Instruction::Add);
+        self.emit_store(&index_name)?;
+        self.emit(Instruction::Pop);
+
+        // jump back to start of for statement
+        self.add_jump(Instruction::Jump(0), &start_label);
+
+        // label for end of for statement
+        self.create_label(&end_label);
+
+        self.resolver.exit_scope();
+
+        Ok(())
+    }
+
+    // compiles `for (num name in start..end step n) { body }`, the loop runs
+    // while `(index - end) * step < 0` so it counts towards `end` in either
+    // direction and never runs with a zero step
+    fn compile_range_loop(
+        &mut self,
+        name: &str,
+        start: ASTNode,
+        end: ASTNode,
+        step: Option<Box<ASTNode>>,
+        body: Vec<ASTNode>,
+    ) -> Result<(), String> {
+        let start_label = self.generate_label("range_start");
+        let continue_label = self.generate_label("range_next");
+        let end_label = self.generate_label("range_end");
+
+        // hidden loop state, the bounds are evaluated once
+        let index_name = format!("${}", self.generate_label("range_index"));
+        let end_name = format!("${}", self.generate_label("range_limit"));
+        let step_name = format!("${}", self.generate_label("range_step"));
+
+        // scope for the loop state
+        self.resolver.enter_scope();
+
+        self.compile_node(start)?;
+        self.emit_declare(&index_name, false)?;
+        self.compile_node(end)?;
+        self.emit_declare(&end_name, false)?;
+        match step {
+            Some(step) => self.compile_node(*step)?,
+            None => self.emit(Instruction::PushNumber(1.0)),
+        }
+        self.emit_declare(&step_name, false)?;
+
+        // create start label before the condition
+        self.create_label(&start_label);
+
+        // (index - end) * step < 0
+        self.emit_load(&index_name)?;
+        self.emit_load(&end_name)?;
+        self.emit(Instruction::Subtract);
+        self.emit_load(&step_name)?;
+        self.emit(Instruction::Multiply);
+        self.emit(Instruction::PushNumber(0.0));
+        self.emit(Instruction::LessThan);
+
+        // jump to end of the loop once the end is reached
+        self.add_jump(Instruction::JumpIfFalse(0), &end_label);
+
+        // scope for the body, the loop variable is a copy of the index
+        self.resolver.enter_scope();
+        self.emit_load(&index_name)?;
+        self.emit_declare(name, false)?;
+
+        self.loops.push((end_label.clone(), continue_label.clone()));
+        for stmt in body {
+            self.compile_node(stmt)?;
+        }
+        self.loops.pop();
+
+        self.resolver.exit_scope();
+
+        // move to the next number, `continue` jumps here
+        self.create_label(&continue_label);
+        self.emit_load(&index_name)?;
+        self.emit_load(&step_name)?;
+        self.emit(Instruction::Add);
+        self.emit_store(&index_name)?;
+        self.emit(Instruction::Pop);
+
+        // jump back to start of the loop
+        self.add_jump(Instruction::Jump(0), &start_label);
+
+        // label for end of the loop
+        self.create_label(&end_label);
+
+        self.resolver.exit_scope();
+        Ok(())
+    }
+
+    // compiles `target op= value`, leaving the stored value on the stack
+    fn compile_compound_assignment(
+        &mut self,
+        target: ASTNode,
+        operation: Instruction,
+        value: ASTNode,
+    ) -> Result<(), String> {
+        match target.kind {
+            ASTNodeKind::Identifier(name) => {
+                // load the current value
+                self.emit_load(&name)?;
+                // load the right side value
+                self.compile_node(value)?;
+                // apply the operation
+                self.emit(operation);
+                // store the result, leaving it on the stack
+                self.emit_store(&name)?;
+            }
+            ASTNodeKind::Index { object, index } => {
+                // keep the array and index around for the store
+                self.compile_node(*object)?;
+                self.compile_node(*index)?;
+                self.emit(Instruction::Duplicate(2));
+                self.emit(Instruction::LoadIndex);
+                self.compile_node(value)?;
+                self.emit(operation);
+                self.emit(Instruction::StoreIndex);
+            }
+            ASTNodeKind::FieldAccess { object, field } => {
+                // keep the instance around for the store
+                self.compile_node(*object)?;
+                self.emit(Instruction::Duplicate(1));
+                self.emit(Instruction::LoadField(field.clone()));
+                self.compile_node(value)?;
+                self.emit(operation);
+                self.emit(Instruction::StoreField(field));
+            }
+            _ => {
+                return Err(
+                    "Left side of assignment must be an identifier, an index or a field"
+                        .to_string(),
+                )
             }
-            _ => false,
         }
+
+        Ok(())
     }
 
     fn compile_node(&mut self, node: ASTNode) -> Result<(), String> {
-        match node {
-            ASTNode::Statement(expr) => {
+        // on error the span of the innermost failing node is kept for the diagnostic
+        let previous_span = std::mem::replace(&mut self.current_span, node.span);
+
+        match node.kind {
+            ASTNodeKind::Statement(expr) => {
                 self.compile_node(*expr)?;
-                self.instructions.push(Instruction::Pop);
+                self.emit(Instruction::Pop);
             }
-            ASTNode::UnaryOperation { op, operand } => {
+            ASTNodeKind::UnaryOperation { op, operand } => {
                 self.compile_node(*operand)?;
                 match op {
-                    Operator::UnaryMinus => self.instructions.push(Instruction::Negate),
-                    Operator::LogicalNot => self.instructions.push(Instruction::LogicalNot),
+                    Operator::UnaryMinus => self.emit(Instruction::Negate),
+                    Operator::LogicalNot => self.emit(Instruction::LogicalNot),
                     _ => return Err(format!("Unsupported unary operator: {:?}", op)),
                 }
             }
-            ASTNode::ReturnStatement(expr) => {
-                self.compile_node(*expr)?;
-                self.instructions.push(Instruction::Return);
+            ASTNodeKind::ReturnStatement(expr) => {
+                match expr {
+                    Some(expr) => self.compile_node(*expr)?,
+                    None => self.emit(Instruction::PushVoid),
+                }
+                self.emit(Instruction::Return);
             }
-            ASTNode::BinaryOperation { left, op, right } => match op {
+            ASTNodeKind::BinaryOperation { left, op, right } => match op {
                 Operator::AssignEquals => {
-                    if let ASTNode::Identifier(name) = *left {
-                        self.compile_node(*right)?;
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
+                    match left.kind {
+                        ASTNodeKind::Identifier(name) => {
+                            self.compile_node(*right)?;
+                            // leaves the stored value on the stack
+                            self.emit_store(&name)?;
+                        }
+                        ASTNodeKind::Index { object, index } => {
+                            self.compile_node(*object)?;
+                            self.compile_node(*index)?;
+                            self.compile_node(*right)?;
+                            // leaves the stored value on the stack
+                            self.emit(Instruction::StoreIndex);
+                        }
+                        ASTNodeKind::FieldAccess { object, field } => {
+                            self.compile_node(*object)?;
+                            self.compile_node(*right)?;
+                            // leaves the stored value on the stack
+                            self.emit(Instruction::StoreField(field));
+                        }
+                        _ => return Err(
+                            "Left side of assignment must be an identifier, an index or a field"
+                                .to_string(),
+                        ),
                     }
                 }
                 Operator::AddAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // add them
-                        self.instructions.push(Instruction::Add);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Add, *right)?
                 }
                 Operator::SubAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // subtract them
-                        self.instructions.push(Instruction::Subtract);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Subtract, *right)?
                 }
                 Operator::MulAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // multiply them
-                        self.instructions.push(Instruction::Multiply);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Multiply, *right)?
                 }
                 Operator::DivAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // divide them
-                        self.instructions.push(Instruction::Divide);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Divide, *right)?
                 }
                 Operator::PowAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // multiply them
-                        self.instructions.push(Instruction::Power);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Power, *right)?
                 }
                 Operator::ModAssign => {
-                    if let ASTNode::Identifier(name) = *left {
-                        // load the current value
-                        self.instructions
-                            .push(Instruction::LoadVariable(name.clone()));
-                        // load the right side value
-                        self.compile_node(*right)?;
-                        // multiply them
-                        self.instructions.push(Instruction::Modulo);
-                        // store the result
-                        self.instructions
-                            .push(Instruction::StoreVariable(name.clone()));
-                        // load the variable
-                        self.instructions.push(Instruction::LoadVariable(name));
-                    } else {
-                        return Err("Left side of assignment must be an identifier".to_string());
-                    }
+                    self.compile_compound_assignment(*left, Instruction::Modulo, *right)?
                 }
                 Operator::LogicalAnd => {
                     // compile left side
@@ -316,7 +688,7 @@ impl Bytecode {
 
                     // skip label - left side was false, push false and skip right side
                     self.create_label(&skip_label);
-                    self.instructions.push(Instruction::PushBoolean(false));
+                    self.emit(Instruction::PushBoolean(false));
 
                     // end label
                     self.create_label(&end_label);
@@ -340,38 +712,48 @@ impl Bytecode {
 
                     // skip label - left side was true, push true and skip right side
                     self.create_label(&skip_label);
-                    self.instructions.push(Instruction::PushBoolean(true));
+                    self.emit(Instruction::PushBoolean(true));
 
                     // end label
                     self.create_label(&end_label);
                 }
+                Operator::Coalesce => {
+                    self.compile_node(*left)?;
+
+                    // keep the left side unless it is none, the right side
+                    // is only evaluated when it is needed
+                    let end_label = self.generate_label("coalesce_end");
+                    self.emit(Instruction::Duplicate(1));
+                    self.emit(Instruction::PushNone);
+                    self.emit(Instruction::NotEquals);
+                    self.add_jump(Instruction::JumpIfTrue(0), &end_label);
+                    self.emit(Instruction::Pop);
+                    self.compile_node(*right)?;
+                    self.create_label(&end_label);
+                }
                 _ => {
                     self.compile_node(*left)?;
                     self.compile_node(*right)?;
 
                     match op {
-                        Operator::Plus => self.instructions.push(Instruction::Add),
-                        Operator::Minus => self.instructions.push(Instruction::Subtract),
-                        Operator::Multiply => self.instructions.push(Instruction::Multiply),
-                        Operator::Divide => self.instructions.push(Instruction::Divide),
-                        Operator::Power => self.instructions.push(Instruction::Power),
-                        Operator::Modulo => self.instructions.push(Instruction::Modulo),
-                        Operator::Equals => self.instructions.push(Instruction::Equals),
-                        Operator::NotEquals => self.instructions.push(Instruction::NotEquals),
-                        Operator::GreaterThan => self.instructions.push(Instruction::GreaterThan),
-                        Operator::LessThan => self.instructions.push(Instruction::LessThan),
-                        Operator::GreaterThanOrEqual => {
-                            self.instructions.push(Instruction::GreaterThanOrEqual)
-                        }
-                        Operator::LessThanOrEqual => {
-                            self.instructions.push(Instruction::LessThanOrEqual)
-                        }
-                        Operator::Concat => self.instructions.push(Instruction::Concat),
+                        Operator::Plus => self.emit(Instruction::Add),
+                        Operator::Minus => self.emit(Instruction::Subtract),
+                        Operator::Multiply => self.emit(Instruction::Multiply),
+                        Operator::Divide => self.emit(Instruction::Divide),
+                        Operator::Power => self.emit(Instruction::Power),
+                        Operator::Modulo => self.emit(Instruction::Modulo),
+                        Operator::Equals => self.emit(Instruction::Equals),
+                        Operator::NotEquals => self.emit(Instruction::NotEquals),
+                        Operator::GreaterThan => self.emit(Instruction::GreaterThan),
+                        Operator::LessThan => self.emit(Instruction::LessThan),
+                        Operator::GreaterThanOrEqual => self.emit(Instruction::GreaterThanOrEqual),
+                        Operator::LessThanOrEqual => self.emit(Instruction::LessThanOrEqual),
+                        Operator::Concat => self.emit(Instruction::Concat),
                         _ => unreachable!("Unexpected binary operator: {:?}", op),
                     }
                 }
             },
-            ASTNode::FunctionDeclaration {
+            ASTNodeKind::FunctionDeclaration {
                 name,
                 parameters,
                 return_type,
@@ -380,11 +762,16 @@ impl Bytecode {
                 let function_label = format!("function_{}", name);
                 let end_label = format!("{}_end", function_label);
 
-                // declare function
-                self.instructions.push(Instruction::DeclareFunction(
-                    name,
-                    parameters.clone(),
-                    return_type,
+                // known before the body, so the function can refer to itself
+                self.function_types
+                    .insert(name.clone(), function_type(&parameters, return_type));
+
+                // declare function, the frame size is patched in once the body is compiled
+                let declaration = self.instructions.len();
+                self.emit(Instruction::DeclareFunction(
+                    name.clone(),
+                    function_parameters(&parameters),
+                    0,
                 ));
 
                 // jump over function body during normal execution
@@ -393,40 +780,74 @@ impl Bytecode {
                 // create function label
                 self.create_label(&function_label);
 
-                // create new scope for function body
-                self.instructions.push(Instruction::EnterScope);
-
-                // check if function has an explicit return
-                let has_explicit_return =
-                    !body.is_empty() && self.is_return_statement(&body[body.len() - 1]);
-
-                // compile function body
-                for stmt in body {
-                    self.compile_node(stmt)?;
-                }
+                let (slot_count, _) = self.compile_function_body(&parameters, body, false)?;
+                self.instructions[declaration] = Instruction::DeclareFunction(
+                    name,
+                    function_parameters(&parameters),
+                    slot_count,
+                );
 
-                // if no explicit return, return void
-                if !has_explicit_return {
-                    self.instructions.push(Instruction::PushVoid);
-                    self.instructions.push(Instruction::Return);
-                }
+                // label for end of function
+                self.create_label(&end_label);
+            }
+            // compiled like a function declaration, the closure is created
+            // where the literal is
+            ASTNodeKind::FunctionLiteral {
+                parameters,
+                return_type,
+                body,
+            } => {
+                // `#` keeps the name apart from the functions of the program
+                let name = format!("anonymous#{}", self.label_counter);
+                self.label_counter += 1;
+                let end_label = format!("function_{}_end", name);
+
+                let declaration = self.instructions.len();
+                self.emit(Instruction::DeclareFunction(
+                    name.clone(),
+                    function_parameters(&parameters),
+                    0,
+                ));
+                self.add_jump(Instruction::Jump(0), &end_label);
+                self.create_label(&format!("function_{}", name));
 
-                // exit scope
-                self.instructions.push(Instruction::ExitScope);
+                let function_type = function_type(&parameters, return_type);
+                let (slot_count, captures) = self.compile_function_body(&parameters, body, true)?;
+                self.instructions[declaration] = Instruction::DeclareFunction(
+                    name.clone(),
+                    function_parameters(&parameters),
+                    slot_count,
+                );
 
-                // label for end of function
                 self.create_label(&end_label);
+                self.emit(Instruction::MakeClosure(name, function_type, captures));
             }
-            ASTNode::FunctionCall { name, arguments } => {
+            ASTNodeKind::FunctionCall { name, arguments } => {
+                // a variable holding a function is called through its value
+                let callable = self.resolver.lookup(&name) == Some(true);
+                if callable {
+                    self.emit_load(&name)?;
+                }
+
                 for arg in &arguments {
                     self.compile_node(arg.clone())?;
                 }
 
                 // call function with number of arguments
-                self.instructions
-                    .push(Instruction::Call(name, arguments.len()));
+                match callable {
+                    true => self.emit(Instruction::CallValue(arguments.len())),
+                    false => self.emit(Instruction::Call(name, arguments.len())),
+                }
             }
-            ASTNode::MethodCall {
+            ASTNodeKind::Call { callee, arguments } => {
+                self.compile_node(*callee)?;
+                let count = arguments.len();
+                for arg in arguments {
+                    self.compile_node(arg)?;
+                }
+                self.emit(Instruction::CallValue(count));
+            }
+            ASTNodeKind::MethodCall {
                 object,
                 method,
                 arguments,
@@ -437,10 +858,9 @@ impl Bytecode {
                     self.compile_node(arg)?;
                 }
 
-                self.instructions
-                    .push(Instruction::CallMethod(method, arguments.len()));
+                self.emit(Instruction::CallMethod(method, arguments.len()));
             }
-            ASTNode::IfStatement {
+            ASTNodeKind::IfStatement {
                 condition,
                 then_body,
                 else_body,
@@ -454,16 +874,8 @@ impl Bytecode {
                 // jump to else body if condition is false
                 self.add_jump(Instruction::JumpIfFalse(0), &else_label);
 
-                // enter scope for then body
-                self.instructions.push(Instruction::EnterScope);
-
                 // compile then body
-                for stmt in then_body {
-                    self.compile_node(stmt)?;
-                }
-
-                // exit then scope
-                self.instructions.push(Instruction::ExitScope);
+                self.compile_block(then_body)?;
 
                 // jump to end after then block
                 self.add_jump(Instruction::Jump(0), &end_label);
@@ -473,22 +885,44 @@ impl Bytecode {
 
                 // compile else body if it exists
                 if let Some(else_body) = else_body {
-                    // enter scope for else body
-                    self.instructions.push(Instruction::EnterScope);
+                    self.compile_block(else_body)?;
+                }
 
-                    // compile else body
-                    for stmt in else_body {
-                        self.compile_node(stmt)?;
+                // label for end of if statement
+                self.create_label(&end_label);
+            }
+            ASTNodeKind::MatchStatement { subject, arms } => {
+                let end_label = self.generate_label("match_end");
+
+                // the subject is evaluated once and compared against every arm
+                let subject_name = format!("${}", self.generate_label("match_subject"));
+                self.resolver.enter_scope();
+                self.compile_node(*subject)?;
+                self.emit_declare(&subject_name, false)?;
+
+                for arm in arms {
+                    let next_label = self.generate_label("match_next");
+
+                    // `_` has no pattern and always runs
+                    if let Some(pattern) = arm.pattern {
+                        self.emit_load(&subject_name)?;
+                        self.compile_node(pattern)?;
+                        self.emit(Instruction::Equals);
+                        self.add_jump(Instruction::JumpIfFalse(0), &next_label);
                     }
 
-                    // exit else scope
-                    self.instructions.push(Instruction::ExitScope);
+                    self.compile_block(arm.body)?;
+                    self.add_jump(Instruction::Jump(0), &end_label);
+
+                    // label for the next arm
+                    self.create_label(&next_label);
                 }
 
-                // label for end of if statement
+                // label for end of match statement
                 self.create_label(&end_label);
+                self.resolver.exit_scope();
             }
-            ASTNode::WhileStatement { condition, body } => {
+            ASTNodeKind::WhileStatement { condition, body } => {
                 let start_label = self.generate_label("while_start");
                 let end_label = self.generate_label("while_end");
 
@@ -501,16 +935,10 @@ impl Bytecode {
                 // jump to end of while statement if condition is false
                 self.add_jump(Instruction::JumpIfFalse(0), &end_label);
 
-                // enter scope for body
-                self.instructions.push(Instruction::EnterScope);
-
-                // compile body
-                for stmt in body {
-                    self.compile_node(stmt)?;
-                }
-
-                // exit scope
-                self.instructions.push(Instruction::ExitScope);
+                // compile body, `continue` checks the condition again
+                self.loops.push((end_label.clone(), start_label.clone()));
+                self.compile_block(body)?;
+                self.loops.pop();
 
                 // jump back to start of while statement
                 self.add_jump(Instruction::Jump(0), &start_label);
@@ -518,31 +946,133 @@ impl Bytecode {
                 // label for end of while statement
                 self.create_label(&end_label);
             }
-            ASTNode::VariableDeclaration {
+            ASTNodeKind::ForStatement {
+                var_type,
+                name,
+                iterable,
+                body,
+            } => match iterable.kind {
+                ASTNodeKind::Range { start, end, step } => {
+                    self.compile_range_loop(&name, *start, *end, step, body)?
+                }
+                _ => {
+                    let callable = matches!(var_type, Type::Function(..));
+                    self.compile_array_loop(&name, callable, *iterable, body)?
+                }
+            },
+            ASTNodeKind::Break | ASTNodeKind::Continue => {
+                let (break_label, continue_label) = match self.loops.last() {
+                    Some(labels) => labels.clone(),
+                    None => return Err("'break' and 'continue' must be inside a loop".to_string()),
+                };
+                let label = match node.kind {
+                    ASTNodeKind::Break => break_label,
+                    _ => continue_label,
+                };
+                self.add_jump(Instruction::Jump(0), &label);
+            }
+            ASTNodeKind::VariableDeclaration {
                 var_type,
                 name,
                 value,
             } => {
-                self.instructions
-                    .push(Instruction::DeclareVariable(name.clone(), var_type));
+                // the value is compiled first, it cannot refer to the new variable
                 self.compile_node(*value)?;
-                self.instructions.push(Instruction::StoreVariable(name));
+                self.emit_declare(&name, matches!(var_type, Type::Function(..)))?;
             }
-            ASTNode::Identifier(name) => {
-                self.instructions.push(Instruction::LoadVariable(name));
+            // a named function used as a value becomes a closure capturing nothing
+            ASTNodeKind::Identifier(name) => match self.function_types.get(&name) {
+                Some(function_type) if self.resolver.lookup(&name).is_none() => {
+                    let function_type = function_type.clone();
+                    self.emit(Instruction::MakeClosure(name, function_type, Vec::new()));
+                }
+                _ => self.emit_load(&name)?,
+            },
+            ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            } => {
+                self.emit(Instruction::DeclareStruct(
+                    name.clone(),
+                    fields.into_iter().map(|f| f.name).collect(),
+                ));
+
+                // methods are compiled as functions taking the instance as `self`
+                for method in methods {
+                    self.compile_node(method.into_method_function(&name))?;
+                }
+            }
+            ASTNodeKind::StructLiteral { name, fields } => {
+                let mut field_names = Vec::with_capacity(fields.len());
+                for (field, value) in fields {
+                    self.compile_node(value)?;
+                    field_names.push(field);
+                }
+                self.emit(Instruction::MakeStruct(name, field_names));
             }
-            ASTNode::NumberLiteral(value) => {
-                self.instructions.push(Instruction::PushNumber(value));
+            ASTNodeKind::FieldAccess { object, field } => {
+                self.compile_node(*object)?;
+                self.emit(Instruction::LoadField(field));
             }
-            ASTNode::StringLiteral(value) => {
-                self.instructions.push(Instruction::PushString(value));
+            ASTNodeKind::Index { object, index } => {
+                self.compile_node(*object)?;
+                self.compile_node(*index)?;
+                self.emit(Instruction::LoadIndex);
             }
-            ASTNode::BooleanLiteral(value) => {
-                self.instructions.push(Instruction::PushBoolean(value));
+            ASTNodeKind::ArrayLiteral(elements) => {
+                let count = elements.len();
+                for element in elements {
+                    self.compile_node(element)?;
+                }
+                self.emit(Instruction::MakeArray(count));
+            }
+            // runs after the code above it, the code below it is not needed
+            ASTNodeKind::Test { name, body } => {
+                if self.test.as_ref() == Some(&name) {
+                    self.compile_block(body)?;
+                    self.emit(Instruction::End);
+                }
+            }
+            ASTNodeKind::NumberLiteral(value) => {
+                self.emit(Instruction::PushNumber(value));
+            }
+            ASTNodeKind::StringLiteral(value) => {
+                self.emit(Instruction::PushString(value));
+            }
+            ASTNodeKind::BooleanLiteral(value) => {
+                self.emit(Instruction::PushBoolean(value));
+            }
+            ASTNodeKind::NoneLiteral => {
+                self.emit(Instruction::PushNone);
             }
             _ => unreachable!("Unexpected node type, expected statement"),
         };
 
+        self.current_span = previous_span;
+
         Ok(())
     }
 }
+
+fn function_parameters(parameters: &[Parameter]) -> Vec<FunctionParameter> {
+    parameters
+        .iter()
+        .map(|parameter| FunctionParameter {
+            name: parameter.name.clone(),
+            param_type: parameter.param_type.clone(),
+            optional: parameter.default.is_some(),
+        })
+        .collect()
+}
+
+// the type of a function value, `fun(num, str) -> bool`
+fn function_type(parameters: &[Parameter], return_type: Option<Type>) -> Type {
+    Type::Function(
+        parameters
+            .iter()
+            .map(|parameter| parameter.param_type.clone())
+            .collect(),
+        Box::new(return_type.unwrap_or(Type::Void)),
+    )
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use crate::parser::{ASTNode, ASTNodeKind};
+
+// the names used inside the function literals of a function body, the locals
+// of the function with these names are kept in cells so closures can share them
+pub fn captured_names(body: &[ASTNode]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for node in body {
+        visit(node, false, &mut names);
+    }
+    names
+}
+
+fn visit_all(nodes: &[ASTNode], inside: bool, names: &mut HashSet<String>) {
+    for node in nodes {
+        visit(node, inside, names);
+    }
+}
+
+// `inside` is set within a function literal, only names used there are collected
+fn visit(node: &ASTNode, inside: bool, names: &mut HashSet<String>) {
+    match &node.kind {
+        ASTNodeKind::Identifier(name) => {
+            if inside {
+                names.insert(name.clone());
+            }
+        }
+        ASTNodeKind::FunctionCall { name, arguments } => {
+            if inside {
+                names.insert(name.clone());
+            }
+            visit_all(arguments, inside, names);
+        }
+        ASTNodeKind::FunctionLiteral {
+            parameters, body, ..
+        } => {
+            for parameter in parameters {
+                if let Some(default) = &parameter.default {
+                    visit(default, true, names);
+                }
+            }
+            visit_all(body, true, names)
+        }
+        ASTNodeKind::Program(nodes) | ASTNodeKind::ArrayLiteral(nodes) => {
+            visit_all(nodes, inside, names)
+        }
+        ASTNodeKind::Statement(node)
+        | ASTNodeKind::ReturnStatement(Some(node))
+        | ASTNodeKind::UnaryOperation { operand: node, .. }
+        | ASTNodeKind::FieldAccess { object: node, .. }
+        | ASTNodeKind::VariableDeclaration { value: node, .. } => visit(node, inside, names),
+        ASTNodeKind::BinaryOperation { left, right, .. }
+        | ASTNodeKind::Index {
+            object: left,
+            index: right,
+        } => {
+            visit(left, inside, names);
+            visit(right, inside, names);
+        }
+        ASTNodeKind::MethodCall {
+            object: callee,
+            arguments,
+            ..
+        }
+        | ASTNodeKind::Call { callee, arguments } => {
+            visit(callee, inside, names);
+            visit_all(arguments, inside, names);
+        }
+        ASTNodeKind::IfStatement {
+            condition,
+            then_body,
+            else_body,
+        } => {
+            visit(condition, inside, names);
+            visit_all(then_body, inside, names);
+            if let Some(else_body) = else_body {
+                visit_all(else_body, inside, names);
+            }
+        }
+        ASTNodeKind::WhileStatement { condition, body } => {
+            visit(condition, inside, names);
+            visit_all(body, inside, names);
+        }
+        ASTNodeKind::MatchStatement { subject, arms } => {
+            visit(subject, inside, names);
+            for arm in arms {
+                visit_all(&arm.body, inside, names);
+            }
+        }
+        ASTNodeKind::ForStatement { iterable, body, .. } => {
+            visit(iterable, inside, names);
+            visit_all(body, inside, names);
+        }
+        ASTNodeKind::Range { start, end, step } => {
+            visit(start, inside, names);
+            visit(end, inside, names);
+            if let Some(step) = step {
+                visit(step, inside, names);
+            }
+        }
+        ASTNodeKind::StructLiteral { fields, .. } => {
+            for (_, value) in fields {
+                visit(value, inside, names);
+            }
+        }
+        ASTNodeKind::Test { body, .. } => visit_all(body, inside, names),
+        // named functions cannot use the locals around them
+        ASTNodeKind::FunctionDeclaration { .. }
+        | ASTNodeKind::StructDeclaration { .. }
+        | ASTNodeKind::Import { .. }
+        | ASTNodeKind::ReturnStatement(None)
+        | ASTNodeKind::Break
+        | ASTNodeKind::Continue
+        | ASTNodeKind::NumberLiteral(_)
+        | ASTNodeKind::StringLiteral(_)
+        | ASTNodeKind::BooleanLiteral(_)
+        | ASTNodeKind::NoneLiteral => (),
+    }
+}
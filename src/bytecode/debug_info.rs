@@ -0,0 +1,69 @@
+use crate::diagnostics::{SourceMap, Span};
+
+// maps instruction addresses back to the source they were compiled from, the
+// VM uses it to locate runtime errors and every frame of their stack trace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    spans: Vec<Span>,          // one per instruction, empty when the source is unknown
+    files: Vec<String>,        // names of the imported files, in `SourceMap` order
+    main_file: Option<String>, // name of the file the main source was read from
+}
+
+impl DebugInfo {
+    pub fn new(spans: Vec<Span>, files: Vec<String>, main_file: Option<String>) -> Self {
+        Self {
+            spans,
+            files,
+            main_file,
+        }
+    }
+
+    pub fn span(&self, address: usize) -> Option<Span> {
+        self.spans.get(address).copied()
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    // the imported files by name, their source is not kept with the code
+    pub fn source_map(&self) -> SourceMap {
+        let mut sources = SourceMap::default();
+        for name in &self.files {
+            sources.add(name.clone(), String::new());
+        }
+        sources
+    }
+
+    pub fn main_file(&self) -> Option<&str> {
+        self.main_file.as_deref()
+    }
+
+    pub(crate) fn set_files(&mut self, files: Vec<String>) {
+        self.files = files;
+    }
+
+    pub(crate) fn set_main_file(&mut self, main_file: Option<String>) {
+        self.main_file = main_file;
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub(crate) fn push(&mut self, span: Span) {
+        self.spans.push(span);
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.spans.pop();
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.spans.truncate(len);
+    }
+}
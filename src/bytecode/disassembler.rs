@@ -0,0 +1,192 @@
+use std::{collections::HashMap, fmt};
+
+use super::{Capture, Instruction};
+
+// names for addresses, so jumps can be shown by label instead of by address
+#[derive(Clone, Debug, Default)]
+pub struct Labels {
+    pub definitions: HashMap<usize, Vec<String>>, // address -> labels placed there
+    pub jumps: HashMap<usize, String>,            // address of a jump -> label it targets
+}
+
+impl Labels {
+    // the label a jump at `address` goes to, addresses without a known label
+    // (like in a loaded `.booc` file) get a generated one
+    fn target(&self, address: usize, target: usize) -> String {
+        if let Some(label) = self.jumps.get(&address) {
+            return label.clone();
+        }
+
+        match self
+            .definitions
+            .get(&target)
+            .and_then(|labels| labels.first())
+        {
+            Some(label) => label.clone(),
+            None => format!("L{:04}", target),
+        }
+    }
+}
+
+// renders instructions one per line with their address, jump targets are
+// shown as labels and function bodies are indented between markers
+pub fn disassemble(instructions: &[Instruction], labels: &Labels) -> String {
+    let mut definitions = labels.definitions.clone();
+    for (address, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::JumpIfTrue(target)
+        | Instruction::JumpIfNotVoid(target) = instruction
+        {
+            let label = labels.target(address, *target);
+            let names = definitions.entry(*target).or_default();
+            if !names.contains(&label) {
+                names.push(label);
+            }
+        }
+    }
+
+    let mut output = String::new();
+    // end address and name of every function being printed, innermost last
+    let mut functions: Vec<(usize, String)> = Vec::new();
+
+    for (address, instruction) in instructions.iter().enumerate() {
+        while let Some((end, name)) = functions.last() {
+            if *end > address {
+                break;
+            }
+            let indent = "  ".repeat(functions.len() - 1);
+            output.push_str(&format!("      {}; end of function {}\n", indent, name));
+            functions.pop();
+        }
+
+        // a body starts after the jump that skips it and ends at that jump's target
+        if let (Some(Instruction::DeclareFunction(name, ..)), Some(Instruction::Jump(end))) = (
+            address.checked_sub(2).and_then(|i| instructions.get(i)),
+            address.checked_sub(1).and_then(|i| instructions.get(i)),
+        ) {
+            let indent = "  ".repeat(functions.len());
+            output.push_str(&format!("      {}; function {}\n", indent, name));
+            functions.push((*end, name.clone()));
+        }
+
+        let indent = "  ".repeat(functions.len());
+
+        if let Some(names) = definitions.get(&address) {
+            let mut names = names.clone();
+            names.sort();
+            for name in names {
+                output.push_str(&format!("      {}{}:\n", indent, name));
+            }
+        }
+
+        let text = match instruction {
+            Instruction::Jump(target) => {
+                format!("{:<18} {}", "Jump", labels.target(address, *target))
+            }
+            Instruction::JumpIfFalse(target) => {
+                format!("{:<18} {}", "JumpIfFalse", labels.target(address, *target))
+            }
+            Instruction::JumpIfTrue(target) => {
+                format!("{:<18} {}", "JumpIfTrue", labels.target(address, *target))
+            }
+            Instruction::JumpIfNotVoid(target) => {
+                format!(
+                    "{:<18} {}",
+                    "JumpIfNotVoid",
+                    labels.target(address, *target)
+                )
+            }
+            _ => instruction.to_string(),
+        };
+        output.push_str(&format!("{:04}  {}{}\n", address, indent, text));
+    }
+
+    output
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::PushNumber(value) => write!(f, "{:<18} {}", "PushNumber", value),
+            Instruction::PushString(value) => write!(f, "{:<18} {:?}", "PushString", value),
+            Instruction::PushBoolean(value) => write!(f, "{:<18} {}", "PushBoolean", value),
+            Instruction::Duplicate(count) => write!(f, "{:<18} {}", "Duplicate", count),
+            Instruction::LoadLocal(slot) => write!(f, "{:<18} {}", "LoadLocal", slot),
+            Instruction::StoreLocal(slot) => write!(f, "{:<18} {}", "StoreLocal", slot),
+            Instruction::LoadGlobal(index) => write!(f, "{:<18} {}", "LoadGlobal", index),
+            Instruction::StoreGlobal(index) => write!(f, "{:<18} {}", "StoreGlobal", index),
+            Instruction::MakeArray(count) => write!(f, "{:<18} {}", "MakeArray", count),
+            Instruction::DeclareStruct(name, fields) | Instruction::MakeStruct(name, fields) => {
+                let mnemonic = match self {
+                    Instruction::DeclareStruct(..) => "DeclareStruct",
+                    _ => "MakeStruct",
+                };
+                write!(f, "{:<18} {} {{ {} }}", mnemonic, name, fields.join(", "))
+            }
+            Instruction::LoadField(field) => write!(f, "{:<18} {}", "LoadField", field),
+            Instruction::StoreField(field) => write!(f, "{:<18} {}", "StoreField", field),
+            Instruction::Jump(address) => write!(f, "{:<18} {:04}", "Jump", address),
+            Instruction::JumpIfFalse(address) => write!(f, "{:<18} {:04}", "JumpIfFalse", address),
+            Instruction::JumpIfTrue(address) => write!(f, "{:<18} {:04}", "JumpIfTrue", address),
+            Instruction::JumpIfNotVoid(address) => {
+                write!(f, "{:<18} {:04}", "JumpIfNotVoid", address)
+            }
+            Instruction::DeclareFunction(name, parameters, slot_count) => {
+                let parameters: Vec<String> = parameters
+                    .iter()
+                    .map(|p| {
+                        let default = if p.optional { " = .." } else { "" };
+                        format!("{:?} {}{}", p.param_type, p.name, default)
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "{:<18} {}({}) slots={}",
+                    "DeclareFunction",
+                    name,
+                    parameters.join(", "),
+                    slot_count
+                )
+            }
+            Instruction::Call(name, arg_count) => {
+                write!(f, "{:<18} {} args={}", "Call", name, arg_count)
+            }
+            Instruction::CallMethod(name, arg_count) => {
+                write!(f, "{:<18} {} args={}", "CallMethod", name, arg_count)
+            }
+            Instruction::MakeCell(slot) => write!(f, "{:<18} {}", "MakeCell", slot),
+            Instruction::LoadCell(slot) => write!(f, "{:<18} {}", "LoadCell", slot),
+            Instruction::StoreCell(slot) => write!(f, "{:<18} {}", "StoreCell", slot),
+            Instruction::LoadUpvalue(index) => write!(f, "{:<18} {}", "LoadUpvalue", index),
+            Instruction::StoreUpvalue(index) => write!(f, "{:<18} {}", "StoreUpvalue", index),
+            Instruction::MakeGlobalCell(index) => write!(f, "{:<18} {}", "MakeGlobalCell", index),
+            Instruction::LoadGlobalCell(index) => write!(f, "{:<18} {}", "LoadGlobalCell", index),
+            Instruction::StoreGlobalCell(index) => {
+                write!(f, "{:<18} {}", "StoreGlobalCell", index)
+            }
+            Instruction::MakeClosure(name, _, captures) => {
+                let captures: Vec<String> = captures
+                    .iter()
+                    .map(|capture| match capture {
+                        Capture::Cell(slot) => format!("cell {}", slot),
+                        Capture::Upvalue(index) => format!("upvalue {}", index),
+                        Capture::GlobalCell(index) => format!("global cell {}", index),
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "{:<18} {} [{}]",
+                    "MakeClosure",
+                    name,
+                    captures.join(", ")
+                )
+            }
+            Instruction::CallValue(arg_count) => {
+                write!(f, "{:<18} args={}", "CallValue", arg_count)
+            }
+            // instructions without operands are shown by name
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
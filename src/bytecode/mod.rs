@@ -1,3 +1,13 @@
 mod bytecode;
+mod captures;
+mod debug_info;
+mod disassembler;
+mod optimizer;
+mod resolver;
+mod serializer;
 
 pub use bytecode::*;
+pub use debug_info::*;
+pub use disassembler::*;
+pub use optimizer::*;
+pub use serializer::*;
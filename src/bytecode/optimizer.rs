@@ -0,0 +1,370 @@
+use crate::{
+    analyzer::flow::terminates,
+    lexer::Operator,
+    parser::{ASTNode, ASTNodeKind},
+};
+
+// how much the compiler rewrites code before compiling it, `-O0` and `-O1`
+// on the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OptLevel {
+    // compiles the code as it is written
+    O0,
+    // folds constant expressions and leaves out code that can never run
+    #[default]
+    O1,
+}
+
+// optimizes type checked top level statements, the result behaves the same
+// as the code it replaces
+pub fn optimize(statements: Vec<ASTNode>) -> Vec<ASTNode> {
+    // code after a top level `return` is kept, functions below it can use
+    // the globals it declares
+    statements
+        .into_iter()
+        .flat_map(optimize_statement)
+        .collect()
+}
+
+// a statement can turn into any number of statements, `if (true) { ... }`
+// becomes its body and `while (false) { ... }` disappears
+fn optimize_statement(node: ASTNode) -> Vec<ASTNode> {
+    let span = node.span;
+    match node.kind {
+        ASTNodeKind::IfStatement {
+            condition,
+            then_body,
+            else_body,
+        } => {
+            let condition = optimize_node(*condition);
+            let then_body = optimize_block(then_body);
+            let else_body = else_body.map(optimize_block);
+
+            let body = match condition.kind {
+                ASTNodeKind::BooleanLiteral(true) => then_body,
+                ASTNodeKind::BooleanLiteral(false) => else_body.unwrap_or_default(),
+                _ => {
+                    return vec![ASTNode::new(
+                        ASTNodeKind::IfStatement {
+                            condition: Box::new(condition),
+                            then_body,
+                            else_body,
+                        },
+                        span,
+                    )]
+                }
+            };
+
+            // declarations need the scope of the block around them
+            if body.iter().any(declares) {
+                let condition = ASTNode::new(ASTNodeKind::BooleanLiteral(true), condition.span);
+                return vec![ASTNode::new(
+                    ASTNodeKind::IfStatement {
+                        condition: Box::new(condition),
+                        then_body: body,
+                        else_body: None,
+                    },
+                    span,
+                )];
+            }
+            body
+        }
+        ASTNodeKind::WhileStatement { condition, body } => {
+            let condition = optimize_node(*condition);
+            if let ASTNodeKind::BooleanLiteral(false) = condition.kind {
+                return Vec::new();
+            }
+
+            vec![ASTNode::new(
+                ASTNodeKind::WhileStatement {
+                    condition: Box::new(condition),
+                    body: optimize_block(body),
+                },
+                span,
+            )]
+        }
+        kind => vec![optimize_node(ASTNode::new(kind, span))],
+    }
+}
+
+// optimizes the statements of a block and drops the ones after a `return`,
+// `break` or `continue`
+fn optimize_block(body: Vec<ASTNode>) -> Vec<ASTNode> {
+    let mut optimized = Vec::new();
+    let mut reachable = true;
+    for statement in body.into_iter().flat_map(optimize_statement) {
+        // named functions and structs can be used by the code above them
+        let declaration = matches!(
+            statement.kind,
+            ASTNodeKind::FunctionDeclaration { .. } | ASTNodeKind::StructDeclaration { .. }
+        );
+
+        if reachable || declaration {
+            reachable = reachable && !terminates(&statement);
+            optimized.push(statement);
+        }
+    }
+    optimized
+}
+
+fn optimize_all(nodes: Vec<ASTNode>) -> Vec<ASTNode> {
+    nodes.into_iter().map(optimize_node).collect()
+}
+
+fn optimize_node(node: ASTNode) -> ASTNode {
+    let span = node.span;
+    let kind = match node.kind {
+        ASTNodeKind::BinaryOperation { left, op, right } => {
+            let left = optimize_node(*left);
+            let right = optimize_node(*right);
+            match fold_binary(&left, &op, &right) {
+                Some(folded) => return ASTNode::new(folded, span),
+                None => ASTNodeKind::BinaryOperation {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                },
+            }
+        }
+        ASTNodeKind::UnaryOperation { op, operand } => {
+            let operand = optimize_node(*operand);
+            match (&op, &operand.kind) {
+                (Operator::UnaryMinus, ASTNodeKind::NumberLiteral(value)) => {
+                    ASTNodeKind::NumberLiteral(-value)
+                }
+                (Operator::LogicalNot, ASTNodeKind::BooleanLiteral(value)) => {
+                    ASTNodeKind::BooleanLiteral(!value)
+                }
+                _ => ASTNodeKind::UnaryOperation {
+                    op,
+                    operand: Box::new(operand),
+                },
+            }
+        }
+        ASTNodeKind::Program(statements) => ASTNodeKind::Program(optimize(statements)),
+        ASTNodeKind::Statement(expr) => ASTNodeKind::Statement(Box::new(optimize_node(*expr))),
+        ASTNodeKind::ReturnStatement(expr) => {
+            ASTNodeKind::ReturnStatement(expr.map(|expr| Box::new(optimize_node(*expr))))
+        }
+        ASTNodeKind::FunctionDeclaration {
+            name,
+            mut parameters,
+            return_type,
+            body,
+        } => {
+            for parameter in &mut parameters {
+                parameter.default = parameter.default.take().map(optimize_node);
+            }
+            ASTNodeKind::FunctionDeclaration {
+                name,
+                parameters,
+                return_type,
+                body: optimize_block(body),
+            }
+        }
+        ASTNodeKind::FunctionLiteral {
+            mut parameters,
+            return_type,
+            body,
+        } => {
+            for parameter in &mut parameters {
+                parameter.default = parameter.default.take().map(optimize_node);
+            }
+            ASTNodeKind::FunctionLiteral {
+                parameters,
+                return_type,
+                body: optimize_block(body),
+            }
+        }
+        ASTNodeKind::MethodCall {
+            object,
+            method,
+            arguments,
+        } => ASTNodeKind::MethodCall {
+            object: Box::new(optimize_node(*object)),
+            method,
+            arguments: optimize_all(arguments),
+        },
+        ASTNodeKind::FunctionCall { name, arguments } => ASTNodeKind::FunctionCall {
+            name,
+            arguments: optimize_all(arguments),
+        },
+        ASTNodeKind::Call { callee, arguments } => ASTNodeKind::Call {
+            callee: Box::new(optimize_node(*callee)),
+            arguments: optimize_all(arguments),
+        },
+        ASTNodeKind::MatchStatement { subject, mut arms } => {
+            for arm in &mut arms {
+                arm.pattern = arm.pattern.take().map(optimize_node);
+                arm.body = optimize_block(std::mem::take(&mut arm.body));
+            }
+            ASTNodeKind::MatchStatement {
+                subject: Box::new(optimize_node(*subject)),
+                arms,
+            }
+        }
+        ASTNodeKind::ForStatement {
+            var_type,
+            name,
+            iterable,
+            body,
+        } => ASTNodeKind::ForStatement {
+            var_type,
+            name,
+            iterable: Box::new(optimize_node(*iterable)),
+            body: optimize_block(body),
+        },
+        ASTNodeKind::Range { start, end, step } => ASTNodeKind::Range {
+            start: Box::new(optimize_node(*start)),
+            end: Box::new(optimize_node(*end)),
+            step: step.map(|step| Box::new(optimize_node(*step))),
+        },
+        ASTNodeKind::VariableDeclaration {
+            var_type,
+            name,
+            value,
+        } => ASTNodeKind::VariableDeclaration {
+            var_type,
+            name,
+            value: Box::new(optimize_node(*value)),
+        },
+        ASTNodeKind::StructDeclaration {
+            name,
+            fields,
+            methods,
+        } => ASTNodeKind::StructDeclaration {
+            name,
+            fields,
+            methods: optimize_all(methods),
+        },
+        ASTNodeKind::StructLiteral { name, fields } => ASTNodeKind::StructLiteral {
+            name,
+            fields: fields
+                .into_iter()
+                .map(|(field, value)| (field, optimize_node(value)))
+                .collect(),
+        },
+        ASTNodeKind::FieldAccess { object, field } => ASTNodeKind::FieldAccess {
+            object: Box::new(optimize_node(*object)),
+            field,
+        },
+        ASTNodeKind::Index { object, index } => ASTNodeKind::Index {
+            object: Box::new(optimize_node(*object)),
+            index: Box::new(optimize_node(*index)),
+        },
+        ASTNodeKind::ArrayLiteral(elements) => ASTNodeKind::ArrayLiteral(optimize_all(elements)),
+        ASTNodeKind::Test { name, body } => ASTNodeKind::Test {
+            name,
+            body: optimize_block(body),
+        },
+        // statements of a block go through `optimize_statement` instead
+        ASTNodeKind::IfStatement {
+            condition,
+            then_body,
+            else_body,
+        } => ASTNodeKind::IfStatement {
+            condition: Box::new(optimize_node(*condition)),
+            then_body: optimize_block(then_body),
+            else_body: else_body.map(optimize_block),
+        },
+        ASTNodeKind::WhileStatement { condition, body } => ASTNodeKind::WhileStatement {
+            condition: Box::new(optimize_node(*condition)),
+            body: optimize_block(body),
+        },
+        kind @ (ASTNodeKind::Break
+        | ASTNodeKind::Continue
+        | ASTNodeKind::Identifier(_)
+        | ASTNodeKind::NumberLiteral(_)
+        | ASTNodeKind::StringLiteral(_)
+        | ASTNodeKind::BooleanLiteral(_)
+        | ASTNodeKind::NoneLiteral
+        | ASTNodeKind::Import { .. }) => kind,
+    };
+
+    ASTNode::new(kind, span)
+}
+
+// whether a statement declares a name in the scope it is in
+fn declares(node: &ASTNode) -> bool {
+    matches!(
+        node.kind,
+        ASTNodeKind::VariableDeclaration { .. }
+            | ASTNodeKind::FunctionDeclaration { .. }
+            | ASTNodeKind::StructDeclaration { .. }
+    )
+}
+
+// the value of an operation on literals, operations that fail when the
+// program runs, like dividing by zero, are left for the vm to report
+fn fold_binary(left: &ASTNode, op: &Operator, right: &ASTNode) -> Option<ASTNodeKind> {
+    use ASTNodeKind::{BooleanLiteral, NoneLiteral, NumberLiteral, StringLiteral};
+
+    let folded = match (&left.kind, op, &right.kind) {
+        (NumberLiteral(a), _, NumberLiteral(b)) if !matches!(op, Operator::Concat) => {
+            let (a, b) = (*a, *b);
+            match op {
+                Operator::Plus => NumberLiteral(a + b),
+                Operator::Minus => NumberLiteral(a - b),
+                Operator::Multiply => NumberLiteral(a * b),
+                Operator::Divide if b != 0.0 => NumberLiteral(a / b),
+                Operator::Power => NumberLiteral(a.powf(b)),
+                Operator::Modulo if b != 0.0 => NumberLiteral(a % b),
+                Operator::Equals => BooleanLiteral(a == b),
+                Operator::NotEquals => BooleanLiteral(a != b),
+                Operator::GreaterThan => BooleanLiteral(a > b),
+                Operator::LessThan => BooleanLiteral(a < b),
+                Operator::GreaterThanOrEqual => BooleanLiteral(a >= b),
+                Operator::LessThanOrEqual => BooleanLiteral(a <= b),
+                _ => return None,
+            }
+        }
+        (_, Operator::Equals, _) => BooleanLiteral(literals_equal(&left.kind, &right.kind)?),
+        (_, Operator::NotEquals, _) => BooleanLiteral(!literals_equal(&left.kind, &right.kind)?),
+        // the right side only runs when the left one does not decide
+        (BooleanLiteral(false), Operator::LogicalAnd, _) => BooleanLiteral(false),
+        (BooleanLiteral(true), Operator::LogicalOr, _) => BooleanLiteral(true),
+        (BooleanLiteral(true), Operator::LogicalAnd, right)
+        | (BooleanLiteral(false), Operator::LogicalOr, right)
+        | (NoneLiteral, Operator::Coalesce, right) => right.clone(),
+        (left, Operator::Coalesce, _) if is_literal(left) => left.clone(),
+        (StringLiteral(_), Operator::Concat, _) | (_, Operator::Concat, StringLiteral(_)) => {
+            StringLiteral(literal_text(&left.kind)? + &literal_text(&right.kind)?)
+        }
+        _ => return None,
+    };
+    Some(folded)
+}
+
+fn is_literal(kind: &ASTNodeKind) -> bool {
+    matches!(
+        kind,
+        ASTNodeKind::NumberLiteral(_)
+            | ASTNodeKind::StringLiteral(_)
+            | ASTNodeKind::BooleanLiteral(_)
+    )
+}
+
+// `==` on two literals, `none` is only equal to `none`
+fn literals_equal(left: &ASTNodeKind, right: &ASTNodeKind) -> Option<bool> {
+    match (left, right) {
+        (ASTNodeKind::StringLiteral(a), ASTNodeKind::StringLiteral(b)) => Some(a == b),
+        (ASTNodeKind::BooleanLiteral(a), ASTNodeKind::BooleanLiteral(b)) => Some(a == b),
+        (ASTNodeKind::NoneLiteral, ASTNodeKind::NoneLiteral) => Some(true),
+        (ASTNodeKind::NoneLiteral, other) | (other, ASTNodeKind::NoneLiteral)
+            if is_literal(other) =>
+        {
+            Some(false)
+        }
+        _ => None,
+    }
+}
+
+// a literal the way `><` turns it into text
+fn literal_text(kind: &ASTNodeKind) -> Option<String> {
+    match kind {
+        ASTNodeKind::StringLiteral(text) => Some(text.clone()),
+        ASTNodeKind::NumberLiteral(value) => Some(value.to_string()),
+        ASTNodeKind::BooleanLiteral(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+
+use super::Capture;
+
+// where a variable lives at runtime
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    Local(u16),      // relative to the frame of the running function
+    Global(u32),     // index into the global table
+    Cell(u16),       // a local captured by a closure, shared through a cell
+    Upvalue(u16),    // index into the captures of the running closure
+    GlobalCell(u32), // a global declared in a block and captured by a closure
+}
+
+#[derive(Clone)]
+struct FunctionScope {
+    scopes: Vec<HashMap<String, (u16, bool)>>, // slot and whether it holds a function
+    next_slot: u16,
+    slot_count: u16,           // the most slots alive at the same time
+    captured: HashSet<String>, // names used by closures inside the function
+    closure: bool,             // function literals can use the variables around them
+    upvalues: Vec<(String, Capture)>,
+}
+
+impl FunctionScope {
+    fn local(&self, name: &str) -> Option<Slot> {
+        let (slot, _) = self.scopes.iter().rev().find_map(|scope| scope.get(name))?;
+        match self.captured.contains(name) {
+            true => Some(Slot::Cell(*slot)),
+            false => Some(Slot::Local(*slot)),
+        }
+    }
+}
+
+// assigns every variable a slot while the compiler walks the program, locals of
+// a function get frame relative slots that are reused once their block ends,
+// everything declared outside of a function becomes a global
+#[derive(Clone)]
+pub struct Resolver {
+    globals: Vec<HashMap<String, (Slot, bool)>>, // slot and whether it holds a function
+    global_count: u32,
+    captured: HashSet<String>, // names used by closures in the top level code
+    functions: Vec<FunctionScope>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            globals: vec![HashMap::new()],
+            global_count: 0,
+            captured: HashSet::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    // globals declared in a block with a name in `captured` are kept in cells,
+    // so closures created in a loop each keep their own like they do for locals
+    pub fn set_captured(&mut self, captured: HashSet<String>) {
+        self.captured = captured;
+    }
+
+    // locals named in `captured` are kept in cells so closures can share them
+    pub fn enter_function(&mut self, captured: HashSet<String>, closure: bool) {
+        self.functions.push(FunctionScope {
+            scopes: vec![HashMap::new()],
+            next_slot: 0,
+            slot_count: 0,
+            captured,
+            closure,
+            upvalues: Vec::new(),
+        });
+    }
+
+    // returns the number of slots the function frame needs and the variables
+    // it captured from the functions around it
+    pub fn exit_function(&mut self) -> (usize, Vec<Capture>) {
+        let function = self
+            .functions
+            .pop()
+            .expect("exit_function called outside of a function");
+        let captures = function
+            .upvalues
+            .into_iter()
+            .map(|(_, capture)| capture)
+            .collect();
+        (function.slot_count as usize, captures)
+    }
+
+    pub fn enter_scope(&mut self) {
+        match self.functions.last_mut() {
+            Some(function) => function.scopes.push(HashMap::new()),
+            None => self.globals.push(HashMap::new()),
+        }
+    }
+
+    pub fn exit_scope(&mut self) {
+        match self.functions.last_mut() {
+            Some(function) => {
+                // the slots of the block can be handed out again, they were
+                // allocated after every slot of the enclosing blocks
+                if let Some(first_slot) = function
+                    .scopes
+                    .pop()
+                    .and_then(|scope| scope.values().map(|(slot, _)| *slot).min())
+                {
+                    function.next_slot = first_slot;
+                }
+            }
+            None => {
+                // globals are never reused, functions declared in the block may
+                // still refer to them
+                if self.globals.len() > 1 {
+                    self.globals.pop();
+                }
+            }
+        }
+    }
+
+    // `callable` variables hold functions, calls by their name call the value
+    pub fn declare(&mut self, name: &str, callable: bool) -> Result<Slot, String> {
+        match self.functions.last_mut() {
+            Some(function) => {
+                let slot = function.next_slot;
+                if slot == u16::MAX {
+                    return Err("Too many local variables in function".to_string());
+                }
+
+                function.next_slot += 1;
+                function.slot_count = function.slot_count.max(function.next_slot);
+                function
+                    .scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), (slot, callable));
+
+                match function.captured.contains(name) {
+                    true => Ok(Slot::Cell(slot)),
+                    false => Ok(Slot::Local(slot)),
+                }
+            }
+            None => {
+                let index = self.global_count;
+                if index == u32::MAX {
+                    return Err("Too many global variables".to_string());
+                }
+
+                self.global_count += 1;
+                let slot = match self.globals.len() > 1 && self.captured.contains(name) {
+                    true => Slot::GlobalCell(index),
+                    false => Slot::Global(index),
+                };
+                self.globals
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), (slot, callable));
+
+                Ok(slot)
+            }
+        }
+    }
+
+    // whether a variable called `name` can be used here and holds a function,
+    // `None` if there is no such variable
+    pub fn lookup(&self, name: &str) -> Option<bool> {
+        let locals = self
+            .functions
+            .iter()
+            .rev()
+            .flat_map(|function| function.scopes.iter().rev())
+            .find_map(|scope| scope.get(name).map(|(_, callable)| *callable));
+
+        locals.or_else(|| {
+            self.globals
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name).map(|(_, callable)| *callable))
+        })
+    }
+
+    pub fn global(&self, name: &str) -> Option<u32> {
+        match self.global_slot(name)? {
+            Slot::Global(index) | Slot::GlobalCell(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    fn global_slot(&self, name: &str) -> Option<Slot> {
+        self.globals
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).map(|(slot, _)| *slot))
+    }
+
+    pub fn resolve(&mut self, name: &str) -> Result<Slot, String> {
+        if let Some(depth) = self.functions.len().checked_sub(1) {
+            if let Some(slot) = self.capture(depth, name)? {
+                return Ok(slot);
+            }
+        }
+
+        self.global_slot(name)
+            .ok_or_else(|| format!("Unknown variable '{}'", name))
+    }
+
+    // finds `name` in the function at `depth`, a closure captures the locals of
+    // the functions around it, each closure in between passes them on
+    fn capture(&mut self, depth: usize, name: &str) -> Result<Option<Slot>, String> {
+        let function = &self.functions[depth];
+        if let Some(slot) = function.local(name) {
+            return Ok(Some(slot));
+        }
+        if let Some(index) = function.upvalues.iter().position(|(n, _)| n == name) {
+            return Ok(Some(Slot::Upvalue(index as u16)));
+        }
+        if depth == 0 {
+            // closures in top level code capture the cells of globals like
+            // they capture locals
+            return match self.global_slot(name) {
+                Some(Slot::GlobalCell(index)) if function.closure => {
+                    self.add_upvalue(depth, name, Capture::GlobalCell(index))
+                }
+                _ => Ok(None),
+            };
+        }
+
+        // locals of enclosing functions are gone once they return
+        if !function.closure {
+            if self.functions[..depth]
+                .iter()
+                .any(|function| function.local(name).is_some())
+            {
+                return Err(format!(
+                    "Cannot use local variable '{}' of an enclosing function",
+                    name
+                ));
+            }
+            return Ok(None);
+        }
+
+        let capture = match self.capture(depth - 1, name)? {
+            Some(Slot::Cell(slot)) => Capture::Cell(slot),
+            Some(Slot::Upvalue(index)) => Capture::Upvalue(index),
+            Some(_) => return Err(format!("Cannot capture local variable '{}'", name)),
+            None => return Ok(None),
+        };
+        self.add_upvalue(depth, name, capture)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        depth: usize,
+        name: &str,
+        capture: Capture,
+    ) -> Result<Option<Slot>, String> {
+        let upvalues = &mut self.functions[depth].upvalues;
+        if upvalues.len() == u16::MAX as usize {
+            return Err("Too many captured variables in function".to_string());
+        }
+        upvalues.push((name.to_string(), capture));
+        Ok(Some(Slot::Upvalue(upvalues.len() as u16 - 1)))
+    }
+}
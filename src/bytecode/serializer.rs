@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+
+use super::{Capture, DebugInfo, FunctionParameter, Instruction};
+use crate::{diagnostics::Span, lexer::Type};
+
+// layout of a `.booc` file, every integer is little endian:
+//
+//   magic        b"BOOC"
+//   version      u16
+//   globals      u32, one more than the highest global index the code uses
+//   constants    u32 count, then per constant a tag (0 number, 1 string) and
+//                an f64 or a u32 length followed by utf-8 bytes
+//   instructions u32 count, then per instruction an opcode and its operands,
+//                numbers and strings are u32 indices into the constants
+//   spans        u32 count (0 or one per instruction), five u32 each
+//   files        u32 count, then the name of every imported file spans point
+//                into as a string constant index
+//   main file    u32 count (0 or 1), then the name of the file the code was
+//                compiled from as a string constant index
+pub const MAGIC: &[u8; 4] = b"BOOC";
+pub const FORMAT_VERSION: u16 = 7;
+
+const CONSTANT_NUMBER: u8 = 0;
+const CONSTANT_STRING: u8 = 1;
+
+// keeps malformed files from recursing without bound
+const MAX_TYPE_DEPTH: usize = 64;
+
+// locals are addressed by u16 slots, the resolver never makes more
+const MAX_SLOTS: usize = u16::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Constant {
+    Number(f64),
+    String(String),
+}
+
+pub fn serialize(instructions: &[Instruction], debug_info: &DebugInfo) -> Result<Vec<u8>, String> {
+    let mut writer = Writer::new();
+
+    writer.u32(instructions.len() as u32);
+    for instruction in instructions {
+        writer.instruction(instruction)?;
+    }
+
+    // spans are optional, a file without them reports runtime errors without a location
+    if debug_info.len() == instructions.len() {
+        writer.u32(debug_info.len() as u32);
+        for span in debug_info.spans() {
+            writer.u32(span.start as u32);
+            writer.u32(span.end as u32);
+            writer.u32(span.line as u32);
+            writer.u32(span.column as u32);
+            writer.u32(span.file as u32);
+        }
+    } else {
+        writer.u32(0);
+    }
+    writer.strings(debug_info.files())?;
+    let main_file: Vec<String> = debug_info
+        .main_file()
+        .map(str::to_string)
+        .into_iter()
+        .collect();
+    writer.strings(&main_file)?;
+
+    let global_count = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::LoadGlobal(index)
+            | Instruction::StoreGlobal(index)
+            | Instruction::MakeGlobalCell(index)
+            | Instruction::LoadGlobalCell(index)
+            | Instruction::StoreGlobalCell(index) => Some(*index as u64 + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let global_count =
+        u32::try_from(global_count).map_err(|_| "Too many globals for the bytecode format")?;
+
+    let mut output = Vec::with_capacity(writer.code.len() + 64);
+    output.extend_from_slice(MAGIC);
+    output.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    output.extend_from_slice(&global_count.to_le_bytes());
+
+    output.extend_from_slice(&(writer.constants.len() as u32).to_le_bytes());
+    for constant in &writer.constants {
+        match constant {
+            Constant::Number(value) => {
+                output.push(CONSTANT_NUMBER);
+                output.extend_from_slice(&value.to_le_bytes());
+            }
+            Constant::String(value) => {
+                output.push(CONSTANT_STRING);
+                output.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                output.extend_from_slice(value.as_bytes());
+            }
+        }
+    }
+
+    output.extend_from_slice(&writer.code);
+    Ok(output)
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<(Vec<Instruction>, DebugInfo), String> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        constants: Vec::new(),
+    };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a boo bytecode file (bad magic header)".to_string());
+    }
+
+    let version = reader.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {}, expected {}",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let global_count = reader.u32()?;
+
+    let constant_count = reader.count()?;
+    for _ in 0..constant_count {
+        let constant = match reader.u8()? {
+            CONSTANT_NUMBER => Constant::Number(reader.f64()?),
+            CONSTANT_STRING => {
+                let length = reader.count()?;
+                let bytes = reader.take(length)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(value) => Constant::String(value.to_string()),
+                    Err(_) => return Err("Invalid utf-8 in string constant".to_string()),
+                }
+            }
+            tag => return Err(format!("Unknown constant tag {}", tag)),
+        };
+        reader.constants.push(constant);
+    }
+
+    let instruction_count = reader.count()?;
+    // counts are untrusted, every instruction takes at least one byte
+    let mut instructions = Vec::with_capacity(instruction_count.min(reader.remaining()));
+    for _ in 0..instruction_count {
+        instructions.push(reader.instruction()?);
+    }
+
+    let span_count = reader.count()?;
+    if span_count != 0 && span_count != instruction_count {
+        return Err(format!(
+            "Expected {} spans, found {}",
+            instruction_count, span_count
+        ));
+    }
+
+    let mut spans = Vec::with_capacity(span_count.min(reader.remaining() / 20));
+    for _ in 0..span_count {
+        spans.push(Span {
+            start: reader.u32()? as usize,
+            end: reader.u32()? as usize,
+            line: reader.u32()? as usize,
+            column: reader.u32()? as usize,
+            file: reader.u32()? as usize,
+        });
+    }
+    let files = reader.strings()?;
+    let mut main_file = reader.strings()?;
+    if main_file.len() > 1 {
+        return Err(format!(
+            "Expected at most 1 main file, found {}",
+            main_file.len()
+        ));
+    }
+
+    if reader.position != bytes.len() {
+        return Err("Unexpected data after the end of the program".to_string());
+    }
+
+    validate(&instructions, global_count)?;
+
+    Ok((instructions, DebugInfo::new(spans, files, main_file.pop())))
+}
+
+// checks what the VM relies on without checking it itself, operands that
+// would make it allocate without bound are rejected as well
+fn validate(instructions: &[Instruction], global_count: u32) -> Result<(), String> {
+    match instructions.last() {
+        Some(Instruction::End) => (),
+        _ => return Err("Program does not end with an End instruction".to_string()),
+    }
+
+    // cells only exist in functions, a cell slot is a slot of the function
+    let max_slot_count = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::DeclareFunction(_, _, slot_count) => Some(*slot_count),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    for (address, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target)
+            | Instruction::JumpIfNotVoid(target)
+                if *target >= instructions.len() =>
+            {
+                return Err(format!(
+                    "Jump target {} out of range at instruction {}",
+                    target, address
+                ));
+            }
+            Instruction::DeclareFunction(name, parameters, slot_count)
+                if *slot_count < parameters.len() =>
+            {
+                return Err(format!(
+                    "Function '{}' has fewer slots than parameters",
+                    name
+                ));
+            }
+            Instruction::DeclareFunction(name, _, slot_count) if *slot_count > MAX_SLOTS => {
+                return Err(format!(
+                    "Function '{}' has {} slots, at most {} are allowed",
+                    name, slot_count, MAX_SLOTS
+                ));
+            }
+            Instruction::LoadGlobal(index)
+            | Instruction::StoreGlobal(index)
+            | Instruction::MakeGlobalCell(index)
+            | Instruction::LoadGlobalCell(index)
+            | Instruction::StoreGlobalCell(index)
+                if *index >= global_count =>
+            {
+                return Err(format!(
+                    "Global index {} out of range at instruction {}",
+                    index, address
+                ));
+            }
+            Instruction::MakeCell(slot)
+            | Instruction::LoadCell(slot)
+            | Instruction::StoreCell(slot)
+                if *slot as usize >= max_slot_count =>
+            {
+                return Err(format!(
+                    "Cell slot {} out of range at instruction {}",
+                    slot, address
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+struct Writer {
+    code: Vec<u8>,
+    constants: Vec<Constant>,
+    strings: HashMap<String, u32>,
+    numbers: HashMap<u64, u32>, // keyed by the bits, f64 is not hashable
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            constants: Vec::new(),
+            strings: HashMap::new(),
+            numbers: HashMap::new(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.code.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn count(&mut self, value: usize) -> Result<(), String> {
+        match u32::try_from(value) {
+            Ok(value) => {
+                self.u32(value);
+                Ok(())
+            }
+            Err(_) => Err(format!(
+                "Operand {} is too large for the bytecode format",
+                value
+            )),
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        let index = match self.strings.get(value) {
+            Some(&index) => index,
+            None => {
+                let index = self.constants.len() as u32;
+                self.constants.push(Constant::String(value.to_string()));
+                self.strings.insert(value.to_string(), index);
+                index
+            }
+        };
+        self.u32(index);
+    }
+
+    fn number(&mut self, value: f64) {
+        let index = match self.numbers.get(&value.to_bits()) {
+            Some(&index) => index,
+            None => {
+                let index = self.constants.len() as u32;
+                self.constants.push(Constant::Number(value));
+                self.numbers.insert(value.to_bits(), index);
+                index
+            }
+        };
+        self.u32(index);
+    }
+
+    fn strings(&mut self, values: &[String]) -> Result<(), String> {
+        self.count(values.len())?;
+        for value in values {
+            self.string(value);
+        }
+        Ok(())
+    }
+
+    fn value_type(&mut self, value_type: &Type) -> Result<(), String> {
+        match value_type {
+            Type::Str => self.u8(0),
+            Type::Num => self.u8(1),
+            Type::Bool => self.u8(2),
+            Type::Void => self.u8(3),
+            Type::Array(element_type) => {
+                self.u8(4);
+                self.value_type(element_type)?;
+            }
+            Type::Struct(name) => {
+                self.u8(5);
+                self.string(name);
+            }
+            Type::Function(parameters, return_type) => {
+                self.u8(6);
+                self.count(parameters.len())?;
+                for parameter in parameters {
+                    self.value_type(parameter)?;
+                }
+                self.value_type(return_type)?;
+            }
+            Type::Optional(inner) => {
+                self.u8(7);
+                self.value_type(inner)?;
+            }
+            Type::Error => return Err("Cannot serialize an unchecked program".to_string()),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::PushNumber(value) => {
+                self.u8(0);
+                self.number(*value);
+            }
+            Instruction::PushString(value) => {
+                self.u8(1);
+                self.string(value);
+            }
+            Instruction::PushBoolean(value) => {
+                self.u8(2);
+                self.u8(*value as u8);
+            }
+            Instruction::PushVoid => self.u8(3),
+            Instruction::Pop => self.u8(4),
+            Instruction::Duplicate(count) => {
+                self.u8(5);
+                self.count(*count)?;
+            }
+            Instruction::Negate => self.u8(6),
+            Instruction::LogicalNot => self.u8(7),
+            Instruction::LoadLocal(slot) => {
+                self.u8(8);
+                self.u16(*slot);
+            }
+            Instruction::StoreLocal(slot) => {
+                self.u8(9);
+                self.u16(*slot);
+            }
+            Instruction::LoadGlobal(index) => {
+                self.u8(10);
+                self.u32(*index);
+            }
+            Instruction::StoreGlobal(index) => {
+                self.u8(11);
+                self.u32(*index);
+            }
+            Instruction::MakeArray(count) => {
+                self.u8(12);
+                self.count(*count)?;
+            }
+            Instruction::LoadIndex => self.u8(13),
+            Instruction::StoreIndex => self.u8(14),
+            Instruction::DeclareStruct(name, fields) => {
+                self.u8(15);
+                self.string(name);
+                self.strings(fields)?;
+            }
+            Instruction::MakeStruct(name, fields) => {
+                self.u8(16);
+                self.string(name);
+                self.strings(fields)?;
+            }
+            Instruction::LoadField(field) => {
+                self.u8(17);
+                self.string(field);
+            }
+            Instruction::StoreField(field) => {
+                self.u8(18);
+                self.string(field);
+            }
+            Instruction::Add => self.u8(19),
+            Instruction::Subtract => self.u8(20),
+            Instruction::Multiply => self.u8(21),
+            Instruction::Divide => self.u8(22),
+            Instruction::Power => self.u8(23),
+            Instruction::Modulo => self.u8(24),
+            Instruction::Concat => self.u8(25),
+            Instruction::Equals => self.u8(26),
+            Instruction::NotEquals => self.u8(27),
+            Instruction::GreaterThan => self.u8(28),
+            Instruction::LessThan => self.u8(29),
+            Instruction::GreaterThanOrEqual => self.u8(30),
+            Instruction::LessThanOrEqual => self.u8(31),
+            Instruction::Jump(address) => {
+                self.u8(32);
+                self.count(*address)?;
+            }
+            Instruction::JumpIfFalse(address) => {
+                self.u8(33);
+                self.count(*address)?;
+            }
+            Instruction::JumpIfTrue(address) => {
+                self.u8(34);
+                self.count(*address)?;
+            }
+            Instruction::DeclareFunction(name, parameters, slot_count) => {
+                self.u8(35);
+                self.string(name);
+                self.count(parameters.len())?;
+                for parameter in parameters {
+                    self.string(&parameter.name);
+                    self.value_type(&parameter.param_type)?;
+                    self.u8(parameter.optional as u8);
+                }
+                self.count(*slot_count)?;
+            }
+            Instruction::Call(name, arg_count) => {
+                self.u8(36);
+                self.string(name);
+                self.count(*arg_count)?;
+            }
+            Instruction::CallMethod(name, arg_count) => {
+                self.u8(37);
+                self.string(name);
+                self.count(*arg_count)?;
+            }
+            Instruction::Return => self.u8(38),
+            Instruction::End => self.u8(39),
+            Instruction::MakeCell(slot) => {
+                self.u8(40);
+                self.u16(*slot);
+            }
+            Instruction::LoadCell(slot) => {
+                self.u8(41);
+                self.u16(*slot);
+            }
+            Instruction::StoreCell(slot) => {
+                self.u8(42);
+                self.u16(*slot);
+            }
+            Instruction::LoadUpvalue(index) => {
+                self.u8(43);
+                self.u16(*index);
+            }
+            Instruction::StoreUpvalue(index) => {
+                self.u8(44);
+                self.u16(*index);
+            }
+            Instruction::MakeClosure(name, function_type, captures) => {
+                self.u8(45);
+                self.string(name);
+                self.value_type(function_type)?;
+                self.count(captures.len())?;
+                for capture in captures {
+                    match capture {
+                        Capture::Cell(slot) => {
+                            self.u8(0);
+                            self.u16(*slot);
+                        }
+                        Capture::Upvalue(index) => {
+                            self.u8(1);
+                            self.u16(*index);
+                        }
+                        Capture::GlobalCell(index) => {
+                            self.u8(2);
+                            self.u32(*index);
+                        }
+                    }
+                }
+            }
+            Instruction::CallValue(arg_count) => {
+                self.u8(46);
+                self.count(*arg_count)?;
+            }
+            Instruction::PushNone => self.u8(47),
+            Instruction::JumpIfNotVoid(address) => {
+                self.u8(48);
+                self.count(*address)?;
+            }
+            Instruction::MakeGlobalCell(index) => {
+                self.u8(49);
+                self.u32(*index);
+            }
+            Instruction::LoadGlobalCell(index) => {
+                self.u8(50);
+                self.u32(*index);
+            }
+            Instruction::StoreGlobalCell(index) => {
+                self.u8(51);
+                self.u32(*index);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    constants: Vec<Constant>,
+}
+
+impl Reader<'_> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], String> {
+        match self
+            .bytes
+            .get(self.position..self.position.saturating_add(length))
+        {
+            Some(bytes) => {
+                self.position += length;
+                Ok(bytes)
+            }
+            None => Err("Unexpected end of file".to_string()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn count(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(format!("Invalid boolean {}", value)),
+        }
+    }
+
+    fn constant(&mut self) -> Result<&Constant, String> {
+        let index = self.count()?;
+        self.constants
+            .get(index)
+            .ok_or_else(|| format!("Constant index {} out of range", index))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.constant()? {
+            Constant::String(value) => Ok(value.clone()),
+            Constant::Number(_) => Err("Expected a string constant, found a number".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        match self.constant()? {
+            Constant::Number(value) => Ok(*value),
+            Constant::String(_) => Err("Expected a number constant, found a string".to_string()),
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        let count = self.count()?;
+        let mut values = Vec::with_capacity(count.min(self.remaining() / 4));
+        for _ in 0..count {
+            values.push(self.string()?);
+        }
+        Ok(values)
+    }
+
+    fn value_type(&mut self, depth: usize) -> Result<Type, String> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err("Type is nested too deeply".to_string());
+        }
+
+        match self.u8()? {
+            0 => Ok(Type::Str),
+            1 => Ok(Type::Num),
+            2 => Ok(Type::Bool),
+            3 => Ok(Type::Void),
+            4 => Ok(Type::Array(Box::new(self.value_type(depth + 1)?))),
+            5 => Ok(Type::Struct(self.string()?)),
+            6 => {
+                let count = self.count()?;
+                let mut parameters = Vec::with_capacity(count.min(self.remaining()));
+                for _ in 0..count {
+                    parameters.push(self.value_type(depth + 1)?);
+                }
+                let return_type = self.value_type(depth + 1)?;
+                Ok(Type::Function(parameters, Box::new(return_type)))
+            }
+            7 => Ok(Type::Optional(Box::new(self.value_type(depth + 1)?))),
+            tag => Err(format!("Unknown type tag {}", tag)),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let instruction = match self.u8()? {
+            0 => Instruction::PushNumber(self.number()?),
+            1 => Instruction::PushString(self.string()?),
+            2 => Instruction::PushBoolean(self.bool()?),
+            3 => Instruction::PushVoid,
+            4 => Instruction::Pop,
+            5 => Instruction::Duplicate(self.count()?),
+            6 => Instruction::Negate,
+            7 => Instruction::LogicalNot,
+            8 => Instruction::LoadLocal(self.u16()?),
+            9 => Instruction::StoreLocal(self.u16()?),
+            10 => Instruction::LoadGlobal(self.u32()?),
+            11 => Instruction::StoreGlobal(self.u32()?),
+            12 => Instruction::MakeArray(self.count()?),
+            13 => Instruction::LoadIndex,
+            14 => Instruction::StoreIndex,
+            15 => Instruction::DeclareStruct(self.string()?, self.strings()?),
+            16 => Instruction::MakeStruct(self.string()?, self.strings()?),
+            17 => Instruction::LoadField(self.string()?),
+            18 => Instruction::StoreField(self.string()?),
+            19 => Instruction::Add,
+            20 => Instruction::Subtract,
+            21 => Instruction::Multiply,
+            22 => Instruction::Divide,
+            23 => Instruction::Power,
+            24 => Instruction::Modulo,
+            25 => Instruction::Concat,
+            26 => Instruction::Equals,
+            27 => Instruction::NotEquals,
+            28 => Instruction::GreaterThan,
+            29 => Instruction::LessThan,
+            30 => Instruction::GreaterThanOrEqual,
+            31 => Instruction::LessThanOrEqual,
+            32 => Instruction::Jump(self.count()?),
+            33 => Instruction::JumpIfFalse(self.count()?),
+            34 => Instruction::JumpIfTrue(self.count()?),
+            35 => {
+                let name = self.string()?;
+                let count = self.count()?;
+                let mut parameters = Vec::new();
+                for _ in 0..count {
+                    parameters.push(FunctionParameter {
+                        name: self.string()?,
+                        param_type: self.value_type(0)?,
+                        optional: self.bool()?,
+                    });
+                }
+                Instruction::DeclareFunction(name, parameters, self.count()?)
+            }
+            36 => Instruction::Call(self.string()?, self.count()?),
+            37 => Instruction::CallMethod(self.string()?, self.count()?),
+            38 => Instruction::Return,
+            39 => Instruction::End,
+            40 => Instruction::MakeCell(self.u16()?),
+            41 => Instruction::LoadCell(self.u16()?),
+            42 => Instruction::StoreCell(self.u16()?),
+            43 => Instruction::LoadUpvalue(self.u16()?),
+            44 => Instruction::StoreUpvalue(self.u16()?),
+            45 => {
+                let name = self.string()?;
+                let function_type = self.value_type(0)?;
+                let count = self.count()?;
+                let mut captures = Vec::with_capacity(count.min(self.remaining() / 3));
+                for _ in 0..count {
+                    captures.push(match self.u8()? {
+                        0 => Capture::Cell(self.u16()?),
+                        1 => Capture::Upvalue(self.u16()?),
+                        2 => Capture::GlobalCell(self.u32()?),
+                        tag => return Err(format!("Unknown capture tag {}", tag)),
+                    });
+                }
+                Instruction::MakeClosure(name, function_type, captures)
+            }
+            46 => Instruction::CallValue(self.count()?),
+            47 => Instruction::PushNone,
+            48 => Instruction::JumpIfNotVoid(self.count()?),
+            49 => Instruction::MakeGlobalCell(self.u32()?),
+            50 => Instruction::LoadGlobalCell(self.u32()?),
+            51 => Instruction::StoreGlobalCell(self.u32()?),
+            opcode => return Err(format!("Unknown opcode {}", opcode)),
+        };
+        Ok(instruction)
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,  // byte offset of the first character
+    pub end: usize,    // byte offset one past the last character
+    pub line: usize,   // 1-based line of the first character
+    pub column: usize, // 1-based column of the first character
+    pub file: FileId,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+            file: MAIN_FILE,
+        }
+    }
+
+    pub fn in_file(self, file: FileId) -> Span {
+        Span { file, ..self }
+    }
+
+    // creates a span covering both spans, keeping the position of the first one
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            end: self.end.max(other.end),
+            ..self
+        }
+    }
+}
+
+// identifies the source file a span points into
+pub type FileId = usize;
+
+// the source being compiled or run, other files are imported modules
+pub const MAIN_FILE: FileId = 0;
+
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+}
+
+// the imported files spans can point into, the main source is not part of it
+// since its name and text are passed along when rendering
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>, // file `i + 1` is at index `i`
+}
+
+impl SourceMap {
+    pub fn add(&mut self, name: String, source: String) -> FileId {
+        self.files.push(SourceFile { name, source });
+        self.files.len()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.files.iter().map(|file| file.name.clone()).collect()
+    }
+
+    pub fn get(&self, file: FileId) -> Option<&SourceFile> {
+        file.checked_sub(1).and_then(|index| self.files.get(index))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    Lexer,
+    Parser,
+    Type,
+    Compiler,
+    Runtime,
+    Import,
+    Limit(Limit), // a runtime error caused by one of the configured limits
+}
+
+// the limit that stopped a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Fuel,
+    CallDepth,
+    StackSize,
+    StringBytes,
+    Interrupted,
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DiagnosticKind::Lexer => "lexer",
+            DiagnosticKind::Parser => "parser",
+            DiagnosticKind::Type => "type",
+            DiagnosticKind::Compiler => "compiler",
+            DiagnosticKind::Import => "import",
+            DiagnosticKind::Runtime | DiagnosticKind::Limit(_) => "runtime",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning, // reported, but the program still runs
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// a function that was running when a runtime error happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub span: Option<Span>, // where the function was when the error happened
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub trace: Vec<TraceFrame>, // innermost function first, only for runtime errors
+}
+
+impl Diagnostic {
+    pub fn new(kind: DiagnosticKind, message: String, span: Option<Span>) -> Self {
+        Self {
+            kind,
+            severity: Severity::Error,
+            message,
+            span,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn warning(kind: DiagnosticKind, message: String, span: Option<Span>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(kind, message, span)
+        }
+    }
+
+    // renders the diagnostic as `file:line:column` followed by the offending
+    // source line with the span underlined and the stack trace
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        self.render_with(filename, source, &SourceMap::default())
+    }
+
+    // like `render`, spans in imported files are looked up in `sources`
+    pub fn render_with(&self, filename: &str, source: &str, sources: &SourceMap) -> String {
+        // the name and text of the file a span points into
+        let file = |span: Option<Span>| match span.map(|span| span.file) {
+            None | Some(MAIN_FILE) => (filename, source),
+            Some(file) => match sources.get(file) {
+                Some(file) => (file.name.as_str(), file.source.as_str()),
+                None => ("<unknown file>", ""),
+            },
+        };
+
+        let (filename, source) = file(self.span);
+        let mut output = self.render_location(filename, source);
+
+        if !self.trace.is_empty() {
+            output.push_str("\nstack trace:");
+        }
+
+        // deep recursion repeats the same frame, it is shown once with a count
+        let mut frames = self.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeated = 0;
+            while frames.next_if_eq(&frame).is_some() {
+                repeated += 1;
+            }
+
+            let (filename, _) = file(frame.span);
+            match frame.span {
+                Some(span) if span.line > 0 => output.push_str(&format!(
+                    "\n  at {} ({}:{})",
+                    frame.function, filename, span.line
+                )),
+                _ => output.push_str(&format!("\n  at {} ({})", frame.function, filename)),
+            }
+            if repeated > 0 {
+                output.push_str(&format!("\n  ... repeated {} more times", repeated));
+            }
+        }
+
+        output
+    }
+
+    fn render_location(&self, filename: &str, source: &str) -> String {
+        let span = match self.span {
+            Some(span) if span.line > 0 => span,
+            _ => {
+                return format!(
+                    "{}: {} {}: {}",
+                    filename, self.kind, self.severity, self.message
+                )
+            }
+        };
+
+        let mut output = format!(
+            "{}:{}:{}: {} {}: {}",
+            filename, span.line, span.column, self.kind, self.severity, self.message
+        );
+
+        let line = match source.lines().nth(span.line - 1) {
+            Some(line) => line,
+            None => return output,
+        };
+
+        // keep tabs in the padding so the caret lines up with the source
+        let padding: String = line
+            .chars()
+            .take(span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let remaining = line.chars().count().saturating_sub(span.column - 1);
+        let length = source
+            .get(span.start..span.end)
+            .map(|text| text.chars().take_while(|c| *c != '\n').count())
+            .unwrap_or(1)
+            .clamp(1, remaining.max(1));
+
+        let gutter = " ".repeat(span.line.to_string().len());
+        output.push_str(&format!("\n{} |\n{} | {}\n", gutter, span.line, line));
+        output.push_str(&format!("{} | {}{}", gutter, padding, "^".repeat(length)));
+
+        output
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) if span.line > 0 => write!(
+                f,
+                "{}:{}: {} {}: {}",
+                span.line, span.column, self.kind, self.severity, self.message
+            ),
+            _ => write!(f, "{} {}: {}", self.kind, self.severity, self.message),
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod diagnostics;
+
+pub use diagnostics::*;
@@ -0,0 +1,504 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    analyzer::TypeChecker,
+    bytecode::{self, Bytecode, DebugInfo, Instruction, Labels, OptLevel},
+    diagnostics::{Diagnostic, DiagnosticKind, SourceMap},
+    lexer::{Lexer, Type},
+    modules::ModuleLoader,
+    parser::{ASTNode, ASTNodeKind, Parser},
+    stdlib::natives::{NativeParameter, NativeSignature, NativeType, Natives},
+    vm::{InterruptHandle, Limits, Value, VM},
+};
+
+// everything that went wrong while compiling or running code
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Error {
+    fn new(kind: DiagnosticKind, message: String) -> Self {
+        Self {
+            diagnostics: vec![Diagnostic::new(kind, message, None)],
+        }
+    }
+
+    // renders every diagnostic with the source line it points at
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        self.render_with(filename, source, &SourceMap::default())
+    }
+
+    // like `render`, diagnostics in imported modules are looked up in `sources`
+    pub fn render_with(&self, filename: &str, source: &str, sources: &SourceMap) -> String {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render_with(filename, source, sources))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl From<Diagnostic> for Error {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self {
+            diagnostics: vec![diagnostic],
+        }
+    }
+}
+
+impl From<Vec<Diagnostic>> for Error {
+    fn from(diagnostics: Vec<Diagnostic>) -> Self {
+        Self { diagnostics }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+// compiled code, `start` is the address the compiled source begins at, the
+// code before it belongs to sources compiled earlier by the same engine
+#[derive(Clone, Debug)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    debug_info: DebugInfo,
+    labels: Labels,
+    start: usize,
+}
+
+impl Program {
+    // loads a program written by `to_bytes`, like a `.booc` file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, Error> {
+        let (instructions, debug_info) = bytecode::deserialize(bytes)
+            .map_err(|message| Error::new(DiagnosticKind::Compiler, message))?;
+        Ok(Program {
+            instructions,
+            debug_info,
+            labels: Labels::default(),
+            start: 0,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        bytecode::serialize(&self.instructions, &self.debug_info)
+            .map_err(|message| Error::new(DiagnosticKind::Compiler, message))
+    }
+
+    pub fn disassemble(&self) -> String {
+        bytecode::disassemble(&self.instructions, &self.labels)
+    }
+
+    // the instructions from `start` on, one per line with their address
+    pub fn disassemble_from_start(&self) -> String {
+        self.instructions
+            .iter()
+            .enumerate()
+            .skip(self.start)
+            .map(|(address, instruction)| format!("{:04}  {}\n", address, instruction))
+            .collect()
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    // the files the program imports by name, their source is not kept with
+    // the code
+    pub fn source_map(&self) -> SourceMap {
+        self.debug_info.source_map()
+    }
+
+    // the name of the file the program was compiled from, kept in `.booc`
+    // files so errors can point at it
+    pub fn main_file(&self) -> Option<&str> {
+        self.debug_info.main_file()
+    }
+}
+
+// a boo session for host programs, every compiled source can use the
+// functions, structs and globals of the sources compiled before it
+pub struct Engine {
+    natives: Rc<Natives>,
+    checker: TypeChecker,
+    compiler: Bytecode,
+    vm: VM,
+    modules: ModuleLoader,
+    warnings: Vec<Diagnostic>,
+    last_compile: Option<Snapshot>,
+}
+
+// the engine before a source was compiled, a source that fails to compile or
+// to run is forgotten by going back to it
+#[derive(Clone)]
+struct Snapshot {
+    checker: TypeChecker,
+    compiler: Bytecode,
+    modules: ModuleLoader,
+    program: (usize, usize), // start and end of the compiled program
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        let empty = ASTNode::new(ASTNodeKind::Program(Vec::new()), Default::default());
+        let natives = Rc::new(Natives::new());
+
+        Self {
+            checker: TypeChecker::with_natives(empty.clone(), natives.clone()),
+            compiler: Bytecode::new(),
+            vm: VM::with_natives(Vec::new(), natives.clone()),
+            natives,
+            modules: ModuleLoader::new(),
+            warnings: Vec::new(),
+            last_compile: None,
+        }
+    }
+
+    // a directory imports are searched in when they are not found next to
+    // the importing file
+    pub fn add_import_path(&mut self, path: impl Into<PathBuf>) {
+        self.modules.add_search_path(path);
+    }
+
+    // the imported files, see `Error::render_with`
+    pub fn sources(&self) -> &SourceMap {
+        self.modules.sources()
+    }
+
+    // makes a host function callable from boo code compiled afterwards, calls
+    // resolve to natives first so it cannot take the name of a boo function,
+    // and compiled code keeps the signature it was checked against
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        parameters: Vec<NativeParameter>,
+        return_type: NativeType,
+        function: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    ) -> Result<(), Error> {
+        if self.checker.has_function(name) {
+            return Err(Error::new(
+                DiagnosticKind::Type,
+                format!(
+                    "Function '{}' is declared in boo code and cannot be replaced by a native function",
+                    name
+                ),
+            ));
+        }
+
+        let signature = NativeSignature {
+            parameters,
+            return_type,
+        };
+        if let Some(existing) = self.natives.function(name) {
+            if existing.signature != signature && !self.compiler.is_empty() {
+                return Err(Error::new(
+                    DiagnosticKind::Type,
+                    format!(
+                        "Native function '{}' cannot change its signature after code was compiled",
+                        name
+                    ),
+                ));
+            }
+        }
+
+        // the type checker trusts the return type, so the result is checked
+        let native = name.to_string();
+        let return_type = signature.return_type.clone();
+        let checked = move |args| {
+            let result = function(args)?;
+            if return_type.accepts(&result) {
+                return Ok(result);
+            }
+            Err(match &return_type {
+                NativeType::Exact(t) => format!(
+                    "Native function '{}' returned '{:?}', expected '{:?}'",
+                    native,
+                    result.value_type(),
+                    t
+                ),
+                _ => format!(
+                    "Native function '{}' returned nothing, expected a value",
+                    native
+                ),
+            })
+        };
+
+        Rc::make_mut(&mut self.natives).register_function(
+            name,
+            signature.parameters,
+            signature.return_type,
+            checked,
+        );
+        self.checker.set_natives(self.natives.clone());
+        self.vm.set_natives(self.natives.clone());
+        self.last_compile = None;
+        Ok(())
+    }
+
+    // bounds every later run and call, exceeding one fails with a
+    // `DiagnosticKind::Limit` error and leaves the engine usable
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.set_limits(limits);
+    }
+
+    // stops the running program from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.vm.interrupt_handle()
+    }
+
+    // code compiled afterwards runs the test called `name` where it is
+    // declared, without one tests are skipped like any `boo run` does
+    pub fn set_test(&mut self, name: Option<&str>) {
+        self.compiler.set_test(name.map(str::to_string));
+    }
+
+    // how code compiled afterwards is optimized, `OptLevel::O1` unless set
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.compiler.set_opt_level(opt_level);
+    }
+
+    // checks and compiles source without running it, programs have to be run
+    // in the order they were compiled
+    pub fn compile(&mut self, source: &str) -> Result<Program, Error> {
+        self.compile_source(source, None, false)
+    }
+
+    // compiles the source of the file at `path`, its imports are searched
+    // relative to it
+    pub fn compile_file(&mut self, path: &Path, source: &str) -> Result<Program, Error> {
+        self.compile_source(source, Some(path), false)
+    }
+
+    // a failed run of the last compiled program forgets its declarations, the
+    // globals it did not get to set would be unusable otherwise
+    pub fn run(&mut self, program: &Program) -> Result<Option<Value>, Error> {
+        self.vm
+            .load(program.instructions.clone(), program.debug_info.clone());
+        let result = self.vm.run_from(program.start);
+
+        let bounds = (program.start, program.instructions.len());
+        match self.last_compile.take() {
+            Some(snapshot) if result.is_err() && snapshot.program == bounds => {
+                self.restore(snapshot);
+            }
+            _ => (),
+        }
+        Ok(result?)
+    }
+
+    // compiles and runs source, returning the value of a trailing expression
+    pub fn eval(&mut self, source: &str) -> Result<Option<Value>, Error> {
+        let program = self.compile_source(source, None, true)?;
+        self.run(&program)
+    }
+
+    // warnings found since the last call, they never stop code from running
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    // calls a function declared by code that has already run
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let arg_types: Vec<Type> = args.iter().map(Value::value_type).collect();
+        self.checker
+            .check_call(name, &arg_types)
+            .map_err(|message| Error::new(DiagnosticKind::Type, message))?;
+
+        Ok(self.vm.call(name, args)?)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let index = self.compiler.global_slot(name)?;
+        self.vm.global(index).cloned()
+    }
+
+    // sets a global, declaring it for code compiled afterwards if it does not
+    // exist yet, an existing global keeps its type
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), Error> {
+        self.checker
+            .declare_global(name, value.value_type())
+            .map_err(|message| Error::new(DiagnosticKind::Type, message))?;
+
+        let index = match self.compiler.global_slot(name) {
+            Some(index) => index,
+            None => self
+                .compiler
+                .declare_global(name, matches!(value.value_type(), Type::Function(..)))
+                .map_err(|message| Error::new(DiagnosticKind::Compiler, message))?,
+        };
+
+        self.vm.set_global(index, value);
+        // going back to before the last compile would undeclare it
+        self.last_compile = None;
+        Ok(())
+    }
+
+    // the type of an expression, without running it
+    pub fn type_of(&self, expression: &str) -> Result<Type, Error> {
+        let expression = self.modules.link_expression(parse_expression(
+            expression,
+            &self.modules,
+            &self.checker,
+        )?)?;
+        let mut checker = self.checker.clone();
+        Ok(checker.check_statements(vec![expression])?)
+    }
+
+    // the code an expression compiles to, without running it
+    pub fn compile_expression(&self, expression: &str) -> Result<Program, Error> {
+        let expression = self.modules.link_expression(parse_expression(
+            expression,
+            &self.modules,
+            &self.checker,
+        )?)?;
+        let mut compiler = self.compiler.clone();
+        let start = compiler.append(ASTNode::new(
+            ASTNodeKind::Program(vec![expression]),
+            Default::default(),
+        ))?;
+
+        Ok(Program {
+            instructions: compiler.instructions(),
+            debug_info: compiler.debug_info(),
+            labels: compiler.labels(),
+            start,
+        })
+    }
+
+    // imports are searched next to `file`, or in the working directory for
+    // source without a file
+    fn compile_source(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        keep_result: bool,
+    ) -> Result<Program, Error> {
+        let statements = parse(source, &self.modules, &self.checker)?;
+        let directory = file
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        // a failed compile must not leave declarations or modules behind
+        let mut snapshot = Snapshot {
+            checker: self.checker.clone(),
+            compiler: self.compiler.clone(),
+            modules: self.modules.clone(),
+            program: (0, 0),
+        };
+        let result = self
+            .modules
+            .link(statements, &directory, file)
+            .map_err(Error::from)
+            .and_then(|statements| self.compile_statements(statements, file, keep_result));
+
+        match &result {
+            Ok(program) => {
+                snapshot.program = (program.start, program.instructions.len());
+                self.last_compile = Some(snapshot);
+            }
+            Err(_) => self.restore(snapshot),
+        }
+        result
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.checker = snapshot.checker;
+        self.compiler = snapshot.compiler;
+        self.modules.restore(snapshot.modules);
+    }
+
+    fn compile_statements(
+        &mut self,
+        mut statements: Vec<ASTNode>,
+        file: Option<&Path>,
+        keep_result: bool,
+    ) -> Result<Program, Error> {
+        // check everything first, nothing is compiled for code with errors
+        let result = self.checker.check_statements(statements.clone());
+        self.warnings.extend(self.checker.take_warnings());
+        let result_type = result?;
+
+        // a trailing expression keeps its value on the stack so it is returned
+        if keep_result && result_type != Type::Void {
+            if let Some(last) = statements.last_mut() {
+                if let ASTNodeKind::Statement(expr) = &last.kind {
+                    *last = (**expr).clone();
+                }
+            }
+        }
+
+        let program = ASTNode::new(ASTNodeKind::Program(statements), Default::default());
+        let start = self.compiler.append(program)?;
+
+        let mut debug_info = self.compiler.debug_info();
+        debug_info.set_files(self.modules.sources().names());
+        debug_info.set_main_file(file.map(|file| file.display().to_string()));
+
+        Ok(Program {
+            instructions: self.compiler.instructions(),
+            debug_info,
+            labels: self.compiler.labels(),
+            start,
+        })
+    }
+}
+
+// modules imported and structs declared by earlier sources can be used in
+// `source`
+fn parse(
+    source: &str,
+    modules: &ModuleLoader,
+    checker: &TypeChecker,
+) -> Result<Vec<ASTNode>, Error> {
+    let tokens = Lexer::new(source).tokenize()?;
+    let program = Parser::new(tokens)
+        .with_module_names(modules.module_names())
+        .with_struct_names(checker.struct_names().map(str::to_string))
+        .parse_program()?;
+
+    match program.kind {
+        ASTNodeKind::Program(statements) => Ok(statements),
+        _ => unreachable!("Unexpected node type, expected program"),
+    }
+}
+
+// parses source that must be a single expression
+fn parse_expression(
+    source: &str,
+    modules: &ModuleLoader,
+    checker: &TypeChecker,
+) -> Result<ASTNode, Error> {
+    let mut statements = parse(source, modules, checker)?;
+
+    match statements.pop() {
+        Some(ASTNode {
+            kind: ASTNodeKind::Statement(expr),
+            ..
+        }) if statements.is_empty() => Ok(*expr),
+        _ => Err(Error::new(
+            DiagnosticKind::Parser,
+            "Expected a single expression".to_string(),
+        )),
+    }
+}
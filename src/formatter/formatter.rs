@@ -0,0 +1,599 @@
+use crate::{
+    diagnostics::{Diagnostic, Span},
+    lexer::{Comment, Keyword, Lexer, SpannedToken, Token},
+    parser::{ASTNode, ASTNodeKind, MatchArm, Parameter, Parser, StructField},
+};
+
+const INDENT: &str = "  ";
+
+// formats boo source the canonical way: two space indentation, spaces around
+// binary operators, `{` on the line of its statement and a `;` after every
+// statement that does not end with a block. comments, single blank lines and
+// the parentheses the source uses are kept
+pub fn format_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize().map_err(|diagnostic| vec![diagnostic])?;
+    let comments = lexer.take_comments();
+
+    let statements = match Parser::new(tokens.clone()).parse_program()?.kind {
+        ASTNodeKind::Program(statements) => statements,
+        _ => unreachable!("Unexpected node type, expected program"),
+    };
+
+    let mut formatter = Formatter {
+        source,
+        tokens,
+        comments,
+        next_comment: 0,
+        output: String::new(),
+        indent: 0,
+        last_end: None,
+    };
+
+    for statement in &statements {
+        formatter.item(statement);
+    }
+    formatter.comments_before(source.len());
+
+    Ok(formatter.output)
+}
+
+// statements ending with a block are not followed by a `;`
+fn needs_semicolon(kind: &ASTNodeKind) -> bool {
+    !matches!(
+        kind,
+        ASTNodeKind::FunctionDeclaration { .. }
+            | ASTNodeKind::IfStatement { .. }
+            | ASTNodeKind::WhileStatement { .. }
+            | ASTNodeKind::ForStatement { .. }
+            | ASTNodeKind::MatchStatement { .. }
+            | ASTNodeKind::StructDeclaration { .. }
+            | ASTNodeKind::Test { .. }
+    )
+}
+
+// a string literal that reads back as `text`
+fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            '\\' | '"' | '{' | '}' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// a field or a method, printed in source order
+enum Member<'n> {
+    Field(&'n StructField),
+    Method(&'n ASTNode),
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    tokens: Vec<SpannedToken>,
+    comments: Vec<Comment>,
+    next_comment: usize, // the first comment not printed yet
+    output: String,
+    indent: usize,
+    // where the last printed item ends in the source, None at the start of a
+    // block so it never starts with a blank line
+    last_end: Option<usize>,
+}
+
+impl Formatter<'_> {
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    // keeps one blank line where the source has one or more
+    fn blank_line(&mut self, start: usize) {
+        let between = self.last_end.and_then(|end| self.source.get(end..start));
+        if between.is_some_and(|between| between.matches('\n').count() > 1) {
+            self.output.push('\n');
+        }
+    }
+
+    // prints the comments before `offset` on their own lines
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment).cloned() {
+            if comment.span.start >= offset {
+                break;
+            }
+
+            self.blank_line(comment.span.start);
+            self.write_indent();
+            self.output.push_str("//");
+            self.output.push_str(comment.text.trim_end());
+            self.output.push('\n');
+            self.last_end = Some(comment.span.end);
+            self.next_comment += 1;
+        }
+    }
+
+    // the next comment if it is on the line where something ends at `end`,
+    // with nothing but commas in between
+    fn trailing_comment(&self, end: usize) -> Option<Comment> {
+        let comment = self.comments.get(self.next_comment)?;
+        let between = self.source.get(end..comment.span.start)?;
+
+        let only_commas = self.tokens[self.token_after(end)..]
+            .iter()
+            .take_while(|token| token.span.start < comment.span.start)
+            .all(|token| token.token == Token::Comma);
+
+        (!between.contains('\n') && only_commas).then(|| comment.clone())
+    }
+
+    // starts the line of an item starting at `start`, the comments up to
+    // `flush` are printed before it so comments inside a statement are kept
+    fn begin_item(&mut self, start: usize, flush: usize) {
+        self.comments_before(start);
+        self.blank_line(start);
+        self.last_end = Some(start);
+        self.comments_before(flush);
+        self.write_indent();
+    }
+
+    // ends the line of an item ending at `end`, with the comment after it
+    fn end_item(&mut self, end: usize) {
+        self.last_end = Some(end);
+
+        if let Some(comment) = self.trailing_comment(end) {
+            self.output.push_str(" //");
+            self.output.push_str(comment.text.trim_end());
+            self.last_end = Some(comment.span.end);
+            self.next_comment += 1;
+        }
+
+        self.output.push('\n');
+    }
+
+    fn item(&mut self, statement: &ASTNode) {
+        let flush = if needs_semicolon(&statement.kind) {
+            statement.span.end
+        } else {
+            statement.span.start
+        };
+
+        self.begin_item(statement.span.start, flush);
+        self.statement(statement);
+        if needs_semicolon(&statement.kind) {
+            self.output.push(';');
+        }
+        self.end_item(statement.span.end);
+    }
+
+    // `open` and `close` are the indices of the braces, where `{` is in the
+    // output is returned to tell if the block stayed empty
+    fn open_block(&mut self, open: usize) -> usize {
+        let opened = self.output.len();
+        self.output.push('{');
+        self.end_item(self.tokens[open].span.end);
+        self.indent += 1;
+        self.last_end = None;
+        opened
+    }
+
+    fn close_block(&mut self, close: usize, opened: usize) {
+        self.comments_before(self.tokens[close].span.start);
+        self.indent -= 1;
+
+        // an empty block stays on one line
+        if self.output.len() == opened + "{\n".len() {
+            self.output.pop();
+        } else {
+            self.write_indent();
+        }
+        self.output.push('}');
+    }
+
+    fn block(&mut self, statements: &[ASTNode], open: usize) {
+        let close = self.matching(open, &Token::LeftBrace, &Token::RightBrace);
+        let opened = self.open_block(open);
+        for statement in statements {
+            self.item(statement);
+        }
+        self.close_block(close, opened);
+    }
+
+    // the index of the first token starting at or after `offset`
+    fn token_after(&self, offset: usize) -> usize {
+        self.tokens
+            .partition_point(|token| token.span.start < offset)
+    }
+
+    // the index of the first `{` after `offset` that is not inside parentheses
+    // or brackets, the block of the statement starting there
+    fn brace_after(&self, offset: usize) -> usize {
+        let mut depth = 0;
+        for (index, token) in self
+            .tokens
+            .iter()
+            .enumerate()
+            .skip(self.token_after(offset))
+        {
+            match token.token {
+                Token::LeftParen | Token::LeftBracket => depth += 1,
+                Token::RightParen | Token::RightBracket => depth -= 1,
+                Token::LeftBrace if depth == 0 => return index,
+                _ => (),
+            }
+        }
+        unreachable!("the parser found a block")
+    }
+
+    // the index of the token closing the one at `open`
+    fn matching(&self, open: usize, left: &Token, right: &Token) -> usize {
+        let mut depth = 0;
+        for (index, token) in self.tokens.iter().enumerate().skip(open) {
+            if token.token == *left {
+                depth += 1;
+            } else if token.token == *right {
+                depth -= 1;
+                if depth == 0 {
+                    return index;
+                }
+            }
+        }
+        unreachable!("the parser found the closing token")
+    }
+
+    fn statement(&mut self, node: &ASTNode) {
+        let text = match &node.kind {
+            ASTNodeKind::Statement(expression) => self.expression(expression),
+            ASTNodeKind::ReturnStatement(Some(value)) => {
+                format!("return {}", self.expression(value))
+            }
+            ASTNodeKind::ReturnStatement(None) => "return".to_string(),
+            ASTNodeKind::Break => "break".to_string(),
+            ASTNodeKind::Continue => "continue".to_string(),
+            ASTNodeKind::VariableDeclaration {
+                var_type,
+                name,
+                value,
+            } => format!("{} {} = {}", var_type, name, self.expression(value)),
+            ASTNodeKind::Import { name, .. } => {
+                let import = self.token_after(node.span.start);
+                let path = &self.tokens[self.token_after(node.span.end) - 1].span;
+                let path = &self.source[path.start..path.end];
+
+                // the name is only written when it is not the file's name
+                match self.tokens.get(import + 1).map(|token| &token.token) {
+                    Some(Token::Identifier(_)) => format!("import {} from {}", name, path),
+                    _ => format!("import {}", path),
+                }
+            }
+            ASTNodeKind::FunctionDeclaration {
+                name,
+                parameters,
+                return_type,
+                body,
+            } => {
+                self.output.push_str(&format!(
+                    "fun {}({}) ",
+                    name,
+                    self.parameter_list(parameters)
+                ));
+                if let Some(return_type) = return_type {
+                    self.output.push_str(&format!("-> {} ", return_type));
+                }
+                self.block(body, self.brace_after(node.span.start));
+                return;
+            }
+            ASTNodeKind::IfStatement {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                let open = self.brace_after(node.span.start);
+                self.output
+                    .push_str(&format!("if ({}) ", self.expression(condition)));
+                self.block(then_body, open);
+
+                if let Some(else_body) = else_body {
+                    // the token after `else`, `if` for an `else if`
+                    let after_else = self.matching(open, &Token::LeftBrace, &Token::RightBrace) + 2;
+                    self.output.push_str(" else ");
+
+                    match (else_body.as_slice(), &self.tokens[after_else].token) {
+                        ([else_if], Token::Keyword(Keyword::If)) => self.statement(else_if),
+                        _ => self.block(else_body, after_else),
+                    }
+                }
+                return;
+            }
+            ASTNodeKind::Test { body, .. } => {
+                let name = &self.tokens[self.token_after(node.span.start) + 1].span;
+                let test = format!("test {} ", &self.source[name.start..name.end]);
+                self.output.push_str(&test);
+                self.block(body, self.brace_after(node.span.start));
+                return;
+            }
+            ASTNodeKind::WhileStatement { condition, body } => {
+                self.output
+                    .push_str(&format!("while ({}) ", self.expression(condition)));
+                self.block(body, self.brace_after(node.span.start));
+                return;
+            }
+            ASTNodeKind::ForStatement {
+                var_type,
+                name,
+                iterable,
+                body,
+            } => {
+                self.output.push_str(&format!(
+                    "for ({} {} in {}) ",
+                    var_type,
+                    name,
+                    self.expression(iterable)
+                ));
+                self.block(body, self.brace_after(node.span.start));
+                return;
+            }
+            ASTNodeKind::MatchStatement { subject, arms } => {
+                self.output
+                    .push_str(&format!("match ({}) ", self.expression(subject)));
+                self.match_arms(arms, self.brace_after(node.span.start));
+                return;
+            }
+            ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            } => {
+                self.output.push_str(&format!("struct {} ", name));
+                self.struct_members(fields, methods, self.brace_after(node.span.start));
+                return;
+            }
+            _ => self.expression(node),
+        };
+
+        self.output.push_str(&text);
+    }
+
+    // arms running a single statement end with a comma
+    fn match_arms(&mut self, arms: &[MatchArm], open: usize) {
+        let close = self.matching(open, &Token::LeftBrace, &Token::RightBrace);
+        let opened = self.open_block(open);
+
+        for arm in arms {
+            let pattern = match &arm.pattern {
+                Some(pattern) => self.expression(pattern),
+                None => "_".to_string(),
+            };
+
+            // the token after `=>`
+            let body = self.token_after(arm.span.end) + 1;
+            match (&self.tokens[body].token, arm.body.as_slice()) {
+                (Token::LeftBrace, _) => {
+                    let end = self.matching(body, &Token::LeftBrace, &Token::RightBrace);
+                    self.begin_item(arm.span.start, arm.span.start);
+                    self.output.push_str(&format!("{} => ", pattern));
+                    self.block(&arm.body, body);
+                    self.end_item(self.tokens[end].span.end);
+                }
+                (_, [statement]) => {
+                    let flush = if needs_semicolon(&statement.kind) {
+                        statement.span.end
+                    } else {
+                        arm.span.start
+                    };
+                    self.begin_item(arm.span.start, flush);
+                    self.output.push_str(&format!("{} => ", pattern));
+                    self.statement(statement);
+                    if needs_semicolon(&statement.kind) {
+                        self.output.push(',');
+                    }
+                    self.end_item(statement.span.end);
+                }
+                _ => unreachable!("an arm without braces has one statement"),
+            }
+        }
+
+        self.close_block(close, opened);
+    }
+
+    fn struct_members(&mut self, fields: &[StructField], methods: &[ASTNode], open: usize) {
+        let close = self.matching(open, &Token::LeftBrace, &Token::RightBrace);
+        let opened = self.open_block(open);
+
+        let mut members: Vec<(usize, Member)> = fields
+            .iter()
+            .map(|field| (field.span.start, Member::Field(field)))
+            .chain(
+                methods
+                    .iter()
+                    .map(|method| (method.span.start, Member::Method(method))),
+            )
+            .collect();
+        members.sort_by_key(|(start, _)| *start);
+
+        for (_, member) in members {
+            match member {
+                Member::Field(field) => {
+                    self.begin_item(field.span.start, field.span.end);
+                    self.output
+                        .push_str(&format!("{} {},", field.field_type, field.name));
+                    self.end_item(field.span.end);
+                }
+                Member::Method(method) => self.item(method),
+            }
+        }
+
+        self.close_block(close, opened);
+    }
+
+    fn expression(&self, node: &ASTNode) -> String {
+        // nested parentheses are printed once
+        let mut span = node.span;
+        let mut parenthesized = false;
+        while let Some(inner) = self.inside_parentheses(span) {
+            span = inner;
+            parenthesized = true;
+        }
+
+        let text = self.unparenthesized(node, span);
+        if parenthesized {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+
+    // the span inside the parentheses when `span` is wrapped in a pair of them
+    fn inside_parentheses(&self, span: Span) -> Option<Span> {
+        let open = self.token_after(span.start);
+        match self.tokens.get(open) {
+            Some(token) if token.token == Token::LeftParen && token.span.start == span.start => (),
+            _ => return None,
+        }
+
+        let close = self.matching(open, &Token::LeftParen, &Token::RightParen);
+        if self.tokens[close].span.end != span.end || close == open + 1 {
+            return None;
+        }
+
+        Some(Span {
+            start: self.tokens[open + 1].span.start,
+            end: self.tokens[close - 1].span.end,
+            ..span
+        })
+    }
+
+    // the source of the literal token covering exactly `span`, numbers and
+    // strings are kept as written, interpolations included
+    fn literal(&self, span: Span) -> Option<String> {
+        let token = self.tokens.get(self.token_after(span.start))?;
+        let is_literal = matches!(
+            token.token,
+            Token::Number(_) | Token::String(_) | Token::InterpolatedString(_)
+        );
+
+        (is_literal && token.span.start == span.start && token.span.end == span.end)
+            .then(|| self.source[span.start..span.end].to_string())
+    }
+
+    fn list(&self, nodes: &[ASTNode]) -> String {
+        nodes
+            .iter()
+            .map(|node| self.expression(node))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn unparenthesized(&self, node: &ASTNode, span: Span) -> String {
+        match &node.kind {
+            // interpolated strings are parsed into concatenations
+            ASTNodeKind::BinaryOperation { left, op, right } => {
+                self.literal(span).unwrap_or_else(|| {
+                    format!(
+                        "{} {} {}",
+                        self.expression(left),
+                        op,
+                        self.expression(right)
+                    )
+                })
+            }
+            ASTNodeKind::UnaryOperation { op, operand } => {
+                format!("{}{}", op, self.expression(operand))
+            }
+            ASTNodeKind::FunctionCall { name, arguments } => {
+                format!("{}({})", name, self.list(arguments))
+            }
+            ASTNodeKind::Call { callee, arguments } => {
+                format!("{}({})", self.expression(callee), self.list(arguments))
+            }
+            // the body is printed by a formatter of its own at the current
+            // indentation, comments inside it were printed before the statement
+            ASTNodeKind::FunctionLiteral {
+                parameters,
+                return_type,
+                body,
+            } => {
+                let mut formatter = Formatter {
+                    source: self.source,
+                    tokens: self.tokens.clone(),
+                    comments: Vec::new(),
+                    next_comment: 0,
+                    output: format!("fun({}) ", self.parameter_list(parameters)),
+                    indent: self.indent,
+                    last_end: None,
+                };
+                if let Some(return_type) = return_type {
+                    formatter.output.push_str(&format!("-> {} ", return_type));
+                }
+                formatter.block(body, self.brace_after(span.start));
+                formatter.output
+            }
+            ASTNodeKind::MethodCall {
+                object,
+                method,
+                arguments,
+            } => format!(
+                "{}.{}({})",
+                self.expression(object),
+                method,
+                self.list(arguments)
+            ),
+            ASTNodeKind::FieldAccess { object, field } => {
+                format!("{}.{}", self.expression(object), field)
+            }
+            ASTNodeKind::Index { object, index } => {
+                format!("{}[{}]", self.expression(object), self.expression(index))
+            }
+            ASTNodeKind::ArrayLiteral(elements) => format!("[{}]", self.list(elements)),
+            ASTNodeKind::StructLiteral { name, fields } if fields.is_empty() => {
+                format!("{} {{}}", name)
+            }
+            ASTNodeKind::StructLiteral { name, fields } => {
+                let fields = fields
+                    .iter()
+                    .map(|(field, value)| format!("{}: {}", field, self.expression(value)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{} {{ {} }}", name, fields)
+            }
+            ASTNodeKind::Range { start, end, step } => {
+                let range = format!("{}..{}", self.expression(start), self.expression(end));
+                match step {
+                    Some(step) => format!("{} step {}", range, self.expression(step)),
+                    None => range,
+                }
+            }
+            ASTNodeKind::Identifier(name) => name.clone(),
+            ASTNodeKind::NumberLiteral(value) => {
+                self.literal(span).unwrap_or_else(|| value.to_string())
+            }
+            ASTNodeKind::StringLiteral(value) => self.literal(span).unwrap_or_else(|| quote(value)),
+            ASTNodeKind::BooleanLiteral(value) => value.to_string(),
+            ASTNodeKind::NoneLiteral => "none".to_string(),
+            _ => self.source[span.start..span.end].to_string(),
+        }
+    }
+
+    fn parameter_list(&self, parameters: &[Parameter]) -> String {
+        parameters
+            .iter()
+            .map(|parameter| {
+                let declaration = format!("{} {}", parameter.param_type, parameter.name);
+                match &parameter.default {
+                    Some(default) => format!("{} = {}", declaration, self.expression(default)),
+                    None => declaration,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
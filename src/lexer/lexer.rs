@@ -1,29 +1,128 @@
-use std::str::Chars;
+use std::{fmt, str::Chars};
+
+use crate::diagnostics::{Diagnostic, DiagnosticKind, FileId, Span, MAIN_FILE};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Identifier(String),
     Number(f64),
     String(String),
+    InterpolatedString(Vec<StringPart>),
     Boolean(bool),
+    None,
     Operator(Operator),
     Keyword(Keyword),
     Type(Type),
     Period,
+    DotDot,
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Arrow,
+    FatArrow,
     Comma,
+    Colon,
+    Question, // `?` after a type, `num?`
 }
 
-#[derive(Clone, Debug, PartialEq, Copy)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+// a `//` comment, kept apart from the tokens so the formatter can put it back
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub text: String, // everything after the `//`
+    pub span: Span,
+}
+
+// a piece of an interpolated string, `"x = {x}"` is the text `x = ` followed by
+// the tokens of the expression `x`
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringPart {
+    Text(String),
+    Code(Vec<SpannedToken>),
+}
+
+#[derive(Clone, PartialEq)]
 pub enum Type {
     Str,
     Num,
     Bool,
     Void,
+    Array(Box<Type>),
+    Struct(String),
+    // `num?`, a value of the type or `none`
+    Optional(Box<Type>),
+    // `fun(num, str) -> bool`, the parameter types and the return type
+    Function(Vec<Type>, Box<Type>),
+    // produced by the type checker for expressions that failed to check, it is
+    // compatible with everything so one mistake is only reported once
+    Error,
+}
+
+// types show up in error messages, so they are printed close to how they are written
+impl fmt::Debug for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Str => write!(f, "Str"),
+            Type::Num => write!(f, "Num"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Void => write!(f, "Void"),
+            Type::Array(element_type) => write!(f, "[{:?}]", element_type),
+            Type::Struct(name) => write!(f, "{}", name),
+            // `none` has an optional type with nothing inside
+            Type::Optional(inner) if **inner == Type::Void => write!(f, "none"),
+            Type::Optional(inner) => write!(f, "{:?}?", inner),
+            Type::Function(parameters, return_type) => {
+                write!(f, "fun(")?;
+                for (i, parameter) in parameters.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}", parameter)?;
+                }
+                write!(f, ") -> {:?}", return_type)
+            }
+            Type::Error => write!(f, "<error>"),
+        }
+    }
+}
+
+// and the way they are written in boo source
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Str => write!(f, "str"),
+            Type::Num => write!(f, "num"),
+            Type::Bool => write!(f, "bool"),
+            Type::Void => write!(f, "void"),
+            Type::Array(element_type) => write!(f, "[{}]", element_type),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Optional(inner) if **inner == Type::Void => write!(f, "none"),
+            Type::Optional(inner) => write!(f, "{}?", inner),
+            Type::Function(parameters, return_type) => {
+                write!(f, "fun(")?;
+                for (i, parameter) in parameters.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", parameter)?;
+                }
+                // functions without a result leave out `-> void`
+                match **return_type {
+                    Type::Void => write!(f, ")"),
+                    _ => write!(f, ") -> {}", return_type),
+                }
+            }
+            Type::Error => write!(f, "<error>"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -33,6 +132,13 @@ pub enum Keyword {
     If,
     Else,
     While,
+    For,
+    In,
+    Struct,
+    Break,
+    Continue,
+    Match,
+    Import,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -70,31 +176,101 @@ pub enum Operator {
     LogicalAnd, // &&
     LogicalOr,  // ||
     LogicalNot, // !
+
+    // optional values
+    Coalesce, // ??
+}
+
+// operators are printed the way they are written
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Operator::Plus => "+",
+            Operator::Minus | Operator::UnaryMinus => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Power => "**",
+            Operator::Modulo => "%",
+            Operator::Concat => "><",
+            Operator::AssignEquals => "=",
+            Operator::AddAssign => "+=",
+            Operator::SubAssign => "-=",
+            Operator::MulAssign => "*=",
+            Operator::DivAssign => "/=",
+            Operator::PowAssign => "**=",
+            Operator::ModAssign => "%=",
+            Operator::Equals => "==",
+            Operator::NotEquals => "!=",
+            Operator::GreaterThan => ">",
+            Operator::LessThan => "<",
+            Operator::GreaterThanOrEqual => ">=",
+            Operator::LessThanOrEqual => "<=",
+            Operator::LogicalAnd => "&&",
+            Operator::LogicalOr => "||",
+            Operator::LogicalNot => "!",
+            Operator::Coalesce => "??",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
+const MISSING_INTERPOLATION_BRACE: &str = "Expected '}' to close the interpolation in string";
+
 pub struct Lexer<'a> {
     input: Chars<'a>,
     current: Option<char>,
+    position: usize,
+    line: usize,
+    column: usize,
+    file: FileId,
+    comments: Vec<Comment>,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
+        Self::with_file(input, MAIN_FILE)
+    }
+
+    // spans of the tokens point into `file`, for imported modules
+    pub fn with_file(input: &'a str, file: FileId) -> Self {
         let mut lexer = Lexer {
             input: input.chars(),
             current: None,
+            position: 0,
+            line: 1,
+            column: 1,
+            file,
+            comments: Vec::new(),
         };
-        lexer.next();
+        lexer.current = lexer.input.next();
         lexer
     }
 
     fn next(&mut self) {
+        if let Some(c) = self.current {
+            self.position += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
         self.current = self.input.next();
     }
 
+    fn span_from(&self, start: usize, line: usize, column: usize) -> Span {
+        Span::new(start, self.position, line, column).in_file(self.file)
+    }
+
     fn peek(&self) -> Option<char> {
         self.current
     }
 
+    fn peek_next(&self) -> Option<char> {
+        self.input.clone().next()
+    }
+
     fn consume_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
         let mut result = String::new();
         while let Some(c) = self.peek() {
@@ -112,17 +288,17 @@ impl<'a> Lexer<'a> {
         let mut num_str = String::new();
 
         // integer part
-        let int_part = self.consume_while(|c| c.is_digit(10));
+        let int_part = self.consume_while(|c| c.is_ascii_digit());
         if int_part.is_empty() {
             return Err("Expected digits".to_string());
         }
         num_str.push_str(&int_part);
 
-        // decimal point
-        if let Some('.') = self.peek() {
+        // decimal point, unless it starts a range like `0..10`
+        if self.peek() == Some('.') && self.peek_next() != Some('.') {
             self.next();
             num_str.push('.');
-            let dec_part = self.consume_while(|c| c.is_digit(10));
+            let dec_part = self.consume_while(|c| c.is_ascii_digit());
             if dec_part.is_empty() {
                 return Err("Expected digits after '.'".to_string());
             }
@@ -138,16 +314,135 @@ impl<'a> Lexer<'a> {
     fn tokenize_string(&mut self) -> Result<Token, String> {
         // consume the opening quote
         self.next();
-        let str_content = self.consume_while(|c| c != '"');
-        match self.peek() {
-            Some('"') => {
-                // consume the closing quote
+
+        let mut parts = Vec::new();
+        let mut text = String::new();
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    // consume the closing quote
+                    self.next();
+                    break;
+                }
+                Some('\\') => {
+                    self.next();
+                    text.push(self.tokenize_escape()?);
+                }
+                Some('{') => {
+                    self.next();
+                    if !text.is_empty() {
+                        parts.push(StringPart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(StringPart::Code(self.tokenize_interpolation()?));
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.next();
+                }
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+
+        if parts.is_empty() {
+            return Ok(Token::String(text));
+        }
+
+        if !text.is_empty() {
+            parts.push(StringPart::Text(text));
+        }
+
+        Ok(Token::InterpolatedString(parts))
+    }
+
+    // reads the character after a backslash
+    fn tokenize_escape(&mut self) -> Result<char, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err("Unterminated string".to_string()),
+        };
+        self.next();
+
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' | '"' | '{' | '}' => Ok(c),
+            'u' => self.tokenize_unicode_escape(),
+            _ => Err(format!("Invalid escape sequence '\\{}'", c)),
+        }
+    }
+
+    // reads `{XXXX}` after `\u`, a unicode code point in hex
+    fn tokenize_unicode_escape(&mut self) -> Result<char, String> {
+        if self.peek() != Some('{') {
+            return Err(
+                "Expected '{' after '\\u', unicode escapes look like '\\u{1F600}'".to_string(),
+            );
+        }
+        self.next();
+
+        let digits = self.consume_while(|c| c.is_ascii_hexdigit());
+
+        if self.peek() != Some('}') {
+            return Err("Expected '}' to close the unicode escape".to_string());
+        }
+        self.next();
+
+        if digits.is_empty() || digits.len() > 6 {
+            return Err(format!("Invalid unicode escape '\\u{{{}}}'", digits));
+        }
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("'\\u{{{}}}' is not a valid unicode character", digits))
+    }
+
+    // tokenizes the expression inside `{...}` of a string, up to the closing brace
+    fn tokenize_interpolation(&mut self) -> Result<Vec<SpannedToken>, String> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(MISSING_INTERPOLATION_BRACE.to_string()),
+            };
+
+            if c == '}' && depth == 0 {
                 self.next();
-                Ok(Token::String(str_content))
+                break;
+            }
+
+            let (start, line, column) = (self.position, self.line, self.column);
+            // in `"a {x"` the closing quote starts a string inside the
+            // interpolation, running off the end means the `}` is missing
+            let token = match self.tokenize_token(c) {
+                Err(_) if self.peek().is_none() => {
+                    return Err(MISSING_INTERPOLATION_BRACE.to_string())
+                }
+                token => token?,
+            };
+            if let Some(token) = token {
+                match token {
+                    Token::LeftBrace => depth += 1,
+                    Token::RightBrace => depth -= 1,
+                    _ => (),
+                }
+                tokens.push(SpannedToken {
+                    token,
+                    span: self.span_from(start, line, column),
+                });
             }
-            Some(c) => Err(format!("Unexpected character in string: {}", c)),
-            _ => Err("Unexpected end of input".to_string()),
         }
+
+        if tokens.is_empty() {
+            return Err("Empty interpolation in string, use '\\{' for a literal brace".to_string());
+        }
+
+        Ok(tokens)
     }
 
     fn tokenize_identifier(&mut self) -> Result<Token, String> {
@@ -160,6 +455,13 @@ impl<'a> Lexer<'a> {
             "if" => Token::Keyword(Keyword::If),
             "else" => Token::Keyword(Keyword::Else),
             "while" => Token::Keyword(Keyword::While),
+            "for" => Token::Keyword(Keyword::For),
+            "in" => Token::Keyword(Keyword::In),
+            "struct" => Token::Keyword(Keyword::Struct),
+            "break" => Token::Keyword(Keyword::Break),
+            "continue" => Token::Keyword(Keyword::Continue),
+            "match" => Token::Keyword(Keyword::Match),
+            "import" => Token::Keyword(Keyword::Import),
 
             // types
             "str" => Token::Type(Type::Str),
@@ -170,6 +472,8 @@ impl<'a> Lexer<'a> {
             "true" => Token::Boolean(true),
             "false" => Token::Boolean(false),
 
+            "none" => Token::None,
+
             // regular identifier
             _ => Token::Identifier(ident_str),
         };
@@ -191,6 +495,10 @@ impl<'a> Lexer<'a> {
                 self.next(); // consume the second operator
                 Token::Operator(Operator::Equals)
             }
+            ('=', Some('>')) => {
+                self.next(); // consume the second operator
+                Token::FatArrow
+            }
             ('!', Some('=')) => {
                 self.next(); // consume the second operator
                 Token::Operator(Operator::NotEquals)
@@ -246,6 +554,10 @@ impl<'a> Lexer<'a> {
                 self.next(); // consume the second operator
                 Token::Operator(Operator::LogicalOr)
             }
+            ('?', Some('?')) => {
+                self.next(); // consume the second operator
+                Token::Operator(Operator::Coalesce)
+            }
 
             // single char operators
             ('+', _) => Token::Operator(Operator::Plus),
@@ -261,7 +573,10 @@ impl<'a> Lexer<'a> {
             (')', _) => Token::RightParen,
             ('{', _) => Token::LeftBrace,
             ('}', _) => Token::RightBrace,
+            ('[', _) => Token::LeftBracket,
+            (']', _) => Token::RightBracket,
             (',', _) => Token::Comma,
+            ('?', _) => Token::Question,
 
             c => return Err(format!("Unexpected operator: {:?}", c)),
         };
@@ -269,55 +584,99 @@ impl<'a> Lexer<'a> {
         Ok(token)
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    pub fn tokenize(&mut self) -> Result<Vec<SpannedToken>, Diagnostic> {
         let mut tokens = Vec::new();
 
         while let Some(c) = self.peek() {
-            let token = match c {
-                '0'..='9' => self.tokenize_number()?,
-                '"' => self.tokenize_string()?,
-                'a'..='z' | 'A'..='Z' | '_' => self.tokenize_identifier()?,
-                '/' => {
-                    // consume the '/'
-                    self.next();
-                    match self.peek() {
-                        Some('/') => {
-                            // consume the '/'
-                            self.next();
-                            self.consume_while(|c| c != '\n');
-                            if let Some('\n') = self.peek() {
-                                // consume the '\n'
-                                self.next();
-                            }
-                            continue;
-                        }
-                        Some('=') => {
-                            // consume the '='
+            let (start, line, column) = (self.position, self.line, self.column);
+
+            let token = match self.tokenize_token(c) {
+                Ok(Some(token)) => token,
+                Ok(None) => continue,
+                Err(message) => {
+                    return Err(Diagnostic::new(
+                        DiagnosticKind::Lexer,
+                        message,
+                        Some(self.span_from(start, line, column)),
+                    ))
+                }
+            };
+
+            tokens.push(SpannedToken {
+                token,
+                span: self.span_from(start, line, column),
+            });
+        }
+
+        Ok(tokens)
+    }
+
+    // the comments skipped by `tokenize`, in order
+    pub fn take_comments(&mut self) -> Vec<Comment> {
+        std::mem::take(&mut self.comments)
+    }
+
+    // tokenizes the token starting at `c`, returns None for skipped input
+    // (whitespace, comments and semicolons)
+    fn tokenize_token(&mut self, c: char) -> Result<Option<Token>, String> {
+        let token = match c {
+            '0'..='9' => self.tokenize_number()?,
+            '"' => self.tokenize_string()?,
+            'a'..='z' | 'A'..='Z' | '_' => self.tokenize_identifier()?,
+            '/' => {
+                let (start, line, column) = (self.position, self.line, self.column);
+
+                // consume the '/'
+                self.next();
+                match self.peek() {
+                    Some('/') => {
+                        // consume the '/'
+                        self.next();
+                        let text = self.consume_while(|c| c != '\n');
+                        self.comments.push(Comment {
+                            text,
+                            span: self.span_from(start, line, column),
+                        });
+                        if let Some('\n') = self.peek() {
+                            // consume the '\n'
                             self.next();
-                            Token::Operator(Operator::DivAssign)
                         }
-                        _ => Token::Operator(Operator::Divide),
+                        return Ok(None);
+                    }
+                    Some('=') => {
+                        // consume the '='
+                        self.next();
+                        Token::Operator(Operator::DivAssign)
                     }
+                    _ => Token::Operator(Operator::Divide),
                 }
-                '+' | '-' | '<' | '>' | '=' | '*' | '(' | ')' | '{' | '}' | ',' | '!' | '%'
-                | '&' | '|' => self.tokenize_operator()?,
-                '.' => {
+            }
+            '+' | '-' | '<' | '>' | '=' | '*' | '(' | ')' | '{' | '}' | '[' | ']' | ',' | '!'
+            | '%' | '&' | '|' | '?' => self.tokenize_operator()?,
+            '.' => {
+                self.next();
+                if self.peek() == Some('.') {
                     self.next();
+                    Token::DotDot
+                } else {
                     Token::Period
                 }
-                ';' => {
-                    self.next();
-                    continue;
-                }
-                c if c.is_whitespace() => {
-                    self.next();
-                    continue;
-                }
-                c => return Err(format!("Unexpected character: {}", c)),
-            };
-            tokens.push(token);
-        }
+            }
+            ':' => {
+                self.next();
+                Token::Colon
+            }
+            ';' => {
+                self.next();
+                return Ok(None);
+            }
+            c if c.is_whitespace() => {
+                self.next();
+                return Ok(None);
+            }
+            c => return Err(format!("Unexpected character: {}", c)),
+        };
 
-        Ok(tokens)
+        Ok(Some(token))
     }
 }
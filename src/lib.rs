@@ -0,0 +1,30 @@
+// every module keeps its implementation in a file named after the module
+#![allow(clippy::module_inception)]
+
+// the compiler stages, public for the `boo` binary only, they are not part of
+// the stable api
+#[doc(hidden)]
+pub mod analyzer;
+mod bytecode;
+#[doc(hidden)]
+pub mod diagnostics;
+mod engine;
+#[doc(hidden)]
+pub mod formatter;
+#[doc(hidden)]
+pub mod lexer;
+#[doc(hidden)]
+pub mod modules;
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod stdlib;
+mod vm;
+
+// the embedding api, everything a host program needs to run boo code
+pub use bytecode::OptLevel;
+pub use diagnostics::{Diagnostic, DiagnosticKind, Limit, Severity, SourceMap, Span, TraceFrame};
+pub use engine::{Engine, Error, Program};
+pub use lexer::Type;
+pub use stdlib::natives::{NativeParameter, NativeType};
+pub use vm::{InterruptHandle, Limits, StructInstance, Value};
@@ -0,0 +1,663 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use boo_lang::{
+    analyzer::TypeChecker,
+    diagnostics::MAIN_FILE,
+    lexer::{Lexer, Type},
+    modules::ModuleLoader,
+    parser::{ASTNode, ASTNodeKind, Parser},
+    stdlib::natives::{NativeSignature, NativeType, Natives, Receiver},
+    Diagnostic, Severity, Span,
+};
+
+use super::{
+    json::Json,
+    symbols::{SymbolKind, Symbols},
+};
+
+// kinds of completion items and document symbols, numbered by the protocol
+const COMPLETION_METHOD: usize = 2;
+const COMPLETION_FUNCTION: usize = 3;
+const COMPLETION_FIELD: usize = 5;
+const COMPLETION_VARIABLE: usize = 6;
+const COMPLETION_STRUCT: usize = 22;
+const SYMBOL_METHOD: usize = 6;
+const SYMBOL_FUNCTION: usize = 12;
+
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const PARSE_ERROR: f64 = -32700.0;
+
+struct Document {
+    text: String,
+    line_starts: Vec<usize>,
+    // from the last version that parsed, completion keeps working while a
+    // line is being typed
+    symbols: Option<Symbols>,
+    current: bool, // whether `symbols` belong to `text`
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        Self {
+            text,
+            line_starts,
+            symbols: None,
+            current: false,
+        }
+    }
+
+    // positions count utf-16 code units, like the editors do
+    fn position(&self, offset: usize) -> Json {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let character = self
+            .text
+            .get(self.line_starts[line]..offset)
+            .map_or(0, |text| text.encode_utf16().count());
+
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    }
+
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line")?.as_usize()?;
+        let character = position.get("character")?.as_usize()?;
+
+        let start = *self.line_starts.get(line)?;
+        let mut units = 0;
+        for (offset, c) in self.text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return Some(start + offset);
+            }
+            units += c.len_utf16();
+        }
+        Some(self.text.len())
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object(vec![
+            ("start", self.position(span.start)),
+            ("end", self.position(span.end.max(span.start))),
+        ])
+    }
+
+    // the symbols of the current text, for answers that must be exact
+    fn current_symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref().filter(|_| self.current)
+    }
+}
+
+// `file:///path/to/file.boo` to the path of the file
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn native_type(native_type: &NativeType) -> String {
+    match native_type {
+        NativeType::Exact(t) => t.to_string(),
+        NativeType::Any => "any".to_string(),
+        NativeType::Element => "element".to_string(),
+    }
+}
+
+fn native_detail(name: &str, signature: &NativeSignature) -> String {
+    let parameters = signature
+        .parameters
+        .iter()
+        .map(|parameter| {
+            // like the parameters of boo functions, without the default value
+            let suffix = match (parameter.optional, parameter.variadic) {
+                (_, true) => "...",
+                (true, _) => " = ..",
+                _ => "",
+            };
+            format!(
+                "{} {}{}",
+                native_type(&parameter.param_type),
+                parameter.name,
+                suffix
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "fun {}({}) -> {}",
+        name,
+        parameters,
+        native_type(&signature.return_type)
+    )
+}
+
+fn completion_item(label: &str, kind: usize, detail: &str) -> Json {
+    Json::object(vec![
+        ("label", label.into()),
+        ("kind", kind.into()),
+        ("detail", detail.into()),
+    ])
+}
+
+// the type of the value written right before a `.`, when it is simple enough
+// to tell without checking the whole document
+fn receiver_type(receiver: &str, symbols: &Symbols, offset: usize) -> Option<Type> {
+    let receiver = receiver.trim_end();
+    if receiver.ends_with('"') {
+        return Some(Type::Str);
+    }
+
+    let word_start = receiver
+        .trim_end_matches(|c: char| c.is_alphanumeric() || c == '_')
+        .len();
+    let word = &receiver[word_start..];
+
+    // a field of something else, like `point.name.`
+    if receiver[..word_start].ends_with('.') {
+        return None;
+    }
+
+    match word {
+        "" => None,
+        "true" | "false" => Some(Type::Bool),
+        word if word.chars().all(|c| c.is_ascii_digit()) => Some(Type::Num),
+        word => symbols
+            .visible_at(offset)
+            .into_iter()
+            .find(|symbol| symbol.name == word && symbol.kind == SymbolKind::Variable)
+            .and_then(|symbol| symbol.value_type.clone()),
+    }
+}
+
+pub struct Server {
+    documents: HashMap<String, Document>,
+    natives: Rc<Natives>,
+    import_paths: Vec<PathBuf>,
+    shutdown: bool, // a shutdown request was received
+    exit: bool,
+}
+
+impl Server {
+    pub fn new(import_paths: Vec<PathBuf>) -> Self {
+        Self {
+            documents: HashMap::new(),
+            natives: Rc::new(Natives::new()),
+            import_paths,
+            shutdown: false,
+            exit: false,
+        }
+    }
+
+    // serves requests from stdin until the editor asks the server to exit
+    pub fn run(&mut self) -> Result<(), String> {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+
+        while !self.exit {
+            let message = match read_message(&mut input)? {
+                Some(message) => message,
+                None => break,
+            };
+
+            let replies = match Json::parse(&message) {
+                Ok(message) => self.handle(&message),
+                Err(error) => vec![error_response(Json::Null, PARSE_ERROR, error)],
+            };
+
+            for reply in replies {
+                write_message(&reply)?;
+            }
+        }
+
+        if self.shutdown {
+            Ok(())
+        } else {
+            Err("The editor exited without shutting the language server down".to_string())
+        }
+    }
+
+    // answers a request or handles a notification, returns the messages to send back
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+
+        // requests have an id, notifications do not
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/hover" => self.hover(params).unwrap_or(Json::Null),
+            "textDocument/definition" => self.definition(params).unwrap_or(Json::Null),
+            "textDocument/completion" => self.completion(params).unwrap_or(Json::Null),
+            "textDocument/documentSymbol" => self.document_symbols(params).unwrap_or(Json::Null),
+            _ => {
+                return vec![error_response(
+                    id,
+                    METHOD_NOT_FOUND,
+                    format!("Unknown method '{}'", method),
+                )]
+            }
+        };
+
+        vec![Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", id),
+            ("result", result),
+        ])]
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params
+            .path(&["textDocument", "uri"])
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_string();
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params
+                    .path(&["textDocument", "text"])
+                    .and_then(Json::as_str);
+                self.update(uri, text.unwrap_or("").to_string())
+            }
+            // the whole text is sent on every change
+            "textDocument/didChange" => {
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+
+                match text {
+                    Some(text) => self.update(uri, text.to_string()),
+                    None => Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![publish_diagnostics(&uri, Vec::new())]
+            }
+            "exit" => {
+                self.exit = true;
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // checks the new text of a document and publishes its diagnostics
+    fn update(&mut self, uri: String, text: String) -> Vec<Json> {
+        let mut document = Document::new(text);
+        let (diagnostics, symbols) = self.analyze(&uri, &document.text);
+
+        document.current = symbols.is_some();
+        document.symbols = match symbols {
+            Some(symbols) => Some(symbols),
+            None => self
+                .documents
+                .remove(&uri)
+                .and_then(|old| old.symbols)
+                .map(|mut symbols| {
+                    symbols.extend_to(document.text.len());
+                    symbols
+                }),
+        };
+
+        let diagnostics = diagnostics
+            .iter()
+            .map(|(diagnostic, span)| {
+                let severity = match diagnostic.severity {
+                    Severity::Error => 1,
+                    Severity::Warning => 2,
+                };
+                Json::object(vec![
+                    ("range", document.range(*span)),
+                    ("severity", severity.into()),
+                    ("source", "boo".into()),
+                    ("message", diagnostic.message.clone().into()),
+                ])
+            })
+            .collect();
+
+        self.documents.insert(uri.clone(), document);
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    // lexes, parses and checks a document with its imports, the symbols are
+    // only found when it parses
+    fn analyze(&self, uri: &str, text: &str) -> (Vec<(Diagnostic, Span)>, Option<Symbols>) {
+        let tokens = match Lexer::new(text).tokenize() {
+            Ok(tokens) => tokens,
+            Err(diagnostic) => return (self.located(vec![diagnostic], None), None),
+        };
+
+        let statements = match Parser::new(tokens.clone()).parse_program() {
+            Ok(ASTNode {
+                kind: ASTNodeKind::Program(statements),
+                ..
+            }) => statements,
+            Ok(_) => unreachable!("Unexpected node type, expected program"),
+            Err(diagnostics) => return (self.located(diagnostics, None), None),
+        };
+
+        let symbols = Symbols::new(&statements, &tokens, text.len());
+
+        // imports are found the way `boo run` finds them
+        let mut modules = ModuleLoader::new();
+        for path in &self.import_paths {
+            modules.add_search_path(path);
+        }
+        let file = uri_path(uri);
+        let directory = file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        let diagnostics = match modules.link(statements, &directory, file.as_deref()) {
+            Ok(statements) => {
+                let program = ASTNode::new(ASTNodeKind::Program(statements), Span::default());
+                let mut checker = TypeChecker::with_natives(program, self.natives.clone());
+                let mut diagnostics = checker.check_program().err().unwrap_or_default();
+                diagnostics.extend(checker.take_warnings());
+                diagnostics
+            }
+            Err(diagnostics) => diagnostics,
+        };
+
+        (self.located(diagnostics, Some(&modules)), Some(symbols))
+    }
+
+    // the span each diagnostic is shown at, a mistake in an imported module is
+    // shown at the start of the document with the module's name
+    fn located(
+        &self,
+        diagnostics: Vec<Diagnostic>,
+        modules: Option<&ModuleLoader>,
+    ) -> Vec<(Diagnostic, Span)> {
+        diagnostics
+            .into_iter()
+            .map(|mut diagnostic| match diagnostic.span {
+                Some(span) if span.file == MAIN_FILE => (diagnostic, span),
+                Some(span) => {
+                    let file = modules
+                        .and_then(|modules| modules.sources().get(span.file))
+                        .map_or("<unknown file>", |file| file.name.as_str());
+                    diagnostic.message = format!("{}:{}: {}", file, span.line, diagnostic.message);
+                    (diagnostic, Span::default())
+                }
+                None => (diagnostic, Span::default()),
+            })
+            .collect()
+    }
+
+    fn document_position<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, usize)> {
+        let uri = params.path(&["textDocument", "uri"])?.as_str()?;
+        let document = self.documents.get(uri)?;
+        let offset = document.offset(params.get("position")?)?;
+        Some((uri, document, offset))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (_, document, offset) = self.document_position(params)?;
+        let symbol = document.current_symbols()?.at(offset)?;
+
+        Some(Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", "markdown".into()),
+                ("value", format!("```boo\n{}\n```", symbol.detail).into()),
+            ]),
+        )]))
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let (uri, document, offset) = self.document_position(params)?;
+        let symbol = document.current_symbols()?.at(offset)?;
+
+        Some(Json::object(vec![
+            ("uri", uri.into()),
+            ("range", document.range(symbol.span)),
+        ]))
+    }
+
+    fn completion(&self, params: &Json) -> Option<Json> {
+        let (_, document, offset) = self.document_position(params)?;
+        let symbols = document.symbols.as_ref()?;
+
+        // the start of the name being typed
+        let before = &document.text[..offset];
+        let before = before.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+
+        let items = match before.strip_suffix('.') {
+            Some(receiver) => {
+                self.member_completions(receiver_type(receiver, symbols, offset), symbols)
+            }
+            None => {
+                let mut items: Vec<Json> = symbols
+                    .visible_at(offset)
+                    .into_iter()
+                    .map(|symbol| {
+                        let kind = match symbol.kind {
+                            SymbolKind::Function => COMPLETION_FUNCTION,
+                            SymbolKind::Struct => COMPLETION_STRUCT,
+                            _ => COMPLETION_VARIABLE,
+                        };
+                        completion_item(&symbol.name, kind, &symbol.detail)
+                    })
+                    .collect();
+
+                let mut natives: Vec<_> = self.natives.functions().collect();
+                natives.sort_by_key(|(name, _)| *name);
+                items.extend(natives.into_iter().map(|(name, native)| {
+                    completion_item(
+                        name,
+                        COMPLETION_FUNCTION,
+                        &native_detail(name, &native.signature),
+                    )
+                }));
+                items
+            }
+        };
+
+        Some(Json::Array(items))
+    }
+
+    // the fields and methods of a value of `value_type`
+    fn member_completions(&self, value_type: Option<Type>, symbols: &Symbols) -> Vec<Json> {
+        let value_type = match value_type {
+            Some(value_type) => value_type,
+            None => return Vec::new(),
+        };
+
+        if let Type::Struct(name) = &value_type {
+            return symbols
+                .members(name)
+                .iter()
+                .map(|member| {
+                    let kind = match member.kind {
+                        SymbolKind::Method => COMPLETION_METHOD,
+                        _ => COMPLETION_FIELD,
+                    };
+                    completion_item(&member.name, kind, &member.detail)
+                })
+                .collect();
+        }
+
+        let mut methods: Vec<_> = Receiver::of_type(&value_type)
+            .map(|receiver| self.natives.methods(receiver).collect())
+            .unwrap_or_default();
+        methods.sort_by_key(|(name, _)| *name);
+
+        methods
+            .into_iter()
+            .map(|(name, native)| {
+                completion_item(
+                    name,
+                    COMPLETION_METHOD,
+                    &native_detail(name, &native.signature),
+                )
+            })
+            .collect()
+    }
+
+    fn document_symbols(&self, params: &Json) -> Option<Json> {
+        let uri = params.path(&["textDocument", "uri"])?.as_str()?;
+        let document = self.documents.get(uri)?;
+        let symbols = document.symbols.as_ref()?;
+
+        let functions = symbols
+            .all()
+            .iter()
+            .filter_map(|symbol| {
+                let kind = match symbol.kind {
+                    SymbolKind::Function => SYMBOL_FUNCTION,
+                    SymbolKind::Method => SYMBOL_METHOD,
+                    _ => return None,
+                };
+                Some(Json::object(vec![
+                    ("name", symbol.name.clone().into()),
+                    ("detail", symbol.detail.clone().into()),
+                    ("kind", kind.into()),
+                    ("range", document.range(symbol.declaration)),
+                    ("selectionRange", document.range(symbol.span)),
+                ]))
+            })
+            .collect();
+
+        Some(Json::Array(functions))
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                // the whole text is sent on every change
+                ("textDocumentSync", 1.into()),
+                ("hoverProvider", true.into()),
+                ("definitionProvider", true.into()),
+                (
+                    "completionProvider",
+                    Json::object(vec![("triggerCharacters", Json::Array(vec![".".into()]))]),
+                ),
+                ("documentSymbolProvider", true.into()),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![
+                ("name", "boo".into()),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ]),
+        ),
+    ])
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+fn error_response(id: Json, code: f64, message: String) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(code)),
+                ("message", message.into()),
+            ]),
+        ),
+    ])
+}
+
+// messages are json with a `Content-Length` header, `None` at the end of input
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        let read = input
+            .read_line(&mut line)
+            .map_err(|e| format!("Unable to read message: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or("Message without a Content-Length header")?;
+    let mut body = vec![0; length];
+    input
+        .read_exact(&mut body)
+        .map_err(|e| format!("Unable to read message: {}", e))?;
+
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| "Message is not valid utf-8".to_string())
+}
+
+fn write_message(message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| format!("Unable to write message: {}", e))
+}
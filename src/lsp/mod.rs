@@ -0,0 +1,5 @@
+mod json;
+mod lsp;
+mod symbols;
+
+pub use lsp::*;
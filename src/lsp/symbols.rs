@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+
+use boo_lang::{
+    lexer::{SpannedToken, Token, Type},
+    parser::{ASTNode, ASTNodeKind, Parameter},
+    Span,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolKind {
+    Variable,
+    Function,
+    Method,
+    Struct,
+}
+
+// a name declared in a document
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub detail: String,           // how it is declared, shown on hover
+    pub value_type: Option<Type>, // the type of a variable
+    pub span: Span,               // the declared name
+    pub declaration: Span,        // the whole declaration
+    visible: (usize, usize),      // the offsets the name can be used between
+}
+
+// a field or a method of a struct
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    pub detail: String,
+    pub kind: SymbolKind,
+}
+
+// the declarations of a document and where each of them is used, found by
+// walking the syntax tree with the same scopes the type checker uses
+#[derive(Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    references: Vec<(Span, usize)>, // every use of a symbol, declarations included
+    members: HashMap<String, Vec<Member>>,
+    end: usize, // where top level names stop being visible
+}
+
+impl Symbols {
+    // `end` is the length of the document, where top level names stop being visible
+    pub fn new(statements: &[ASTNode], tokens: &[SpannedToken], end: usize) -> Self {
+        let mut builder = Builder {
+            tokens,
+            symbols: Symbols {
+                end,
+                ..Symbols::default()
+            },
+            scopes: vec![(end, Vec::new())],
+        };
+        builder.statements(statements);
+        builder.symbols
+    }
+
+    // keeps top level names visible up to the new end of an edited document,
+    // the symbols of its last version are used while it does not parse
+    pub fn extend_to(&mut self, end: usize) {
+        for symbol in &mut self.symbols {
+            if symbol.visible.1 == self.end {
+                symbol.visible.1 = end;
+            }
+        }
+        self.end = end;
+    }
+
+    pub fn all(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // the symbol used or declared at `offset`
+    pub fn at(&self, offset: usize) -> Option<&Symbol> {
+        self.references
+            .iter()
+            .find(|(span, _)| span.start <= offset && offset <= span.end)
+            .map(|(_, index)| &self.symbols[*index])
+    }
+
+    // the variables and functions that can be used at `offset`, a name declared
+    // in an inner scope hides the same name declared outside of it
+    pub fn visible_at(&self, offset: usize) -> Vec<&Symbol> {
+        let mut visible: HashMap<(&str, bool), &Symbol> = HashMap::new();
+        for symbol in &self.symbols {
+            let (from, to) = symbol.visible;
+            if symbol.kind == SymbolKind::Method || offset < from || offset > to {
+                continue;
+            }
+
+            let key = (symbol.name.as_str(), symbol.kind == SymbolKind::Function);
+            match visible.get(&key) {
+                Some(other) if other.declaration.start > symbol.declaration.start => (),
+                _ => {
+                    visible.insert(key, symbol);
+                }
+            }
+        }
+
+        let mut visible: Vec<&Symbol> = visible.into_values().collect();
+        visible.sort_by(|a, b| a.name.cmp(&b.name));
+        visible
+    }
+
+    pub fn members(&self, struct_name: &str) -> &[Member] {
+        self.members
+            .get(struct_name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+fn signature(name: &str, parameters: &[Parameter], return_type: &Option<Type>) -> String {
+    let parameters = parameters
+        .iter()
+        .map(|parameter| {
+            let default = if parameter.default.is_some() {
+                " = .."
+            } else {
+                ""
+            };
+            format!("{} {}{}", parameter.param_type, parameter.name, default)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match return_type {
+        Some(return_type) => format!("fun {}({}) -> {}", name, parameters, return_type),
+        None => format!("fun {}({})", name, parameters),
+    }
+}
+
+struct Builder<'t> {
+    tokens: &'t [SpannedToken],
+    symbols: Symbols,
+    scopes: Vec<(usize, Vec<usize>)>, // where each scope ends and the symbols declared in it
+}
+
+impl Builder<'_> {
+    fn enter_scope(&mut self, end: usize) {
+        self.scopes.push((end, Vec::new()));
+    }
+
+    fn exit_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    // the span of the first `name` token from `from` on, declarations only
+    // store the span of the whole statement
+    fn name_span(&self, from: usize, name: &str) -> Option<Span> {
+        let first = self.tokens.partition_point(|token| token.span.start < from);
+        self.tokens[first..]
+            .iter()
+            .find(
+                |token| matches!(&token.token, Token::Identifier(identifier) if identifier == name),
+            )
+            .map(|token| token.span)
+    }
+
+    // the name can be used from `visible_from` to the end of the current scope
+    fn declare(&mut self, symbol: Symbol, visible_from: usize) -> usize {
+        let (end, scope) = self
+            .scopes
+            .last_mut()
+            .expect("there is always a global scope");
+        let index = self.symbols.symbols.len();
+        scope.push(index);
+
+        self.symbols.references.push((symbol.span, index));
+        self.symbols.symbols.push(Symbol {
+            visible: (visible_from, *end),
+            ..symbol
+        });
+        index
+    }
+
+    fn resolve(&self, name: &str, function: bool) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|(_, scope)| scope.iter().rev())
+            .copied()
+            .find(|index| {
+                let symbol = &self.symbols.symbols[*index];
+                symbol.name == name
+                    && (symbol.kind == SymbolKind::Function) == function
+                    && symbol.kind != SymbolKind::Method
+            })
+    }
+
+    fn reference(&mut self, span: Span, name: &str, function: bool) {
+        if let Some(index) = self.resolve(name, function) {
+            self.symbols.references.push((span, index));
+        }
+    }
+
+    fn statements(&mut self, nodes: &[ASTNode]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn block(&mut self, nodes: &[ASTNode], end: usize) {
+        self.enter_scope(end);
+        self.statements(nodes);
+        self.exit_scope();
+    }
+
+    fn variable(
+        &mut self,
+        name: &str,
+        var_type: &Type,
+        span: Span,
+        declaration: Span,
+        from: usize,
+    ) {
+        let symbol = Symbol {
+            name: name.to_string(),
+            kind: SymbolKind::Variable,
+            detail: format!("{} {}", var_type, name),
+            value_type: Some(var_type.clone()),
+            span,
+            declaration,
+            visible: (0, 0),
+        };
+        self.declare(symbol, from);
+    }
+
+    // a function, or a method of `container`
+    fn function(&mut self, node: &ASTNode, container: Option<&str>) {
+        let (name, parameters, return_type, body) = match &node.kind {
+            ASTNodeKind::FunctionDeclaration {
+                name,
+                parameters,
+                return_type,
+                body,
+            } => (name, parameters, return_type, body),
+            _ => return,
+        };
+
+        let span = self.name_span(node.span.start, name).unwrap_or(node.span);
+        let (kind, full_name) = match container {
+            Some(container) => (SymbolKind::Method, format!("{}.{}", container, name)),
+            None => (SymbolKind::Function, name.clone()),
+        };
+
+        // visible in its own body, so it can call itself
+        let symbol = Symbol {
+            name: name.clone(),
+            kind,
+            detail: signature(&full_name, parameters, return_type),
+            value_type: None,
+            span,
+            declaration: node.span,
+            visible: (0, 0),
+        };
+        self.declare(symbol, node.span.start);
+
+        self.enter_scope(node.span.end);
+        if let Some(container) = container {
+            let self_type = Type::Struct(container.to_string());
+            self.variable("self", &self_type, span, node.span, node.span.start);
+        }
+
+        let mut from = span.end;
+        for parameter in parameters {
+            let parameter_span = self.name_span(from, &parameter.name).unwrap_or(span);
+            from = parameter_span.end;
+            if let Some(default) = &parameter.default {
+                self.node(default);
+                from = default.span.end;
+            }
+            self.variable(
+                &parameter.name,
+                &parameter.param_type,
+                parameter_span,
+                parameter_span,
+                node.span.start,
+            );
+        }
+
+        self.statements(body);
+        self.exit_scope();
+    }
+
+    fn node(&mut self, node: &ASTNode) {
+        match &node.kind {
+            ASTNodeKind::VariableDeclaration {
+                var_type,
+                name,
+                value,
+            } => {
+                self.node(value);
+                let span = self.name_span(node.span.start, name).unwrap_or(node.span);
+                self.variable(name, var_type, span, node.span, node.span.end);
+            }
+            ASTNodeKind::FunctionDeclaration { .. } => self.function(node, None),
+            ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            } => {
+                let span = self.name_span(node.span.start, name).unwrap_or(node.span);
+                let symbol = Symbol {
+                    name: name.clone(),
+                    kind: SymbolKind::Struct,
+                    detail: format!("struct {}", name),
+                    value_type: None,
+                    span,
+                    declaration: node.span,
+                    visible: (0, 0),
+                };
+                self.declare(symbol, node.span.start);
+
+                let mut members: Vec<Member> = fields
+                    .iter()
+                    .map(|field| Member {
+                        name: field.name.clone(),
+                        detail: format!("{} {}", field.field_type, field.name),
+                        kind: SymbolKind::Variable,
+                    })
+                    .collect();
+
+                for method in methods {
+                    if let ASTNodeKind::FunctionDeclaration {
+                        name: method_name,
+                        parameters,
+                        return_type,
+                        ..
+                    } = &method.kind
+                    {
+                        members.push(Member {
+                            name: method_name.clone(),
+                            detail: signature(
+                                &format!("{}.{}", name, method_name),
+                                parameters,
+                                return_type,
+                            ),
+                            kind: SymbolKind::Method,
+                        });
+                    }
+                    self.function(method, Some(name));
+                }
+                self.symbols.members.insert(name.clone(), members);
+            }
+            ASTNodeKind::IfStatement {
+                condition,
+                then_body,
+                else_body,
+            } => {
+                self.node(condition);
+                self.block(then_body, node.span.end);
+                if let Some(else_body) = else_body {
+                    self.block(else_body, node.span.end);
+                }
+            }
+            ASTNodeKind::WhileStatement { condition, body } => {
+                self.node(condition);
+                self.block(body, node.span.end);
+            }
+            ASTNodeKind::Test { body, .. } => self.block(body, node.span.end),
+            ASTNodeKind::ForStatement {
+                var_type,
+                name,
+                iterable,
+                body,
+            } => {
+                self.node(iterable);
+                self.enter_scope(node.span.end);
+                let span = self.name_span(node.span.start, name).unwrap_or(node.span);
+                self.variable(name, var_type, span, span, span.end);
+                self.statements(body);
+                self.exit_scope();
+            }
+            ASTNodeKind::MatchStatement { subject, arms } => {
+                self.node(subject);
+                for arm in arms {
+                    self.block(&arm.body, node.span.end);
+                }
+            }
+            // a named function used as a value
+            ASTNodeKind::Identifier(name) => match self.resolve(name, false) {
+                Some(index) => self.symbols.references.push((node.span, index)),
+                None => self.reference(node.span, name, true),
+            },
+            ASTNodeKind::FunctionCall { name, arguments } => {
+                let span = Span {
+                    end: node.span.start + name.len(),
+                    ..node.span
+                };
+                // a variable holding a function is called by its name too
+                let variable = self.resolve(name, false).filter(|index| {
+                    matches!(
+                        self.symbols.symbols[*index].value_type,
+                        Some(Type::Function(..))
+                    )
+                });
+                match variable {
+                    Some(index) => self.symbols.references.push((span, index)),
+                    None => self.reference(span, name, true),
+                }
+                self.statements(arguments);
+            }
+            ASTNodeKind::Call { callee, arguments } => {
+                self.node(callee);
+                self.statements(arguments);
+            }
+            ASTNodeKind::FunctionLiteral {
+                parameters, body, ..
+            } => {
+                self.enter_scope(node.span.end);
+                let mut from = node.span.start;
+                for parameter in parameters {
+                    let span = self.name_span(from, &parameter.name).unwrap_or(node.span);
+                    from = span.end;
+                    if let Some(default) = &parameter.default {
+                        self.node(default);
+                        from = default.span.end;
+                    }
+                    self.variable(
+                        &parameter.name,
+                        &parameter.param_type,
+                        span,
+                        span,
+                        span.start,
+                    );
+                }
+                self.statements(body);
+                self.exit_scope();
+            }
+            ASTNodeKind::Statement(expression) | ASTNodeKind::ReturnStatement(Some(expression)) => {
+                self.node(expression)
+            }
+            ASTNodeKind::BinaryOperation { left, right, .. } => {
+                self.node(left);
+                self.node(right);
+            }
+            ASTNodeKind::UnaryOperation { operand, .. } => self.node(operand),
+            ASTNodeKind::MethodCall {
+                object, arguments, ..
+            } => {
+                self.node(object);
+                self.statements(arguments);
+            }
+            ASTNodeKind::FieldAccess { object, .. } => self.node(object),
+            ASTNodeKind::Index { object, index } => {
+                self.node(object);
+                self.node(index);
+            }
+            ASTNodeKind::ArrayLiteral(elements) => self.statements(elements),
+            ASTNodeKind::StructLiteral { fields, .. } => {
+                for (_, value) in fields {
+                    self.node(value);
+                }
+            }
+            ASTNodeKind::Range { start, end, step } => {
+                self.node(start);
+                self.node(end);
+                if let Some(step) = step {
+                    self.node(step);
+                }
+            }
+            _ => (),
+        }
+    }
+}
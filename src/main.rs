@@ -1,70 +1,373 @@
-use std::{env, fs, time::Instant};
+// every module keeps its implementation in a file named after the module
+#![allow(clippy::module_inception)]
 
-use bytecode::Bytecode;
-use lexer::Lexer;
-use parser::Parser;
-use vm::VM;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
 
-mod analyzer;
-mod bytecode;
-mod lexer;
-mod parser;
-mod stdlib;
-mod vm;
+use boo_lang::{
+    formatter::format_source,
+    lexer::{Lexer, SpannedToken},
+    parser::{ASTNode, ASTNodeKind, Parser},
+    Diagnostic, Engine, OptLevel, Program, SourceMap,
+};
+use lsp::Server;
+use repl::Repl;
+
+mod lsp;
+mod repl;
+
+fn report(diagnostics: Vec<Diagnostic>, filename: &str, contents: &str, sources: &SourceMap) -> ! {
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic.render_with(filename, contents, sources));
+    }
+
+    if diagnostics.len() > 1 {
+        eprintln!("{} errors found", diagnostics.len());
+    }
+
+    process::exit(1);
+}
+
+const USAGE: &str = "usage: boo [--import-path=<dir>]... [-O0|-O1] [repl | [run] [--emit=tokens|ast|bytecode] <file> | build <file> [-o <output>] | fmt [--check] <file>... | test <file>... | lsp]";
+
+// a compilation stage to print instead of running the program
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Bytecode,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
 fn main() -> Result<(), String> {
-    let filename = env::args().nth(1).unwrap_or_else(|| "main.boo".to_string());
+    let mut emit = None;
+    let mut import_paths = Vec::new();
+    let mut opt_level = OptLevel::default();
+    let mut args = Vec::new();
+    for arg in env::args().skip(1) {
+        if let Some(path) = arg.strip_prefix("--import-path=") {
+            import_paths.push(PathBuf::from(path));
+            continue;
+        }
 
-    let contents = fs::read_to_string(&filename)
-        .map_err(|e| format!("Unable to read file {}: {}", filename, e))?;
+        // `-O0` compiles the code as it is written, to compare the optimized
+        // code with
+        if let Some(level) = arg.strip_prefix("-O") {
+            opt_level = match level {
+                "0" => OptLevel::O0,
+                "1" => OptLevel::O1,
+                _ => {
+                    eprintln!(
+                        "Unknown optimization level '-O{}', expected -O0 or -O1",
+                        level
+                    );
+                    usage();
+                }
+            };
+            continue;
+        }
 
-    let mut lexer = Lexer::new(&contents);
-    let tokens = lexer.tokenize();
+        emit = match arg.strip_prefix("--emit=") {
+            Some("tokens") => Some(Emit::Tokens),
+            Some("ast") => Some(Emit::Ast),
+            Some("bytecode") => Some(Emit::Bytecode),
+            Some(kind) => {
+                eprintln!(
+                    "Unknown --emit kind '{}', expected tokens, ast or bytecode",
+                    kind
+                );
+                usage();
+            }
+            None => {
+                args.push(arg);
+                continue;
+            }
+        };
+    }
 
-    if tokens.is_err() {
-        return Err(format!("Lexer error: {}", tokens.err().unwrap()));
+    match (
+        args.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .as_slice(),
+        emit,
+    ) {
+        // without a file (or with `repl`) start an interactive session
+        ([] | ["repl"], None) => Repl::new(import_paths).run(),
+        (["build", filename], None) => build(
+            filename,
+            &Path::new(filename).with_extension("booc"),
+            &import_paths,
+            opt_level,
+        )?,
+        (["build", filename, "-o", output], None) => {
+            build(filename, Path::new(output), &import_paths, opt_level)?
+        }
+        (["fmt", rest @ ..], None) if !rest.is_empty() => fmt(rest)?,
+        (["test", rest @ ..], None) if !rest.is_empty() => test(rest, &import_paths, opt_level)?,
+        // a language server for editors, speaking json-rpc over stdio
+        (["lsp"], None) => Server::new(import_paths).run()?,
+        (["run", filename], emit) => run(filename, emit, &import_paths, opt_level)?,
+        ([filename], emit) if *filename != "build" && *filename != "run" => {
+            run(filename, emit, &import_paths, opt_level)?
+        }
+        _ => usage(),
     }
 
-    // println!("Tokens: {:#?}", tokens);
+    Ok(())
+}
+
+fn read_source(filename: &str) -> Result<String, String> {
+    fs::read_to_string(filename).map_err(|e| format!("Unable to read file {}: {}", filename, e))
+}
 
-    let mut parser = Parser::new(tokens.unwrap());
-    let ast = parser.parse_program();
+fn tokenize(filename: &str, contents: &str) -> Vec<SpannedToken> {
+    match Lexer::new(contents).tokenize() {
+        Ok(tokens) => tokens,
+        Err(diagnostic) => report(vec![diagnostic], filename, contents, &SourceMap::default()),
+    }
+}
+
+fn parse(filename: &str, contents: &str) -> ASTNode {
+    let tokens = tokenize(filename, contents);
+    match Parser::new(tokens).parse_program() {
+        Ok(ast) => ast,
+        Err(diagnostics) => report(diagnostics, filename, contents, &SourceMap::default()),
+    }
+}
 
-    if ast.is_err() {
-        return Err(format!("Parser error: {}", ast.err().unwrap()));
+fn new_engine(import_paths: &[PathBuf], opt_level: OptLevel) -> Engine {
+    let mut engine = Engine::new();
+    for path in import_paths {
+        engine.add_import_path(path);
     }
+    engine.set_opt_level(opt_level);
+    engine
+}
 
-    // println!("AST: {:#?}", ast);
+// lexes, parses, checks and compiles a source file and its imports,
+// reporting any error
+fn compile(
+    filename: &str,
+    contents: &str,
+    import_paths: &[PathBuf],
+    opt_level: OptLevel,
+) -> (Engine, Program) {
+    let mut engine = new_engine(import_paths, opt_level);
+    let result = engine.compile_file(Path::new(filename), contents);
 
-    let mut typechecker = analyzer::TypeChecker::new(ast.clone().unwrap());
-    let result = typechecker.check_program();
+    for warning in engine.take_warnings() {
+        eprintln!(
+            "{}",
+            warning.render_with(filename, contents, engine.sources())
+        );
+    }
 
-    if result.is_err() {
-        return Err(format!("Typechecker error: {}", result.err().unwrap()));
+    match result {
+        Ok(program) => (engine, program),
+        Err(error) => report(error.diagnostics, filename, contents, engine.sources()),
     }
+}
 
-    let mut bytecode_compiler = Bytecode::new(ast.unwrap());
-    let bytecode = bytecode_compiler.compile();
+fn build(
+    filename: &str,
+    output: &Path,
+    import_paths: &[PathBuf],
+    opt_level: OptLevel,
+) -> Result<(), String> {
+    let contents = read_source(filename)?;
+    let (_, program) = compile(filename, &contents, import_paths, opt_level);
+    let bytes = program.to_bytes().map_err(|error| error.to_string())?;
 
-    if bytecode.is_err() {
-        return Err(format!(
-            "Bytecode compiler error: {}",
-            bytecode.err().unwrap()
-        ));
+    fs::write(output, bytes)
+        .map_err(|e| format!("Unable to write file {}: {}", output.display(), e))
+}
+
+// rewrites source files in the canonical style, with `--check` only reports
+// the files that are not formatted and fails if there are any
+fn fmt(args: &[&str]) -> Result<(), String> {
+    let check = args.contains(&"--check");
+    let filenames: Vec<&str> = args
+        .iter()
+        .copied()
+        .filter(|arg| *arg != "--check")
+        .collect();
+    if filenames.is_empty() {
+        usage();
     }
 
-    // println!("Bytecode: {:#?}", bytecode);
+    let mut unformatted = 0;
+    for filename in filenames {
+        let contents = read_source(filename)?;
+        let formatted = match format_source(&contents) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => report(diagnostics, filename, &contents, &SourceMap::default()),
+        };
+
+        if formatted == contents {
+            continue;
+        }
+
+        if check {
+            eprintln!("{} is not formatted", filename);
+            unformatted += 1;
+        } else {
+            fs::write(filename, formatted)
+                .map_err(|e| format!("Unable to write file {}: {}", filename, e))?;
+        }
+    }
+
+    if unformatted > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+// runs the `test` blocks of source files, each in a fresh program that ends
+// with the test, and fails if any of them fails
+fn test(filenames: &[&str], import_paths: &[PathBuf], opt_level: OptLevel) -> Result<(), String> {
+    let mut passed = 0;
+    let mut failures = Vec::new();
+
+    for filename in filenames {
+        let contents = read_source(filename)?;
+        let tests: Vec<String> = match parse(filename, &contents).kind {
+            ASTNodeKind::Program(statements) => statements
+                .into_iter()
+                .filter_map(|statement| match statement.kind {
+                    ASTNodeKind::Test { name, .. } => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            _ => unreachable!("Unexpected node type, expected program"),
+        };
+
+        // every test is checked before any of them runs
+        compile(filename, &contents, import_paths, opt_level);
+
+        let plural = if tests.len() == 1 { "" } else { "s" };
+        println!("running {} test{} in {}", tests.len(), plural, filename);
+        for name in tests {
+            let mut engine = new_engine(import_paths, opt_level);
+            engine.set_test(Some(&name));
+
+            let result = engine
+                .compile_file(Path::new(filename), &contents)
+                .and_then(|program| engine.run(&program));
+
+            match result {
+                Ok(_) => {
+                    println!("test {} ... ok", name);
+                    passed += 1;
+                }
+                Err(error) => {
+                    println!("test {} ... FAILED", name);
+                    let rendered = error.render_with(filename, &contents, engine.sources());
+                    failures.push((format!("{} in {}", name, filename), rendered));
+                }
+            }
+        }
+    }
+
+    for (name, error) in &failures {
+        println!("\n---- {} ----\n{}", name, error);
+    }
+
+    let status = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        status,
+        passed,
+        failures.len()
+    );
+
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+// runs a source file, or a compiled `.booc` file without recompiling it, or
+// prints one of its compilation stages
+fn run(
+    filename: &str,
+    emit: Option<Emit>,
+    import_paths: &[PathBuf],
+    opt_level: OptLevel,
+) -> Result<(), String> {
+    let is_compiled = Path::new(filename)
+        .extension()
+        .is_some_and(|extension| extension == "booc");
+
+    let (mut engine, program, contents, sources) = if is_compiled {
+        let bytes =
+            fs::read(filename).map_err(|e| format!("Unable to read file {}: {}", filename, e))?;
+        let program = Program::from_bytes(&bytes)
+            .map_err(|e| format!("Invalid bytecode file {}: {}", filename, e))?;
+
+        match emit {
+            None => (),
+            Some(Emit::Bytecode) => {
+                // label names are not stored, jump targets get generated ones
+                print!("{}", program.disassemble());
+                return Ok(());
+            }
+            Some(_) => return Err(format!("{} is compiled, it has no tokens or ast", filename)),
+        }
+
+        // the source is not available, errors only point at a line and column
+        let sources = program.source_map();
+        (Engine::new(), program, String::new(), sources)
+    } else {
+        let contents = read_source(filename)?;
+
+        match emit {
+            None => (),
+            Some(Emit::Tokens) => {
+                for token in tokenize(filename, &contents) {
+                    println!(
+                        "{}:{}\t{:?}",
+                        token.span.line, token.span.column, token.token
+                    );
+                }
+                return Ok(());
+            }
+            Some(Emit::Ast) => {
+                println!("{:#?}", parse(filename, &contents));
+                return Ok(());
+            }
+            Some(Emit::Bytecode) => {
+                let (_, program) = compile(filename, &contents, import_paths, opt_level);
+                print!("{}", program.disassemble());
+                return Ok(());
+            }
+        }
 
-    let mut vm = VM::new(bytecode.unwrap());
+        let (engine, program) = compile(filename, &contents, import_paths, opt_level);
+        let sources = engine.sources().clone();
+        (engine, program, contents, sources)
+    };
 
     let start = Instant::now();
 
-    let result = vm.run();
+    let result = engine.run(&program);
 
     let duration = start.elapsed();
 
-    if result.is_err() {
-        println!("VM error: {}", result.err().unwrap());
+    // a `.booc` file reports errors in the source file it was built from
+    if let Err(error) = result {
+        let filename = program.main_file().unwrap_or(filename);
+        eprintln!("{}", error.render_with(filename, &contents, &sources));
     }
 
     println!("Execution time: {:?}", duration);
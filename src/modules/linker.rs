@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, Span},
+    lexer::Type,
+    parser::{ASTNode, ASTNodeKind, FunctionParts, MatchArm, Parameter, StructField},
+};
+
+// everything declared at the top level of a module, importers can use all of it
+#[derive(Clone, Debug, Default)]
+pub struct Exports {
+    pub functions: HashSet<String>,
+    pub structs: HashSet<String>,
+    pub globals: HashSet<String>,
+}
+
+impl Exports {
+    pub fn of(statements: &[ASTNode]) -> Self {
+        let mut exports = Exports::default();
+        for statement in statements {
+            match &statement.kind {
+                ASTNodeKind::FunctionDeclaration { name, .. } => {
+                    exports.functions.insert(name.clone());
+                }
+                ASTNodeKind::StructDeclaration { name, .. } => {
+                    exports.structs.insert(name.clone());
+                }
+                ASTNodeKind::VariableDeclaration { name, .. } => {
+                    exports.globals.insert(name.clone());
+                }
+                _ => (),
+            }
+        }
+        exports
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Module {
+    pub prefix: String, // every top level name of the module starts with it
+    pub exports: Exports,
+}
+
+// the name a top level declaration of the module with `prefix` gets, giving
+// every module its own namespace in the type checker and the VM
+pub fn qualified_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+// renames the top level declarations of a module and every use of them,
+// `module.member` of imported modules becomes the member's qualified name
+pub struct Linker<'a> {
+    prefix: &'a str, // empty for the main source, its names are kept
+    exports: &'a Exports,
+    imports: &'a HashMap<String, Module>,
+    scopes: Vec<HashSet<String>>, // local names, innermost last, empty at the top level
+}
+
+impl<'a> Linker<'a> {
+    pub fn new(
+        prefix: &'a str,
+        exports: &'a Exports,
+        imports: &'a HashMap<String, Module>,
+    ) -> Self {
+        Self {
+            prefix,
+            exports,
+            imports,
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn link(&mut self, statements: Vec<ASTNode>) -> Result<Vec<ASTNode>, Vec<Diagnostic>> {
+        let mut linked = Vec::with_capacity(statements.len());
+        let mut errors = Vec::new();
+
+        for statement in statements {
+            // the tests of a module only run when the module itself is tested
+            if !self.prefix.is_empty() && matches!(statement.kind, ASTNodeKind::Test { .. }) {
+                continue;
+            }
+
+            match self.node(statement) {
+                Ok(statement) => linked.push(statement),
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(linked)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains(name))
+    }
+
+    fn declare_local(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string());
+        }
+    }
+
+    // the module a name refers to, unless a local variable shadows it
+    fn module(&self, name: &str) -> Option<&'a Module> {
+        if self.is_local(name) {
+            return None;
+        }
+        self.imports.get(name)
+    }
+
+    fn member(
+        &self,
+        module_name: &str,
+        member: &str,
+        kind: &str,
+        span: Span,
+    ) -> Result<String, Diagnostic> {
+        let module = match self.imports.get(module_name) {
+            Some(module) => module,
+            None => return Err(error(format!("Unknown module '{}'", module_name), span)),
+        };
+
+        let exported = match kind {
+            "function" => &module.exports.functions,
+            "struct" => &module.exports.structs,
+            _ => &module.exports.globals,
+        };
+        if !exported.contains(member) {
+            return Err(error(
+                format!("Module '{}' has no {} '{}'", module_name, kind, member),
+                span,
+            ));
+        }
+
+        Ok(qualified_name(&module.prefix, member))
+    }
+
+    fn link_type(&self, value_type: Type, span: Span) -> Result<Type, Diagnostic> {
+        match value_type {
+            Type::Struct(name) => match name.split_once('.') {
+                Some((module, member)) => {
+                    Ok(Type::Struct(self.member(module, member, "struct", span)?))
+                }
+                None if self.exports.structs.contains(&name) => {
+                    Ok(Type::Struct(qualified_name(self.prefix, &name)))
+                }
+                None => Ok(Type::Struct(name)),
+            },
+            Type::Array(element_type) => {
+                Ok(Type::Array(Box::new(self.link_type(*element_type, span)?)))
+            }
+            Type::Optional(inner) => Ok(Type::Optional(Box::new(self.link_type(*inner, span)?))),
+            Type::Function(parameters, return_type) => Ok(Type::Function(
+                parameters
+                    .into_iter()
+                    .map(|parameter| self.link_type(parameter, span))
+                    .collect::<Result<_, _>>()?,
+                Box::new(self.link_type(*return_type, span)?),
+            )),
+            value_type => Ok(value_type),
+        }
+    }
+
+    fn block(&mut self, statements: Vec<ASTNode>) -> Result<Vec<ASTNode>, Diagnostic> {
+        self.scopes.push(HashSet::new());
+        let statements = self.nodes(statements);
+        self.scopes.pop();
+        statements
+    }
+
+    fn nodes(&mut self, nodes: Vec<ASTNode>) -> Result<Vec<ASTNode>, Diagnostic> {
+        nodes.into_iter().map(|node| self.node(node)).collect()
+    }
+
+    // links the node in place, keeping its allocation
+    fn boxed(&mut self, mut node: Box<ASTNode>) -> Result<Box<ASTNode>, Diagnostic> {
+        *node = self.node(*node)?;
+        Ok(node)
+    }
+
+    // a function or a method, methods keep their name
+    fn function(
+        &mut self,
+        name: String,
+        parameters: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+        span: Span,
+    ) -> Result<ASTNodeKind, Diagnostic> {
+        let (parameters, return_type, body) =
+            self.function_parts(parameters, return_type, body, span)?;
+
+        Ok(ASTNodeKind::FunctionDeclaration {
+            name,
+            parameters,
+            return_type,
+            body,
+        })
+    }
+
+    // the parameters, return type and body of a function or a function literal
+    fn function_parts(
+        &mut self,
+        parameters: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+        span: Span,
+    ) -> Result<FunctionParts, Diagnostic> {
+        let return_type = match return_type {
+            Some(return_type) => Some(self.link_type(return_type, span)?),
+            None => None,
+        };
+
+        self.scopes.push(HashSet::from(["self".to_string()]));
+        let parts = self.parameters(parameters, span).and_then(|parameters| {
+            let body = self.nodes(body)?;
+            Ok((parameters, return_type, body))
+        });
+        self.scopes.pop();
+
+        parts
+    }
+
+    // declares the parameters in the current scope, a default value sees the
+    // parameters before it
+    fn parameters(
+        &mut self,
+        parameters: Vec<Parameter>,
+        span: Span,
+    ) -> Result<Vec<Parameter>, Diagnostic> {
+        parameters
+            .into_iter()
+            .map(|parameter| {
+                let parameter = Parameter {
+                    param_type: self.link_type(parameter.param_type, span)?,
+                    default: parameter
+                        .default
+                        .map(|default| self.node(default))
+                        .transpose()?,
+                    ..parameter
+                };
+                self.declare_local(&parameter.name);
+                Ok(parameter)
+            })
+            .collect()
+    }
+
+    fn node(&mut self, node: ASTNode) -> Result<ASTNode, Diagnostic> {
+        let span = node.span;
+        let top_level = self.scopes.is_empty();
+
+        let kind = match node.kind {
+            ASTNodeKind::Program(statements) => ASTNodeKind::Program(self.nodes(statements)?),
+            ASTNodeKind::Statement(expression) => ASTNodeKind::Statement(self.boxed(expression)?),
+            ASTNodeKind::ReturnStatement(value) => ASTNodeKind::ReturnStatement(match value {
+                Some(value) => Some(self.boxed(value)?),
+                None => None,
+            }),
+            ASTNodeKind::BinaryOperation { left, op, right } => ASTNodeKind::BinaryOperation {
+                left: self.boxed(left)?,
+                op,
+                right: self.boxed(right)?,
+            },
+            ASTNodeKind::UnaryOperation { op, operand } => ASTNodeKind::UnaryOperation {
+                op,
+                operand: self.boxed(operand)?,
+            },
+            ASTNodeKind::FunctionDeclaration {
+                name,
+                parameters,
+                return_type,
+                body,
+            } => {
+                let name = match top_level {
+                    true => qualified_name(self.prefix, &name),
+                    false => name,
+                };
+                self.function(name, parameters, return_type, body, span)?
+            }
+            // `module.function(...)`
+            ASTNodeKind::MethodCall {
+                object,
+                method,
+                arguments,
+            } => match &object.kind {
+                ASTNodeKind::Identifier(module) if self.module(module).is_some() => {
+                    // a global holding a function is called like a function
+                    let kind = match self.imports[module].exports.globals.contains(&method) {
+                        true => "variable",
+                        false => "function",
+                    };
+                    ASTNodeKind::FunctionCall {
+                        name: self.member(module, &method, kind, span)?,
+                        arguments: self.nodes(arguments)?,
+                    }
+                }
+                _ => ASTNodeKind::MethodCall {
+                    object: self.boxed(object)?,
+                    method,
+                    arguments: self.nodes(arguments)?,
+                },
+            },
+            ASTNodeKind::FunctionCall { name, arguments } => {
+                // a local variable holding a function hides the functions of the module
+                let exported =
+                    self.exports.functions.contains(&name) || self.exports.globals.contains(&name);
+                let name = match exported && !self.is_local(&name) {
+                    true => qualified_name(self.prefix, &name),
+                    false => name,
+                };
+                ASTNodeKind::FunctionCall {
+                    name,
+                    arguments: self.nodes(arguments)?,
+                }
+            }
+            ASTNodeKind::Call { callee, arguments } => ASTNodeKind::Call {
+                callee: self.boxed(callee)?,
+                arguments: self.nodes(arguments)?,
+            },
+            ASTNodeKind::FunctionLiteral {
+                parameters,
+                return_type,
+                body,
+            } => {
+                let (parameters, return_type, body) =
+                    self.function_parts(parameters, return_type, body, span)?;
+                ASTNodeKind::FunctionLiteral {
+                    parameters,
+                    return_type,
+                    body,
+                }
+            }
+            ASTNodeKind::IfStatement {
+                condition,
+                then_body,
+                else_body,
+            } => ASTNodeKind::IfStatement {
+                condition: self.boxed(condition)?,
+                then_body: self.block(then_body)?,
+                else_body: match else_body {
+                    Some(else_body) => Some(self.block(else_body)?),
+                    None => None,
+                },
+            },
+            ASTNodeKind::WhileStatement { condition, body } => ASTNodeKind::WhileStatement {
+                condition: self.boxed(condition)?,
+                body: self.block(body)?,
+            },
+            ASTNodeKind::MatchStatement { subject, arms } => ASTNodeKind::MatchStatement {
+                subject: self.boxed(subject)?,
+                arms: arms
+                    .into_iter()
+                    .map(|arm| {
+                        Ok(MatchArm {
+                            body: self.block(arm.body)?,
+                            ..arm
+                        })
+                    })
+                    .collect::<Result<_, Diagnostic>>()?,
+            },
+            ASTNodeKind::ForStatement {
+                var_type,
+                name,
+                iterable,
+                body,
+            } => {
+                let var_type = self.link_type(var_type, span)?;
+                let iterable = self.boxed(iterable)?;
+
+                self.scopes.push(HashSet::from([name.clone()]));
+                let body = self.nodes(body);
+                self.scopes.pop();
+
+                ASTNodeKind::ForStatement {
+                    var_type,
+                    name,
+                    iterable,
+                    body: body?,
+                }
+            }
+            ASTNodeKind::Range { start, end, step } => ASTNodeKind::Range {
+                start: self.boxed(start)?,
+                end: self.boxed(end)?,
+                step: match step {
+                    Some(step) => Some(self.boxed(step)?),
+                    None => None,
+                },
+            },
+            ASTNodeKind::VariableDeclaration {
+                var_type,
+                name,
+                value,
+            } => {
+                let var_type = self.link_type(var_type, span)?;
+                let value = self.boxed(value)?;
+
+                let name = match top_level {
+                    true => qualified_name(self.prefix, &name),
+                    false => {
+                        self.declare_local(&name);
+                        name
+                    }
+                };
+                ASTNodeKind::VariableDeclaration {
+                    var_type,
+                    name,
+                    value,
+                }
+            }
+            ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            } => {
+                let fields = fields
+                    .into_iter()
+                    .map(|field| {
+                        Ok(StructField {
+                            field_type: self.link_type(field.field_type, span)?,
+                            ..field
+                        })
+                    })
+                    .collect::<Result<_, Diagnostic>>()?;
+
+                let methods = methods
+                    .into_iter()
+                    .map(|method| match method.kind {
+                        ASTNodeKind::FunctionDeclaration {
+                            name,
+                            parameters,
+                            return_type,
+                            body,
+                        } => Ok(ASTNode::new(
+                            self.function(name, parameters, return_type, body, method.span)?,
+                            method.span,
+                        )),
+                        _ => self.node(method),
+                    })
+                    .collect::<Result<_, Diagnostic>>()?;
+
+                ASTNodeKind::StructDeclaration {
+                    name: qualified_name(self.prefix, &name),
+                    fields,
+                    methods,
+                }
+            }
+            ASTNodeKind::StructLiteral { name, fields } => {
+                let name = match self.link_type(Type::Struct(name), span)? {
+                    Type::Struct(name) => name,
+                    _ => unreachable!("struct types stay structs"),
+                };
+                let fields = fields
+                    .into_iter()
+                    .map(|(field, value)| Ok((field, self.node(value)?)))
+                    .collect::<Result<_, Diagnostic>>()?;
+
+                ASTNodeKind::StructLiteral { name, fields }
+            }
+            // `module.global`, or `module.function` used as a value
+            ASTNodeKind::FieldAccess { object, field } => match &object.kind {
+                ASTNodeKind::Identifier(module) if self.module(module).is_some() => {
+                    let kind = match self.imports[module].exports.functions.contains(&field) {
+                        true => "function",
+                        false => "variable",
+                    };
+                    ASTNodeKind::Identifier(self.member(module, &field, kind, span)?)
+                }
+                _ => ASTNodeKind::FieldAccess {
+                    object: self.boxed(object)?,
+                    field,
+                },
+            },
+            ASTNodeKind::Index { object, index } => ASTNodeKind::Index {
+                object: self.boxed(object)?,
+                index: self.boxed(index)?,
+            },
+            ASTNodeKind::Identifier(name) => {
+                if self.is_local(&name) {
+                    ASTNodeKind::Identifier(name)
+                } else if self.exports.globals.contains(&name)
+                    || self.exports.functions.contains(&name)
+                {
+                    ASTNodeKind::Identifier(qualified_name(self.prefix, &name))
+                } else if self.imports.contains_key(&name) {
+                    return Err(error(
+                        format!(
+                            "Module '{}' is not a value, use its members like '{}.name'",
+                            name, name
+                        ),
+                        span,
+                    ));
+                } else {
+                    ASTNodeKind::Identifier(name)
+                }
+            }
+            ASTNodeKind::ArrayLiteral(elements) => ASTNodeKind::ArrayLiteral(self.nodes(elements)?),
+            ASTNodeKind::Import { .. } => {
+                return Err(error(
+                    "Imports are only allowed at the top level".to_string(),
+                    span,
+                ))
+            }
+            // the parser reports tests that are not at the top level
+            ASTNodeKind::Test { name, body } => ASTNodeKind::Test {
+                name,
+                body: self.block(body)?,
+            },
+            kind @ (ASTNodeKind::Break
+            | ASTNodeKind::Continue
+            | ASTNodeKind::NumberLiteral(_)
+            | ASTNodeKind::StringLiteral(_)
+            | ASTNodeKind::BooleanLiteral(_)
+            | ASTNodeKind::NoneLiteral) => kind,
+        };
+
+        Ok(ASTNode::new(kind, span))
+    }
+}
+
+pub(super) fn error(message: String, span: Span) -> Diagnostic {
+    Diagnostic::new(DiagnosticKind::Import, message, Some(span))
+}
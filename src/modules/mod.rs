@@ -0,0 +1,5 @@
+mod linker;
+mod modules;
+
+pub use linker::*;
+pub use modules::*;
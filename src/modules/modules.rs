@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{linker::error, Exports, Linker, Module};
+use crate::{
+    diagnostics::{Diagnostic, SourceMap, Span},
+    lexer::Lexer,
+    parser::{ASTNode, ASTNodeKind, Parser},
+};
+
+// finds, parses and links imported modules, each file is loaded once and its
+// code runs before the code importing it
+#[derive(Clone, Debug, Default)]
+pub struct ModuleLoader {
+    search_paths: Vec<PathBuf>, // searched after the importing file's directory
+    modules: HashMap<PathBuf, Module>, // loaded modules by canonical path
+    loading: Vec<(PathBuf, String)>, // canonical path and name of the modules being loaded
+    prefixes: HashSet<String>,
+    imports: HashMap<String, Module>, // imports of the main source, kept across compiles
+    sources: SourceMap,
+}
+
+impl ModuleLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    // the loaded files, diagnostics in modules point into them
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    // resolves the imports of the main source, `file` is its path if it has
+    // one, imports are searched relative to `directory`; returns the code of
+    // every module loaded for the first time followed by the main source
+    pub fn link(
+        &mut self,
+        statements: Vec<ASTNode>,
+        directory: &Path,
+        file: Option<&Path>,
+    ) -> Result<Vec<ASTNode>, Vec<Diagnostic>> {
+        // a module importing the main file is a cycle as well
+        let main =
+            file.and_then(|file| Some((file.canonicalize().ok()?, file.display().to_string())));
+        self.loading.extend(main.clone());
+
+        let mut output = Vec::new();
+        let mut imports = self.imports.clone();
+        let linked = self.link_module(statements, directory, "", &mut imports, &mut output);
+
+        if main.is_some() {
+            self.loading.pop();
+        }
+
+        let (_, statements) = linked?;
+        self.imports = imports;
+        output.extend(statements);
+        Ok(output)
+    }
+
+    // the names the main source's modules were imported with
+    pub fn module_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.imports.keys().cloned()
+    }
+
+    // goes back to an earlier state of the loader after a failed compile, the
+    // files loaded since are kept so its diagnostics can still be rendered
+    pub fn restore(&mut self, mut snapshot: ModuleLoader) {
+        snapshot.sources = std::mem::take(&mut self.sources);
+        *self = snapshot;
+    }
+
+    // resolves `module.member` in an expression using the main source's imports
+    pub fn link_expression(&self, expression: ASTNode) -> Result<ASTNode, Vec<Diagnostic>> {
+        let exports = Exports::default();
+        let mut linked = Linker::new("", &exports, &self.imports).link(vec![expression])?;
+        Ok(linked.remove(0))
+    }
+
+    fn link_module(
+        &mut self,
+        statements: Vec<ASTNode>,
+        directory: &Path,
+        prefix: &str,
+        imports: &mut HashMap<String, Module>,
+        output: &mut Vec<ASTNode>,
+    ) -> Result<(Exports, Vec<ASTNode>), Vec<Diagnostic>> {
+        let mut body = Vec::with_capacity(statements.len());
+        for statement in statements {
+            match statement.kind {
+                ASTNodeKind::Import { path, name } => {
+                    let module = self.load(&path, directory, statement.span, output)?;
+
+                    // importing the same module again keeps its name, like the REPL does
+                    if let Some(existing) = imports.get(&name) {
+                        if existing.prefix != module.prefix {
+                            return Err(vec![error(
+                                format!("'{}' is the name of two imported modules", name),
+                                statement.span,
+                            )]);
+                        }
+                    }
+                    imports.insert(name, module);
+                }
+                _ => body.push(statement),
+            }
+        }
+
+        let exports = Exports::of(&body);
+        for statement in &body {
+            if let ASTNodeKind::VariableDeclaration { name, .. } = &statement.kind {
+                if imports.contains_key(name) {
+                    return Err(vec![error(
+                        format!(
+                            "'{}' is the name of both a variable and an imported module",
+                            name
+                        ),
+                        statement.span,
+                    )]);
+                }
+            }
+        }
+
+        let statements = Linker::new(prefix, &exports, imports).link(body)?;
+        Ok((exports, statements))
+    }
+
+    fn load(
+        &mut self,
+        path: &str,
+        directory: &Path,
+        span: Span,
+        output: &mut Vec<ASTNode>,
+    ) -> Result<Module, Vec<Diagnostic>> {
+        let (file, found) = self
+            .resolve(path, directory)
+            .ok_or_else(|| vec![error(format!("Cannot find module '{}'", path), span)])?;
+
+        let name = found.display().to_string();
+
+        // cached, its code is already part of the program
+        if let Some(module) = self.modules.get(&file) {
+            return Ok(module.clone());
+        }
+
+        if let Some(start) = self
+            .loading
+            .iter()
+            .position(|(loading, _)| *loading == file)
+        {
+            let mut cycle: Vec<&str> = self.loading[start..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect();
+            cycle.push(&name);
+            return Err(vec![error(
+                format!("Import cycle: {}", cycle.join(" -> ")),
+                span,
+            )]);
+        }
+
+        let source = fs::read_to_string(&file).map_err(|e| {
+            vec![error(
+                format!("Unable to read module '{}': {}", name, e),
+                span,
+            )]
+        })?;
+        let id = self.sources.add(name.clone(), source);
+        let source = &self.sources.get(id).expect("file was just added").source;
+
+        let tokens = Lexer::with_file(source, id)
+            .tokenize()
+            .map_err(|diagnostic| vec![diagnostic])?;
+        let statements = match Parser::new(tokens).parse_program()?.kind {
+            ASTNodeKind::Program(statements) => statements,
+            _ => unreachable!("Unexpected node type, expected program"),
+        };
+
+        let prefix = self.unique_prefix(&file);
+        let directory = found.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        self.loading.push((file.clone(), name));
+        let linked = self.link_module(statements, &directory, &prefix, &mut HashMap::new(), output);
+        self.loading.pop();
+
+        // its imports were added to the output while linking, before its own code
+        let (exports, statements) = linked?;
+        output.extend(statements);
+
+        let module = Module { prefix, exports };
+        self.modules.insert(file, module.clone());
+        Ok(module)
+    }
+
+    // the canonical path of an imported file and the path it was found at,
+    // `./` and `../` paths are only searched next to the importing file
+    fn resolve(&self, path: &str, directory: &Path) -> Option<(PathBuf, PathBuf)> {
+        let mut relative = PathBuf::from(path);
+        if relative.extension().is_none() {
+            relative.set_extension("boo");
+        }
+
+        let mut candidates = vec![directory.join(&relative)];
+        if !path.starts_with("./") && !path.starts_with("../") {
+            candidates.extend(self.search_paths.iter().map(|dir| dir.join(&relative)));
+        }
+
+        candidates
+            .into_iter()
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| Some((candidate.canonicalize().ok()?, candidate)))
+    }
+
+    // modules are named after their file, a number keeps the names unique
+    fn unique_prefix(&mut self, file: &Path) -> String {
+        let stem = file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut prefix = stem.clone();
+        let mut count = 1;
+        while !self.prefixes.insert(prefix.clone()) {
+            count += 1;
+            prefix = format!("{}#{}", stem, count);
+        }
+        prefix
+    }
+}
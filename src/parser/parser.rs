@@ -1,12 +1,64 @@
-use std::{iter::Peekable, vec};
+use std::{collections::HashSet, path::Path};
 
-use crate::lexer::{Keyword, Operator, Token, Type};
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, Span},
+    lexer::{Keyword, Operator, SpannedToken, StringPart, Token, Type},
+};
 
 #[derive(Clone, Debug)]
-pub enum ASTNode {
+pub struct ASTNode {
+    pub kind: ASTNodeKind,
+    pub span: Span,
+}
+
+impl ASTNode {
+    pub fn new(kind: ASTNodeKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    // turns a method declared inside `struct_name` into a plain function named
+    // `Struct.method` that receives the instance as its first parameter (`self`)
+    pub fn into_method_function(self, struct_name: &str) -> ASTNode {
+        match self.kind {
+            ASTNodeKind::FunctionDeclaration {
+                name,
+                parameters,
+                return_type,
+                body,
+            } => {
+                let mut method_parameters = vec![Parameter {
+                    name: "self".to_string(),
+                    param_type: Type::Struct(struct_name.to_string()),
+                    default: None,
+                }];
+                method_parameters.extend(parameters);
+
+                ASTNode::new(
+                    ASTNodeKind::FunctionDeclaration {
+                        name: method_function_name(struct_name, &name),
+                        parameters: method_parameters,
+                        return_type,
+                        body,
+                    },
+                    self.span,
+                )
+            }
+            _ => self,
+        }
+    }
+}
+
+pub fn method_function_name(struct_name: &str, method: &str) -> String {
+    format!("{}.{}", struct_name, method)
+}
+
+#[derive(Clone, Debug)]
+pub enum ASTNodeKind {
     Program(Vec<ASTNode>),
     Statement(Box<ASTNode>),
-    ReturnStatement(Box<ASTNode>),
+    ReturnStatement(Option<Box<ASTNode>>), // `None` for a bare `return;`
+    Break,
+    Continue,
     BinaryOperation {
         left: Box<ASTNode>,
         op: Operator,
@@ -31,6 +83,18 @@ pub enum ASTNode {
         name: String,
         arguments: Vec<ASTNode>,
     },
+    // `fun(num x) -> num { ... }` used as a value, it can use the variables
+    // around it
+    FunctionLiteral {
+        parameters: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+    },
+    // calls a function value that is not just a name, like `adders[0](1)`
+    Call {
+        callee: Box<ASTNode>,
+        arguments: Vec<ASTNode>,
+    },
     IfStatement {
         condition: Box<ASTNode>,
         then_body: Vec<ASTNode>,
@@ -40,145 +104,652 @@ pub enum ASTNode {
         condition: Box<ASTNode>,
         body: Vec<ASTNode>,
     },
+    MatchStatement {
+        subject: Box<ASTNode>,
+        arms: Vec<MatchArm>,
+    },
+    ForStatement {
+        var_type: Type,
+        name: String,
+        iterable: Box<ASTNode>,
+        body: Vec<ASTNode>,
+    },
+    // `start..end step n`, only allowed as the iterable of a for loop
+    Range {
+        start: Box<ASTNode>,
+        end: Box<ASTNode>,
+        step: Option<Box<ASTNode>>,
+    },
     VariableDeclaration {
         var_type: Type,
         name: String,
         value: Box<ASTNode>,
     },
+    StructDeclaration {
+        name: String,
+        fields: Vec<StructField>,
+        methods: Vec<ASTNode>,
+    },
+    StructLiteral {
+        name: String,
+        fields: Vec<(String, ASTNode)>,
+    },
+    FieldAccess {
+        object: Box<ASTNode>,
+        field: String,
+    },
+    Index {
+        object: Box<ASTNode>,
+        index: Box<ASTNode>,
+    },
     Identifier(String),
+    ArrayLiteral(Vec<ASTNode>),
     NumberLiteral(f64),
     StringLiteral(String),
     BooleanLiteral(bool),
+    NoneLiteral,
+    // `import "path"` or `import name from "path"`, the members of the module
+    // are used as `name.member`
+    Import {
+        path: String,
+        name: String,
+    },
+    // `test "name" { ... }`, only run by `boo test`, every other run skips it
+    Test {
+        name: String,
+        body: Vec<ASTNode>,
+    },
 }
 
+// the parameters, return type and body shared by functions and function literals
+pub type FunctionParts = (Vec<Parameter>, Option<Type>, Vec<ASTNode>);
+
 #[derive(Clone, Debug)]
 pub struct Parameter {
     pub name: String,
     pub param_type: Type,
-    pub optional: bool,
+    pub default: Option<ASTNode>, // `num x = 5`, used when the argument is left out
+}
+
+#[derive(Clone, Debug)]
+pub struct StructField {
+    pub name: String,
+    pub field_type: Type,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchArm {
+    pub pattern: Option<ASTNode>, // a literal, `None` for `_`
+    pub body: Vec<ASTNode>,
+    pub span: Span, // span of the pattern
 }
 
 pub struct Parser {
-    tokens: Peekable<vec::IntoIter<Token>>,
+    tokens: Vec<SpannedToken>,
+    position: usize,
+    last_span: Span,
+    struct_names: HashSet<String>,
+    module_names: HashSet<String>,
+    block_depth: usize, // blocks around the statement being parsed
+    errors: Vec<Diagnostic>,
+}
+
+// the name a module imported without `name from` is used by, its file name
+pub fn module_name(path: &str) -> Option<String> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    let mut chars = stem.chars();
+    let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+
+    valid.then(|| stem.to_string())
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(tokens: Vec<SpannedToken>) -> Self {
+        // struct names are collected upfront so `Point p` and `Point { ... }`
+        // can be told apart from other identifiers, even before the declaration
+        let struct_names = tokens
+            .windows(2)
+            .filter_map(|pair| match (&pair[0].token, &pair[1].token) {
+                (Token::Keyword(Keyword::Struct), Token::Identifier(name)) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        // module names are collected the same way, so `module.Point p` and
+        // `module.Point { ... }` can be parsed
+        let module_names = tokens
+            .windows(3)
+            .filter_map(
+                |window| match (&window[0].token, &window[1].token, &window[2].token) {
+                    (Token::Keyword(Keyword::Import), Token::String(path), _) => module_name(path),
+                    (
+                        Token::Keyword(Keyword::Import),
+                        Token::Identifier(name),
+                        Token::Identifier(from),
+                    ) if from == "from" => Some(name.clone()),
+                    _ => None,
+                },
+            )
+            .collect();
+
         Self {
-            tokens: tokens.into_iter().peekable(),
+            tokens,
+            position: 0,
+            last_span: Span::default(),
+            struct_names,
+            module_names,
+            block_depth: 0,
+            errors: Vec::new(),
         }
     }
 
+    // modules imported before this source, like in an earlier REPL line
+    pub fn with_module_names(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.module_names.extend(names);
+        self
+    }
+
+    // structs declared before this source, like in an earlier REPL line
+    pub fn with_struct_names(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.struct_names.extend(names);
+        self
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|t| &t.token)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.position + n).map(|t| &t.token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        match self.tokens.get(self.position) {
+            Some(spanned) => {
+                self.position += 1;
+                self.last_span = spanned.span;
+                Some(spanned.token.clone())
+            }
+            None => {
+                // point errors about the end of input right after the last token
+                if let Some(last) = self.tokens.last() {
+                    self.last_span = Span {
+                        start: last.span.end,
+                        column: last.span.column + (last.span.end - last.span.start),
+                        ..last.span
+                    };
+                }
+                None
+            }
+        }
+    }
+
+    // span of the next token, used to mark where a node starts
+    fn peek_span(&self) -> Span {
+        match self.tokens.get(self.position) {
+            Some(spanned) => spanned.span,
+            None => self.last_span,
+        }
+    }
+
+    // span from `start` up to the last consumed token
+    fn span_from(&self, start: Span) -> Span {
+        start.merge(self.last_span)
+    }
+
+    // desugars `"a {x} b"` into `"a " >< x >< " b"`, the chain starts with a
+    // string so the left side of every concatenation is a string
+    fn parse_interpolated_string(
+        &mut self,
+        parts: Vec<StringPart>,
+        span: Span,
+    ) -> Result<ASTNodeKind, String> {
+        let mut parts = parts.into_iter().peekable();
+
+        let mut node = match parts.next_if(|part| matches!(part, StringPart::Text(_))) {
+            Some(StringPart::Text(text)) => ASTNode::new(ASTNodeKind::StringLiteral(text), span),
+            _ => ASTNode::new(ASTNodeKind::StringLiteral(String::new()), span),
+        };
+
+        for part in parts {
+            let right = match part {
+                StringPart::Text(text) => ASTNode::new(ASTNodeKind::StringLiteral(text), span),
+                StringPart::Code(tokens) => self.parse_interpolation(tokens)?,
+            };
+
+            node = ASTNode::new(
+                ASTNodeKind::BinaryOperation {
+                    left: Box::new(node),
+                    op: Operator::Concat,
+                    right: Box::new(right),
+                },
+                span,
+            );
+        }
+
+        Ok(node.kind)
+    }
+
+    // parses the tokens between the braces of an interpolation as one expression
+    fn parse_interpolation(&mut self, tokens: Vec<SpannedToken>) -> Result<ASTNode, String> {
+        let mut parser = Parser::new(tokens);
+        parser.struct_names = self.struct_names.clone();
+
+        let result = parser
+            .parse_expression()
+            .and_then(|expr| match parser.next() {
+                None => Ok(expr),
+                Some(token) => Err(format!("Unexpected token in interpolation: {:?}", token)),
+            });
+
+        // errors point into the string
+        if result.is_err() {
+            self.last_span = parser.last_span;
+        }
+
+        result
+    }
+
     fn parse_primary(&mut self) -> Result<ASTNode, String> {
+        let start = self.peek_span();
+
         // Check for unary operators first
-        if let Some(Token::Operator(Operator::LogicalNot)) = self.tokens.peek() {
-            self.tokens.next(); // consume the !
+        if let Some(Token::Operator(Operator::LogicalNot)) = self.peek() {
+            self.next(); // consume the !
             let operand = self.parse_primary()?;
-            return Ok(ASTNode::UnaryOperation {
-                op: Operator::LogicalNot,
-                operand: Box::new(operand),
-            });
+            return Ok(ASTNode::new(
+                ASTNodeKind::UnaryOperation {
+                    op: Operator::LogicalNot,
+                    operand: Box::new(operand),
+                },
+                self.span_from(start),
+            ));
         }
 
-        let mut node = match self.tokens.next() {
+        let kind = match self.next() {
             Some(Token::LeftParen) => {
                 let expr = self.parse_expression()?;
 
                 // make sure we have a closing parenthensis
-                match self.tokens.next() {
-                    Some(Token::RightParen) => Ok(expr),
+                match self.next() {
+                    Some(Token::RightParen) => Ok(expr.kind),
                     Some(token) => Err(format!("Expected ')', found {:?}", token)),
                     _ => Err("Unexpected end of input".to_string()),
                 }
             }
-            Some(Token::Identifier(ident)) => match self.tokens.peek() {
+            Some(Token::Identifier(ident)) => match self.peek() {
                 Some(Token::LeftParen) => {
-                    self.tokens.next();
+                    self.next();
                     self.parse_function_call(ident)
                 }
-                _ => Ok(ASTNode::Identifier(ident)),
+                Some(Token::LeftBrace) if self.struct_names.contains(&ident) => {
+                    self.next();
+                    self.parse_struct_literal(ident)
+                }
+                // `module.Point { ... }`
+                Some(Token::Period) if self.is_qualified_struct_literal(&ident) => {
+                    self.next();
+                    let name = match self.next() {
+                        Some(Token::Identifier(name)) => format!("{}.{}", ident, name),
+                        _ => unreachable!("checked by is_qualified_struct_literal"),
+                    };
+                    self.next(); // '{'
+                    self.parse_struct_literal(name)
+                }
+                _ => Ok(ASTNodeKind::Identifier(ident)),
             },
-            Some(Token::Number(num)) => Ok(ASTNode::NumberLiteral(num)),
-            Some(Token::String(str)) => Ok(ASTNode::StringLiteral(str)),
-            Some(Token::Boolean(bool)) => Ok(ASTNode::BooleanLiteral(bool)),
+            Some(Token::LeftBracket) => self.parse_array_literal(),
+            Some(Token::Keyword(Keyword::Fun)) => self.parse_function_literal(),
+            Some(Token::Number(num)) => Ok(ASTNodeKind::NumberLiteral(num)),
+            Some(Token::String(str)) => Ok(ASTNodeKind::StringLiteral(str)),
+            Some(Token::InterpolatedString(parts)) => self.parse_interpolated_string(parts, start),
+            Some(Token::Boolean(bool)) => Ok(ASTNodeKind::BooleanLiteral(bool)),
+            Some(Token::None) => Ok(ASTNodeKind::NoneLiteral),
             Some(token) => Err(format!("Unexpected token: {:?}", token)),
             _ => Err("Unexpected end of input".to_string()),
+        }?;
+
+        let mut node = ASTNode::new(kind, self.span_from(start));
+
+        // check for method calls and indexing
+        loop {
+            match self.peek() {
+                Some(Token::Period) => {
+                    // consume the period
+                    self.next();
+                    node = self.parse_method_call(node, start)?;
+                }
+                // a bracket starting a type belongs to the next declaration, since
+                // semicolons are optional
+                Some(Token::LeftBracket) if !self.is_declaration_ahead() => {
+                    // consume the left bracket
+                    self.next();
+
+                    let index = self.parse_expression()?;
+
+                    match self.next() {
+                        Some(Token::RightBracket) => (),
+                        Some(token) => return Err(format!("Expected ']', found {:?}", token)),
+                        _ => return Err("Unexpected end of input".to_string()),
+                    }
+
+                    node = ASTNode::new(
+                        ASTNodeKind::Index {
+                            object: Box::new(node),
+                            index: Box::new(index),
+                        },
+                        self.span_from(start),
+                    );
+                }
+                // a parenthesis on the next line starts a new statement
+                Some(Token::LeftParen) if !self.starts_line() => {
+                    self.next();
+                    let arguments = self.parse_arguments()?;
+                    node = ASTNode::new(
+                        ASTNodeKind::Call {
+                            callee: Box::new(node),
+                            arguments,
+                        },
+                        self.span_from(start),
+                    );
+                }
+                _ => break,
+            }
+        }
+
+        Ok(node)
+    }
+
+    fn parse_method_call(&mut self, object: ASTNode, start: Span) -> Result<ASTNode, String> {
+        // parse the method name
+        let method_name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            Some(token) => return Err(format!("Expected method name, found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
         };
 
-        // check for method call
-        while let Some(Token::Period) = self.tokens.peek() {
-            // consume the period
-            self.tokens.next();
+        // without parenthesis this is a field access
+        match self.peek() {
+            Some(Token::LeftParen) => {
+                self.next();
+            }
+            _ => {
+                return Ok(ASTNode::new(
+                    ASTNodeKind::FieldAccess {
+                        object: Box::new(object),
+                        field: method_name,
+                    },
+                    self.span_from(start),
+                ))
+            }
+        };
+
+        // parse arguments
+        let mut args = Vec::new();
+
+        // handle no arguments
+        if let Some(Token::RightParen) = self.peek() {
+            // consume the right parenthesis
+            self.next();
+        } else {
+            // parse first argument
+            args.push(self.parse_expression()?);
 
-            // parse the method name
-            let method_name = match self.tokens.next() {
-                Some(Token::Identifier(name)) => name,
-                Some(token) => return Err(format!("Expected method name, found {:?}", token)),
+            // handle remaining arguments
+            while let Some(Token::Comma) = self.peek() {
+                // consume the comma
+                self.next();
+                args.push(self.parse_expression()?);
+            }
+
+            // check for closing parenthesis
+            match self.next() {
+                Some(Token::RightParen) => (),
+                Some(token) => return Err(format!("Expected ')', found {:?}", token)),
                 _ => return Err("Unexpected end of input".to_string()),
-            };
+            }
+        }
+
+        Ok(ASTNode::new(
+            ASTNodeKind::MethodCall {
+                object: Box::new(object),
+                method: method_name,
+                arguments: args,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    fn parse_array_literal(&mut self) -> Result<ASTNodeKind, String> {
+        let mut elements = Vec::new();
+
+        // empty array literal
+        if let Some(Token::RightBracket) = self.peek() {
+            self.next();
+            return Ok(ASTNodeKind::ArrayLiteral(elements));
+        }
 
-            // check for opening parenthesis
-            match self.tokens.next() {
-                Some(Token::LeftParen) => (),
-                Some(token) => return Err(format!("Expected '(', found {:?}", token)),
+        elements.push(self.parse_expression()?);
+
+        while let Some(Token::Comma) = self.peek() {
+            self.next();
+            elements.push(self.parse_expression()?);
+        }
+
+        match self.next() {
+            Some(Token::RightBracket) => Ok(ASTNodeKind::ArrayLiteral(elements)),
+            Some(token) => Err(format!("Expected ']', found {:?}", token)),
+            _ => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    fn parse_struct_literal(&mut self, name: String) -> Result<ASTNodeKind, String> {
+        let mut fields = Vec::new();
+
+        while let Some(token) = self.peek() {
+            if matches!(token, Token::RightBrace) {
+                break;
+            }
+
+            let field = match self.next() {
+                Some(Token::Identifier(field)) => field,
+                Some(token) => return Err(format!("Expected field name, found {:?}", token)),
                 _ => return Err("Unexpected end of input".to_string()),
             };
 
-            // parse arguments
-            let mut args = Vec::new();
+            match self.next() {
+                Some(Token::Colon) => (),
+                Some(token) => return Err(format!("Expected ':', found {:?}", token)),
+                _ => return Err("Unexpected end of input".to_string()),
+            };
 
-            // handle no arguments
-            if let Some(Token::RightParen) = self.tokens.peek() {
-                // consume the right parenthesis
-                self.tokens.next();
-            } else {
-                // parse first argument
-                args.push(self.parse_expression()?);
+            fields.push((field, self.parse_expression()?));
 
-                // handle remaining arguments
-                while let Some(Token::Comma) = self.tokens.peek() {
-                    // consume the comma
-                    self.tokens.next();
-                    args.push(self.parse_expression()?);
+            // fields are separated by commas, a trailing comma is allowed
+            match self.peek() {
+                Some(Token::Comma) => {
+                    self.next();
                 }
+                Some(Token::RightBrace) => (),
+                Some(token) => return Err(format!("Expected ',' or '}}', found {:?}", token)),
+                _ => return Err("Unexpected end of input".to_string()),
+            }
+        }
+
+        match self.next() {
+            Some(Token::RightBrace) => Ok(ASTNodeKind::StructLiteral { name, fields }),
+            Some(token) => Err(format!("Expected '}}', found {:?}", token)),
+            _ => Err("Unexpected end of input".to_string()),
+        }
+    }
 
-                // check for closing parenthesis
-                match self.tokens.next() {
-                    Some(Token::RightParen) => (),
-                    Some(token) => return Err(format!("Expected ')', found {:?}", token)),
-                    _ => return Err("Unexpected end of input".to_string()),
+    fn is_qualified_struct_literal(&self, module: &str) -> bool {
+        self.module_names.contains(module)
+            && matches!(self.peek_nth(1), Some(Token::Identifier(_)))
+            && matches!(self.peek_nth(2), Some(Token::LeftBrace))
+    }
+
+    fn parse_type(&mut self) -> Result<Type, String> {
+        let base_type = self.parse_base_type()?;
+
+        match self.peek() {
+            Some(Token::Question) => {
+                self.next();
+                Ok(Type::Optional(Box::new(base_type)))
+            }
+            _ => Ok(base_type),
+        }
+    }
+
+    // a type without the `?` that makes it optional
+    fn parse_base_type(&mut self) -> Result<Type, String> {
+        match self.next() {
+            Some(Token::Type(t)) => Ok(t),
+            // a struct of an imported module, `module.Point`
+            Some(Token::Identifier(module))
+                if self.module_names.contains(&module)
+                    && matches!(self.peek(), Some(Token::Period)) =>
+            {
+                self.next();
+                match self.next() {
+                    Some(Token::Identifier(name)) => {
+                        Ok(Type::Struct(format!("{}.{}", module, name)))
+                    }
+                    Some(token) => Err(format!("Expected struct name, found {:?}", token)),
+                    _ => Err("Unexpected end of input".to_string()),
+                }
+            }
+            Some(Token::Identifier(name)) => Ok(Type::Struct(name)),
+            Some(Token::LeftBracket) => {
+                let element_type = self.parse_type()?;
+                match self.next() {
+                    Some(Token::RightBracket) => Ok(Type::Array(Box::new(element_type))),
+                    Some(token) => Err(format!("Expected ']', found {:?}", token)),
+                    _ => Err("Unexpected end of input".to_string()),
                 }
             }
+            Some(Token::Keyword(Keyword::Fun)) => self.parse_function_type(),
+            Some(token) => Err(format!("Expected type, found {:?}", token)),
+            _ => Err("Unexpected end of input".to_string()),
+        }
+    }
 
-            node = Ok(ASTNode::MethodCall {
-                object: Box::new(node?),
-                method: method_name,
-                arguments: args,
-            });
+    // `fun(num, str) -> bool`, after the `fun`
+    fn parse_function_type(&mut self) -> Result<Type, String> {
+        match self.next() {
+            Some(Token::LeftParen) => (),
+            Some(token) => return Err(format!("Expected '(', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let mut parameters = Vec::new();
+        if let Some(Token::RightParen) = self.peek() {
+            self.next();
+        } else {
+            parameters.push(self.parse_type()?);
+            while let Some(Token::Comma) = self.peek() {
+                self.next();
+                parameters.push(self.parse_type()?);
+            }
+
+            match self.next() {
+                Some(Token::RightParen) => (),
+                Some(token) => return Err(format!("Expected ')', found {:?}", token)),
+                _ => return Err("Unexpected end of input".to_string()),
+            }
         }
 
-        node
+        let return_type = match self.peek() {
+            Some(Token::Arrow) => {
+                self.next();
+                self.parse_type()?
+            }
+            _ => Type::Void,
+        };
+
+        Ok(Type::Function(parameters, Box::new(return_type)))
     }
 
-    fn parse_parameter(&mut self) -> Result<Parameter, String> {
-        match (self.tokens.next(), self.tokens.next()) {
-            (Some(Token::Type(param_type)), Some(Token::Identifier(name))) => {
-                let mut optional = false;
-                if let Some(Token::Operator(Operator::Multiply)) = self.tokens.peek() {
-                    self.tokens.next();
-                    optional = true;
+    // the number of tokens of the type starting `n` tokens ahead, three for
+    // `module.Point`, `None` if no type starts there
+    fn type_length(&self, n: usize) -> Option<usize> {
+        let length = self.base_type_length(n)?;
+
+        match self.peek_nth(n + length) {
+            Some(Token::Question) => Some(length + 1),
+            _ => Some(length),
+        }
+    }
+
+    fn base_type_length(&self, n: usize) -> Option<usize> {
+        match (self.peek_nth(n), self.peek_nth(n + 1)) {
+            (Some(Token::Type(_)), _) => Some(1),
+            (Some(Token::Identifier(name)), _) if self.struct_names.contains(name) => Some(1),
+            (Some(Token::Identifier(name)), Some(Token::Period))
+                if self.module_names.contains(name)
+                    && matches!(self.peek_nth(n + 2), Some(Token::Identifier(_))) =>
+            {
+                Some(3)
+            }
+            (Some(Token::LeftBracket), _) => {
+                let length = self.type_length(n + 1)?;
+                matches!(self.peek_nth(n + 1 + length), Some(Token::RightBracket))
+                    .then_some(length + 2)
+            }
+            (Some(Token::Keyword(Keyword::Fun)), Some(Token::LeftParen)) => {
+                let mut end = n + 2;
+                if !matches!(self.peek_nth(end), Some(Token::RightParen)) {
+                    loop {
+                        end += self.type_length(end)?;
+                        match self.peek_nth(end) {
+                            Some(Token::Comma) => end += 1,
+                            Some(Token::RightParen) => break,
+                            _ => return None,
+                        }
+                    }
+                }
+
+                // past the `)`
+                end += 1;
+                if let Some(Token::Arrow) = self.peek_nth(end) {
+                    end += 1 + self.type_length(end + 1)?;
                 }
+                Some(end - n)
+            }
+            _ => None,
+        }
+    }
+
+    // checks if the upcoming tokens are a type followed by a name (`num x`,
+    // `[num] xs`, `Point? p`, `fun(num) -> num f`...), which starts a
+    // variable declaration
+    fn is_declaration_ahead(&self) -> bool {
+        self.type_length(0)
+            .is_some_and(|length| matches!(self.peek_nth(length), Some(Token::Identifier(_))))
+    }
+
+    fn parse_parameter(&mut self) -> Result<Parameter, String> {
+        let param_type = self.parse_type()?;
+
+        match self.next() {
+            Some(Token::Identifier(name)) => {
+                let default = match self.peek() {
+                    Some(Token::Operator(Operator::AssignEquals)) => {
+                        self.next();
+                        Some(self.parse_expression()?)
+                    }
+                    _ => None,
+                };
 
                 Ok(Parameter {
                     name,
                     param_type,
-                    optional,
+                    default,
                 })
             }
-            (Some(token1), Some(token2)) => Err(format!(
-                "Expected type and identifier, found {:?} {:?}",
-                token1, token2
-            )),
+            Some(token) => Err(format!("Expected parameter name, found {:?}", token)),
             _ => Err("Unexpected end of input".to_string()),
         }
     }
@@ -187,19 +758,19 @@ impl Parser {
         let mut parameters = Vec::new();
 
         // empty parameter list (no parameters)
-        if let Some(Token::RightParen) = self.tokens.peek() {
-            self.tokens.next();
+        if let Some(Token::RightParen) = self.peek() {
+            self.next();
             return Ok(parameters);
         };
 
         parameters.push(self.parse_parameter()?);
 
-        while let Some(Token::Comma) = self.tokens.peek() {
-            self.tokens.next();
+        while let Some(Token::Comma) = self.peek() {
+            self.next();
             parameters.push(self.parse_parameter()?);
         }
 
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::RightParen) => Ok(parameters),
             Some(token) => Err(format!("Expected ')', found {:?}", token)),
             _ => Err("Unexpected end of input".to_string()),
@@ -209,11 +780,13 @@ impl Parser {
     fn parse_function_body(&mut self) -> Result<Vec<ASTNode>, String> {
         let mut statements = Vec::new();
 
-        while let Some(token) = self.tokens.peek() {
+        while let Some(token) = self.peek() {
             if matches!(token, Token::RightBrace) {
                 break;
             }
-            statements.push(self.parse_statement()?);
+            if let Some(statement) = self.parse_statement_or_recover() {
+                statements.push(statement);
+            }
         }
 
         Ok(statements)
@@ -222,30 +795,57 @@ impl Parser {
     fn parse_block(&mut self) -> Result<Vec<ASTNode>, String> {
         let mut statements = Vec::new();
 
-        while let Some(token) = self.tokens.peek() {
+        self.block_depth += 1;
+        while let Some(token) = self.peek() {
             if matches!(token, Token::RightBrace) {
                 break;
             }
-            statements.push(self.parse_statement()?);
+            if let Some(statement) = self.parse_statement_or_recover() {
+                statements.push(statement);
+            }
         }
+        self.block_depth -= 1;
 
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::RightBrace) => Ok(statements),
-            Some(token) => return Err(format!("Expected '}}', found {:?}", token)),
-            _ => return Err("Unexpected end of input".to_string()),
+            Some(token) => Err(format!("Expected '}}', found {:?}", token)),
+            _ => Err("Unexpected end of input".to_string()),
         }
     }
 
-    fn parse_function_declaration(&mut self) -> Result<ASTNode, String> {
+    fn parse_function_declaration(&mut self) -> Result<ASTNodeKind, String> {
         // parse function name
-        let name = match self.tokens.next() {
+        let name = match self.next() {
             Some(Token::Identifier(name)) => name,
             Some(token) => return Err(format!("Expected function name, found {:?}", token)),
             _ => return Err("Expected function name, found end of input".to_string()),
         };
 
+        let (parameters, return_type, body) = self.parse_function_signature_and_body()?;
+
+        Ok(ASTNodeKind::FunctionDeclaration {
+            name,
+            parameters,
+            return_type,
+            body,
+        })
+    }
+
+    // `fun(num x) -> num { ... }` in an expression, after the `fun`
+    fn parse_function_literal(&mut self) -> Result<ASTNodeKind, String> {
+        let (parameters, return_type, body) = self.parse_function_signature_and_body()?;
+
+        Ok(ASTNodeKind::FunctionLiteral {
+            parameters,
+            return_type,
+            body,
+        })
+    }
+
+    // everything after the name of a function, or after `fun` for a literal
+    fn parse_function_signature_and_body(&mut self) -> Result<FunctionParts, String> {
         // parse opening parenthesis
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftParen) => (),
             Some(token) => return Err(format!("Expected '(', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -255,19 +855,15 @@ impl Parser {
         let parameters = self.parse_parameter_list()?;
 
         // parse return type
-        let return_type = if let Some(Token::Arrow) = self.tokens.peek() {
-            self.tokens.next();
-            match self.tokens.next() {
-                Some(Token::Type(return_type)) => Some(return_type),
-                Some(token) => return Err(format!("Expected return type, found {:?}", token)),
-                _ => return Err("Unexpected end of input".to_string()),
-            }
+        let return_type = if let Some(Token::Arrow) = self.peek() {
+            self.next();
+            Some(self.parse_type()?)
         } else {
             None
         };
 
         // parse opening brace
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftBrace) => (),
             Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -276,46 +872,47 @@ impl Parser {
         let body = self.parse_function_body()?;
 
         // parse closing brace
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::RightBrace) => (),
             Some(token) => return Err(format!("Expected '}}', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
         };
 
-        Ok(ASTNode::FunctionDeclaration {
-            name,
-            parameters,
-            return_type,
-            body,
-        })
+        Ok((parameters, return_type, body))
+    }
+
+    fn parse_function_call(&mut self, name: String) -> Result<ASTNodeKind, String> {
+        let arguments = self.parse_arguments()?;
+        Ok(ASTNodeKind::FunctionCall { name, arguments })
     }
 
-    fn parse_function_call(&mut self, name: String) -> Result<ASTNode, String> {
+    // the arguments of a call, after the `(`
+    fn parse_arguments(&mut self) -> Result<Vec<ASTNode>, String> {
         let mut arguments = Vec::new();
 
         // empty argument list (no arguments)
-        if let Some(Token::RightParen) = self.tokens.peek() {
-            self.tokens.next();
-            return Ok(ASTNode::FunctionCall { name, arguments });
+        if let Some(Token::RightParen) = self.peek() {
+            self.next();
+            return Ok(arguments);
         };
 
         arguments.push(self.parse_expression()?);
 
-        while let Some(Token::Comma) = self.tokens.peek() {
-            self.tokens.next();
+        while let Some(Token::Comma) = self.peek() {
+            self.next();
             arguments.push(self.parse_expression()?);
         }
 
-        match self.tokens.next() {
-            Some(Token::RightParen) => return Ok(ASTNode::FunctionCall { name, arguments }),
+        match self.next() {
+            Some(Token::RightParen) => Ok(arguments),
             Some(token) => Err(format!("Expected ')', found {:?}", token)),
             _ => Err("Unexpected end of input".to_string()),
         }
     }
 
-    fn parse_if_statement(&mut self) -> Result<ASTNode, String> {
+    fn parse_if_statement(&mut self) -> Result<ASTNodeKind, String> {
         // parse condition
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftParen) => (),
             Some(token) => return Err(format!("Expected '(' after 'if', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -323,14 +920,14 @@ impl Parser {
 
         let condition = self.parse_expression()?;
 
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::RightParen) => (),
             Some(token) => return Err(format!("Expected ')', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
         };
 
         // parse then body
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftBrace) => (),
             Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -338,10 +935,22 @@ impl Parser {
 
         let then_body = self.parse_block()?;
 
-        let else_body = if let Some(Token::Keyword(Keyword::Else)) = self.tokens.peek() {
-            self.tokens.next(); // consume the keyword (else)
+        let else_body = if let Some(Token::Keyword(Keyword::Else)) = self.peek() {
+            self.next(); // consume the keyword (else)
+
+            // `else if` is an else body holding just the next if statement
+            if let Some(Token::Keyword(Keyword::If)) = self.peek() {
+                let start = self.peek_span();
+                self.next();
+                let kind = self.parse_if_statement()?;
+                return Ok(ASTNodeKind::IfStatement {
+                    condition: Box::new(condition),
+                    then_body,
+                    else_body: Some(vec![ASTNode::new(kind, self.span_from(start))]),
+                });
+            }
 
-            match self.tokens.next() {
+            match self.next() {
                 Some(Token::LeftBrace) => (),
                 Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
                 _ => return Err("Unexpected end of input".to_string()),
@@ -352,16 +961,111 @@ impl Parser {
             None
         };
 
-        Ok(ASTNode::IfStatement {
+        Ok(ASTNodeKind::IfStatement {
             condition: Box::new(condition),
             then_body,
             else_body,
         })
     }
 
-    fn parse_while_statement(&mut self) -> Result<ASTNode, String> {
+    fn parse_match_statement(&mut self) -> Result<ASTNodeKind, String> {
+        match self.next() {
+            Some(Token::LeftParen) => (),
+            Some(token) => return Err(format!("Expected '(' after 'match', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let subject = self.parse_expression()?;
+
+        match self.next() {
+            Some(Token::RightParen) => (),
+            Some(token) => return Err(format!("Expected ')', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        match self.next() {
+            Some(Token::LeftBrace) => (),
+            Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let mut arms = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::RightBrace) => {
+                    self.next();
+                    break;
+                }
+                None => return Err("Unexpected end of input".to_string()),
+                _ => (),
+            }
+
+            let start = self.peek_span();
+            let pattern = self.parse_match_pattern()?;
+            let span = self.span_from(start);
+
+            match self.next() {
+                Some(Token::FatArrow) => (),
+                Some(token) => return Err(format!("Expected '=>', found {:?}", token)),
+                _ => return Err("Unexpected end of input".to_string()),
+            };
+
+            // an arm runs either a block or a single statement
+            let body = match self.peek() {
+                Some(Token::LeftBrace) => {
+                    self.next();
+                    self.parse_block()?
+                }
+                _ => vec![self.parse_statement()?],
+            };
+
+            // arms may be separated by commas
+            if let Some(Token::Comma) = self.peek() {
+                self.next();
+            }
+
+            arms.push(MatchArm {
+                pattern,
+                body,
+                span,
+            });
+        }
+
+        Ok(ASTNodeKind::MatchStatement {
+            subject: Box::new(subject),
+            arms,
+        })
+    }
+
+    // patterns are literals so duplicate arms can be found before running
+    fn parse_match_pattern(&mut self) -> Result<Option<ASTNode>, String> {
+        let start = self.peek_span();
+
+        let kind = match self.next() {
+            Some(Token::Identifier(name)) if name == "_" => return Ok(None),
+            Some(Token::Number(value)) => ASTNodeKind::NumberLiteral(value),
+            Some(Token::Operator(Operator::Minus)) => match self.next() {
+                Some(Token::Number(value)) => ASTNodeKind::NumberLiteral(-value),
+                Some(token) => return Err(format!("Expected number after '-', found {:?}", token)),
+                _ => return Err("Unexpected end of input".to_string()),
+            },
+            Some(Token::String(value)) => ASTNodeKind::StringLiteral(value),
+            Some(Token::Boolean(value)) => ASTNodeKind::BooleanLiteral(value),
+            Some(token) => {
+                return Err(format!(
+                    "Expected a number, string, boolean or '_' in match arm, found {:?}",
+                    token
+                ))
+            }
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        Ok(Some(ASTNode::new(kind, self.span_from(start))))
+    }
+
+    fn parse_while_statement(&mut self) -> Result<ASTNodeKind, String> {
         // parse condition
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftParen) => (),
             Some(token) => return Err(format!("Expected '(' after 'while', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -369,14 +1073,14 @@ impl Parser {
 
         let condition = self.parse_expression()?;
 
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::RightParen) => (),
             Some(token) => return Err(format!("Expected ')', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
         };
 
         // parse while body
-        match self.tokens.next() {
+        match self.next() {
             Some(Token::LeftBrace) => (),
             Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
             _ => return Err("Unexpected end of input".to_string()),
@@ -384,18 +1088,205 @@ impl Parser {
 
         let body = self.parse_block()?;
 
-        Ok(ASTNode::WhileStatement {
+        Ok(ASTNodeKind::WhileStatement {
             condition: Box::new(condition),
             body,
         })
     }
 
-    fn parse_variable_declaration(&mut self, var_type: Type) -> Result<ASTNode, String> {
-        match self.tokens.next() {
-            Some(Token::Identifier(name)) => match self.tokens.next() {
+    fn parse_struct_declaration(&mut self) -> Result<ASTNodeKind, String> {
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            Some(token) => return Err(format!("Expected struct name, found {:?}", token)),
+            _ => return Err("Expected struct name, found end of input".to_string()),
+        };
+
+        match self.next() {
+            Some(Token::LeftBrace) => (),
+            Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let mut fields = Vec::new();
+        let mut methods = Vec::new();
+
+        while let Some(token) = self.peek() {
+            match token {
+                Token::RightBrace => break,
+                Token::Keyword(Keyword::Fun) => {
+                    let start = self.peek_span();
+                    self.next();
+                    let method = self.parse_function_declaration()?;
+                    methods.push(ASTNode::new(method, self.span_from(start)));
+                }
+                _ => {
+                    let start = self.peek_span();
+                    let field_type = self.parse_type()?;
+
+                    let field_name = match self.next() {
+                        Some(Token::Identifier(name)) => name,
+                        Some(token) => {
+                            return Err(format!("Expected field name, found {:?}", token))
+                        }
+                        _ => return Err("Unexpected end of input".to_string()),
+                    };
+
+                    fields.push(StructField {
+                        name: field_name,
+                        field_type,
+                        span: self.span_from(start),
+                    });
+
+                    // fields are separated by commas
+                    if let Some(Token::Comma) = self.peek() {
+                        self.next();
+                    }
+                }
+            }
+        }
+
+        match self.next() {
+            Some(Token::RightBrace) => Ok(ASTNodeKind::StructDeclaration {
+                name,
+                fields,
+                methods,
+            }),
+            Some(token) => Err(format!("Expected '}}', found {:?}", token)),
+            _ => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    fn parse_for_statement(&mut self) -> Result<ASTNodeKind, String> {
+        match self.next() {
+            Some(Token::LeftParen) => (),
+            Some(token) => return Err(format!("Expected '(' after 'for', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        // parse loop variable
+        let var_type = self.parse_type()?;
+
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            Some(token) => return Err(format!("Expected identifier, found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        match self.next() {
+            Some(Token::Keyword(Keyword::In)) => (),
+            Some(token) => return Err(format!("Expected 'in', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let iterable = self.parse_expression()?;
+        let iterable = match self.peek() {
+            Some(Token::DotDot) => self.parse_range(iterable)?,
+            _ => iterable,
+        };
+
+        match self.next() {
+            Some(Token::RightParen) => (),
+            Some(token) => return Err(format!("Expected ')', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        // parse for body
+        match self.next() {
+            Some(Token::LeftBrace) => (),
+            Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let body = self.parse_block()?;
+
+        Ok(ASTNodeKind::ForStatement {
+            var_type,
+            name,
+            iterable: Box::new(iterable),
+            body,
+        })
+    }
+
+    // parses the rest of `start..end` with an optional `step n`, `step` is
+    // only a keyword here so it can still be used as a name elsewhere
+    fn parse_range(&mut self, start: ASTNode) -> Result<ASTNode, String> {
+        self.next(); // '..'
+        let end = self.parse_expression()?;
+
+        let step = match self.peek() {
+            Some(Token::Identifier(name)) if name == "step" => {
+                self.next();
+                Some(Box::new(self.parse_expression()?))
+            }
+            _ => None,
+        };
+
+        let span = self.span_from(start.span);
+        Ok(ASTNode::new(
+            ASTNodeKind::Range {
+                start: Box::new(start),
+                end: Box::new(end),
+                step,
+            },
+            span,
+        ))
+    }
+
+    fn parse_import(&mut self) -> Result<ASTNodeKind, String> {
+        // `from` is only a keyword here so it can still be used as a name elsewhere
+        let name = match (self.peek(), self.peek_nth(1)) {
+            (Some(Token::Identifier(name)), Some(Token::Identifier(from))) if from == "from" => {
+                let name = name.clone();
+                self.next();
+                self.next();
+                Some(name)
+            }
+            _ => None,
+        };
+
+        let path = match self.next() {
+            Some(Token::String(path)) => path,
+            Some(token) => return Err(format!("Expected module path, found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let name = match name.or_else(|| module_name(&path)) {
+            Some(name) => name,
+            None => {
+                return Err(format!(
+                    "Module '{}' needs a name to be imported, use 'import name from \"{}\"'",
+                    path, path
+                ))
+            }
+        };
+
+        Ok(ASTNodeKind::Import { path, name })
+    }
+
+    fn parse_test(&mut self) -> Result<ASTNodeKind, String> {
+        let name = match self.next() {
+            Some(Token::String(name)) => name,
+            Some(token) => return Err(format!("Expected test name, found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        match self.next() {
+            Some(Token::LeftBrace) => (),
+            Some(token) => return Err(format!("Expected '{{', found {:?}", token)),
+            _ => return Err("Unexpected end of input".to_string()),
+        };
+
+        let body = self.parse_block()?;
+
+        Ok(ASTNodeKind::Test { name, body })
+    }
+
+    fn parse_variable_declaration(&mut self, var_type: Type) -> Result<ASTNodeKind, String> {
+        match self.next() {
+            Some(Token::Identifier(name)) => match self.next() {
                 Some(Token::Operator(Operator::AssignEquals)) => {
                     let value = self.parse_expression()?;
-                    Ok(ASTNode::VariableDeclaration {
+                    Ok(ASTNodeKind::VariableDeclaration {
                         name,
                         var_type,
                         value: Box::new(value),
@@ -410,34 +1301,92 @@ impl Parser {
     }
 
     fn parse_statement(&mut self) -> Result<ASTNode, String> {
-        match self.tokens.peek() {
+        let start = self.peek_span();
+
+        let kind = match self.peek() {
+            // `fun(num) -> num f = ...` declares a variable holding a function
+            Some(Token::Keyword(Keyword::Fun)) if self.is_declaration_ahead() => {
+                let var_type = self.parse_type()?;
+                self.parse_variable_declaration(var_type)?
+            }
             Some(Token::Keyword(Keyword::Fun)) => {
-                self.tokens.next();
-                self.parse_function_declaration()
+                self.next();
+                self.parse_function_declaration()?
             }
             Some(Token::Keyword(Keyword::If)) => {
-                self.tokens.next();
-                self.parse_if_statement()
+                self.next();
+                self.parse_if_statement()?
             }
             Some(Token::Keyword(Keyword::While)) => {
-                self.tokens.next();
-                self.parse_while_statement()
+                self.next();
+                self.parse_while_statement()?
+            }
+            Some(Token::Keyword(Keyword::Match)) => {
+                self.next();
+                self.parse_match_statement()?
             }
-            Some(Token::Type(t)) => {
-                let var_type = t.clone();
-                self.tokens.next();
-                self.parse_variable_declaration(var_type)
+            Some(Token::Keyword(Keyword::For)) => {
+                self.next();
+                self.parse_for_statement()?
+            }
+            Some(Token::Keyword(Keyword::Struct)) => {
+                self.next();
+                self.parse_struct_declaration()?
+            }
+            Some(Token::Type(_)) => {
+                let var_type = self.parse_type()?;
+                self.parse_variable_declaration(var_type)?
+            }
+            Some(Token::LeftBracket) | Some(Token::Identifier(_))
+                if self.is_declaration_ahead() =>
+            {
+                let var_type = self.parse_type()?;
+                self.parse_variable_declaration(var_type)?
             }
             Some(Token::Keyword(Keyword::Return)) => {
-                self.tokens.next();
-                let expression = self.parse_expression()?;
-                Ok(ASTNode::ReturnStatement(Box::new(expression)))
+                self.next();
+                // semicolons are not tokens, a bare `return` is the last
+                // statement of its block or line
+                let bare = match self.tokens.get(self.position) {
+                    Some(next) => {
+                        next.token == Token::RightBrace || next.span.line > self.last_span.line
+                    }
+                    None => true,
+                };
+                match bare {
+                    true => ASTNodeKind::ReturnStatement(None),
+                    false => ASTNodeKind::ReturnStatement(Some(Box::new(self.parse_expression()?))),
+                }
+            }
+            Some(Token::Keyword(Keyword::Break)) => {
+                self.next();
+                ASTNodeKind::Break
+            }
+            Some(Token::Keyword(Keyword::Continue)) => {
+                self.next();
+                ASTNodeKind::Continue
+            }
+            Some(Token::Keyword(Keyword::Import)) => {
+                self.next();
+                self.parse_import()?
+            }
+            // `test` is only a keyword before a name so it can still be used as one
+            Some(Token::Identifier(name))
+                if name == "test" && matches!(self.peek_nth(1), Some(Token::String(_))) =>
+            {
+                self.next();
+                if self.block_depth > 0 {
+                    return Err("Tests are only allowed at the top level".to_string());
+                }
+                self.parse_test()?
             }
             _ => {
                 let expression = self.parse_expression()?;
-                Ok(ASTNode::Statement(Box::new(expression)))
+                ASTNodeKind::Statement(Box::new(expression))
             }
-        }
+        };
+
+        Ok(ASTNode::new(kind, self.span_from(start)))
     }
 
     fn parse_expression_with_precedence(&mut self, prec: usize) -> Result<ASTNode, String> {
@@ -452,6 +1401,8 @@ impl Parser {
                 Operator::PowAssign,
                 Operator::ModAssign,
             ],
+            // `value ?? default`
+            vec![Operator::Coalesce],
             // logical operators
             vec![Operator::LogicalOr],
             vec![Operator::LogicalAnd],
@@ -472,42 +1423,48 @@ impl Parser {
             vec![Operator::Power],
         ];
 
-        let right_associative_operators = vec![Operator::Power];
+        let right_associative_operators = [Operator::Power, Operator::Coalesce];
 
         // highest precedence (primary expressions)
         if prec >= precedence_order.len() {
             // check for unary minus before parsing primary
-            if let Some(Token::Operator(Operator::Minus)) = self.tokens.peek() {
-                self.tokens.next(); // consume the minus
+            if let Some(Token::Operator(Operator::Minus)) = self.peek() {
+                let start = self.peek_span();
+                self.next(); // consume the minus
                 let operand = self.parse_expression_with_precedence(precedence_order.len() - 1)?;
-                return Ok(ASTNode::UnaryOperation {
-                    op: Operator::UnaryMinus,
-                    operand: Box::new(operand),
-                });
+                return Ok(ASTNode::new(
+                    ASTNodeKind::UnaryOperation {
+                        op: Operator::UnaryMinus,
+                        operand: Box::new(operand),
+                    },
+                    self.span_from(start),
+                ));
             }
             return self.parse_primary();
         }
 
         let mut left = self.parse_expression_with_precedence(prec + 1)?;
 
-        while let Some(Token::Operator(op)) = self.tokens.peek() {
+        while let Some(Token::Operator(op)) = self.peek() {
             if precedence_order[prec].contains(op) {
                 let op = op.clone();
-                self.tokens.next();
+                self.next();
 
-                let right = if prec == 0 {
-                    self.parse_expression_with_precedence(prec)?
-                } else if right_associative_operators.contains(&op) {
+                let right = if prec == 0 || right_associative_operators.contains(&op) {
                     self.parse_expression_with_precedence(prec)?
                 } else {
                     self.parse_expression_with_precedence(prec + 1)?
                 };
 
-                left = ASTNode::BinaryOperation {
-                    left: Box::new(left),
-                    op,
-                    right: Box::new(right),
-                };
+                let span = left.span.merge(right.span);
+                left = ASTNode::new(
+                    ASTNodeKind::BinaryOperation {
+                        left: Box::new(left),
+                        op,
+                        right: Box::new(right),
+                    },
+                    span,
+                );
             } else {
                 break;
             }
@@ -520,11 +1477,111 @@ impl Parser {
         self.parse_expression_with_precedence(0)
     }
 
-    pub fn parse_program(&mut self) -> Result<ASTNode, String> {
+    // parses a statement, on error the error is recorded and the parser skips
+    // ahead to the next statement so more errors can be reported
+    fn parse_statement_or_recover(&mut self) -> Option<ASTNode> {
+        let position = self.position;
+
+        match self.parse_statement() {
+            Ok(statement) => Some(statement),
+            Err(message) => {
+                self.errors.push(Diagnostic::new(
+                    DiagnosticKind::Parser,
+                    message,
+                    Some(self.last_span),
+                ));
+
+                // a closing brace or keyword that was consumed while failing belongs
+                // to the enclosing block or the next statement, otherwise always make
+                // progress
+                if self.position > position + 1
+                    && is_statement_boundary(&self.tokens[self.position - 1].token)
+                {
+                    self.position -= 1;
+                } else if self.position == position {
+                    self.next();
+                }
+
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    // skips tokens until something that starts a statement, or the brace that
+    // closes the current block
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+
+        while let Some(token) = self.peek() {
+            match token {
+                token if depth == 0 && is_statement_boundary(token) => return,
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => depth -= 1,
+                // types also appear inside statements, only trust them at the start of a line
+                Token::Type(_) if depth == 0 && self.starts_line() => return,
+                _ => (),
+            }
+            self.next();
+        }
+    }
+
+    fn starts_line(&self) -> bool {
+        match (
+            self.tokens.get(self.position),
+            self.position
+                .checked_sub(1)
+                .and_then(|i| self.tokens.get(i)),
+        ) {
+            (Some(current), Some(previous)) => current.span.line > previous.span.line,
+            _ => true,
+        }
+    }
+
+    pub fn parse_program(&mut self) -> Result<ASTNode, Vec<Diagnostic>> {
+        let start = self.peek_span();
         let mut statements = Vec::new();
-        while self.tokens.peek().is_some() {
-            statements.push(self.parse_statement()?);
+
+        while self.peek().is_some() {
+            if let Some(statement) = self.parse_statement_or_recover() {
+                statements.push(statement);
+            }
+
+            // a brace left over by recovery has no block to close at the top level
+            if !self.errors.is_empty() {
+                if let Some(Token::RightBrace) = self.peek() {
+                    self.next();
+                }
+            }
+        }
+
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
         }
-        Ok(ASTNode::Program(statements))
+
+        Ok(ASTNode::new(
+            ASTNodeKind::Program(statements),
+            self.span_from(start),
+        ))
     }
 }
+
+// tokens that close a block or can only start a statement
+fn is_statement_boundary(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::RightBrace
+            | Token::Keyword(
+                Keyword::Fun
+                    | Keyword::If
+                    | Keyword::While
+                    | Keyword::Match
+                    | Keyword::For
+                    | Keyword::Struct
+                    | Keyword::Return
+                    | Keyword::Break
+                    | Keyword::Continue
+                    | Keyword::Import
+            )
+    )
+}
@@ -0,0 +1,161 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use boo_lang::{Diagnostic, Engine, SourceMap, Value};
+
+const SOURCE_NAME: &str = "<repl>";
+
+const HELP: &str = "\
+Commands:
+  :type <expr>      show the type of an expression
+  :bytecode <expr>  show the bytecode of an expression
+  :reset            forget every variable and function
+  :help             show this message
+  :quit             exit the REPL";
+
+pub struct Repl {
+    engine: Engine,
+    import_paths: Vec<PathBuf>, // kept for :reset
+}
+
+impl Repl {
+    pub fn new(import_paths: Vec<PathBuf>) -> Self {
+        let mut engine = Engine::new();
+        for path in &import_paths {
+            engine.add_import_path(path);
+        }
+
+        Self {
+            engine,
+            import_paths,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("Boo REPL, type :help for a list of commands");
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        loop {
+            let mut input = String::new();
+
+            // keep reading lines until every block is closed
+            loop {
+                print!("{}", if input.is_empty() { ">> " } else { ".. " });
+                io::stdout().flush().ok();
+
+                match lines.next() {
+                    Some(Ok(line)) => {
+                        input.push_str(&line);
+                        input.push('\n');
+                    }
+                    _ => {
+                        println!();
+                        return;
+                    }
+                }
+
+                if nesting_depth(&input) <= 0 {
+                    break;
+                }
+            }
+
+            let input = input.trim();
+
+            if input.is_empty() {
+                continue;
+            }
+
+            match input.split_once(char::is_whitespace).unwrap_or((input, "")) {
+                (":quit", _) | (":exit", _) => return,
+                (":help", _) => println!("{}", HELP),
+                (":reset", _) => {
+                    *self = Repl::new(self.import_paths.clone());
+                    println!("Environment reset");
+                }
+                (":type", expr) => self.show_type(expr.trim()),
+                (":bytecode", expr) => self.show_bytecode(expr.trim()),
+                (command, _) if command.starts_with(':') => {
+                    println!(
+                        "Unknown command '{}', type :help for a list of commands",
+                        command
+                    )
+                }
+                _ => self.eval(input),
+            }
+        }
+    }
+
+    fn eval(&mut self, source: &str) {
+        let result = self.engine.eval(source);
+        report(&self.engine.take_warnings(), source, self.engine.sources());
+
+        match result {
+            Ok(Some(Value::String(s))) => println!("{:?}", s),
+            Ok(Some(Value::Void) | None) => (),
+            Ok(Some(value)) => println!("{}", value),
+            Err(error) => report(&error.diagnostics, source, self.engine.sources()),
+        }
+    }
+
+    fn show_type(&mut self, source: &str) {
+        match self.engine.type_of(source) {
+            Ok(expr_type) => println!("{:?}", expr_type),
+            Err(error) => report(&error.diagnostics, source, self.engine.sources()),
+        }
+    }
+
+    // compiles on a copy so the expression is never run
+    fn show_bytecode(&mut self, source: &str) {
+        match self.engine.compile_expression(source) {
+            Ok(program) => print!("{}", program.disassemble_from_start()),
+            Err(error) => report(&error.diagnostics, source, self.engine.sources()),
+        }
+    }
+}
+
+// diagnostics in imported modules are shown with their own file
+fn report(diagnostics: &[Diagnostic], source: &str, sources: &SourceMap) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.render_with(SOURCE_NAME, source, sources));
+    }
+}
+
+// counts the blocks, calls and arrays left open in the input, ignoring
+// strings and comments
+fn nesting_depth(input: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '(' | '[' => depth += 1,
+            '}' | ')' | ']' => depth -= 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        // an escaped quote does not end the string
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    depth
+}
@@ -0,0 +1,188 @@
+use std::f64::consts::PI;
+
+use super::natives::{NativeParameter, NativeType, Natives, Receiver};
+use crate::{lexer::Type, vm::Value};
+
+// the arguments of a math function, the type checker makes sure they are numbers
+fn numbers(name: &str, args: &[Value]) -> Result<Vec<f64>, String> {
+    args.iter()
+        .map(|arg| match arg {
+            Value::Number(n) => Ok(*n),
+            _ => Err(format!("function: {}() arguments must be numbers", name)),
+        })
+        .collect()
+}
+
+type MathFn = fn(f64) -> f64;
+
+fn unary(name: &str, args: Vec<Value>, f: MathFn) -> Result<Value, String> {
+    match numbers(name, &args)?.as_slice() {
+        [n] => Ok(Value::Number(f(*n))),
+        _ => Err(format!(
+            "function: {}() requires exactly one argument",
+            name
+        )),
+    }
+}
+
+pub fn pi(args: Vec<Value>) -> Result<Value, String> {
+    if !args.is_empty() {
+        return Err("function: pi() takes no arguments".to_string());
+    }
+
+    Ok(Value::Number(PI))
+}
+
+// the natural logarithm, or the logarithm in `base`
+pub fn log(args: Vec<Value>) -> Result<Value, String> {
+    match numbers("log", &args)?.as_slice() {
+        [n] => Ok(Value::Number(n.ln())),
+        // the dedicated functions are exact for powers of their base
+        [n, base] if *base == 10.0 => Ok(Value::Number(n.log10())),
+        [n, base] if *base == 2.0 => Ok(Value::Number(n.log2())),
+        [n, base] => Ok(Value::Number(n.log(*base))),
+        _ => Err("function: log() requires a number and an optional base".to_string()),
+    }
+}
+
+pub fn min(args: Vec<Value>) -> Result<Value, String> {
+    numbers("min", &args)?
+        .into_iter()
+        .reduce(f64::min)
+        .map(Value::Number)
+        .ok_or_else(|| "function: min() requires at least one argument".to_string())
+}
+
+pub fn max(args: Vec<Value>) -> Result<Value, String> {
+    numbers("max", &args)?
+        .into_iter()
+        .reduce(f64::max)
+        .map(Value::Number)
+        .ok_or_else(|| "function: max() requires at least one argument".to_string())
+}
+
+pub fn clamp(args: Vec<Value>) -> Result<Value, String> {
+    match numbers("clamp", &args)?.as_slice() {
+        // `f64::clamp` panics on these, they are reported instead
+        [_, low, high] if low > high || low.is_nan() || high.is_nan() => Err(format!(
+            "function: clamp() min must not be greater than max, got {} and {}",
+            low, high
+        )),
+        [n, low, high] => Ok(Value::Number(n.clamp(*low, *high))),
+        _ => Err("function: clamp() requires exactly three arguments".to_string()),
+    }
+}
+
+// the most decimals `format_fixed` writes, `format!` panics above 65535
+const MAX_FIXED_DIGITS: f64 = 100.0;
+
+// formats `num` with a fixed number of decimals
+pub fn format_fixed(num: f64, digits: f64, method: &str) -> Result<String, String> {
+    if !(0.0..=MAX_FIXED_DIGITS).contains(&digits) || digits.fract() != 0.0 {
+        return Err(format!(
+            "method: {}() digits must be a whole number from 0 to {}, got {}",
+            method, MAX_FIXED_DIGITS, digits
+        ));
+    }
+
+    Ok(format!("{:.*}", digits as usize, num))
+}
+
+pub fn number_is_nan(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::Number(num)] => Ok(Value::Boolean(num.is_nan())),
+        _ => Err("method: is_nan() requires exactly one number".to_string()),
+    }
+}
+
+pub fn number_to_fixed(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::Number(num), Value::Number(digits)] => {
+            format_fixed(*num, *digits, "to_fixed").map(Value::String)
+        }
+        _ => Err("method: to_fixed() requires a number and a digit count".to_string()),
+    }
+}
+
+pub fn register_math(natives: &mut Natives) {
+    let number = || NativeType::Exact(Type::Num);
+
+    // functions of a single number
+    let unary_functions: [(&'static str, MathFn); 9] = [
+        ("sqrt", f64::sqrt),
+        ("abs", f64::abs),
+        ("floor", f64::floor),
+        ("ceil", f64::ceil),
+        ("round", f64::round),
+        ("sin", f64::sin),
+        ("cos", f64::cos),
+        ("tan", f64::tan),
+        ("exp", f64::exp),
+    ];
+    for (name, f) in unary_functions {
+        natives.register_function(
+            name,
+            vec![NativeParameter::required("n", number())],
+            number(),
+            move |args| unary(name, args, f),
+        );
+    }
+
+    natives.register_function(
+        "log",
+        vec![
+            NativeParameter::required("n", number()),
+            NativeParameter::optional("base", number()),
+        ],
+        number(),
+        log,
+    );
+
+    natives.register_function("pi", vec![], number(), pi);
+
+    natives.register_function(
+        "min",
+        vec![
+            NativeParameter::required("a", number()),
+            NativeParameter::variadic("rest", number()),
+        ],
+        number(),
+        min,
+    );
+    natives.register_function(
+        "max",
+        vec![
+            NativeParameter::required("a", number()),
+            NativeParameter::variadic("rest", number()),
+        ],
+        number(),
+        max,
+    );
+
+    natives.register_function(
+        "clamp",
+        vec![
+            NativeParameter::required("n", number()),
+            NativeParameter::required("min", number()),
+            NativeParameter::required("max", number()),
+        ],
+        number(),
+        clamp,
+    );
+
+    // number methods
+    natives.register_method(
+        Receiver::Number,
+        "is_nan",
+        vec![],
+        NativeType::Exact(Type::Bool),
+        number_is_nan,
+    );
+    natives.register_method(
+        Receiver::Number,
+        "to_fixed",
+        vec![NativeParameter::required("digits", number())],
+        NativeType::Exact(Type::Str),
+        number_to_fixed,
+    );
+}
@@ -1,3 +1,4 @@
+pub mod math;
+pub mod natives;
 pub mod stdlib;
-
-use stdlib::*;
+pub mod strings;
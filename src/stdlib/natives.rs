@@ -0,0 +1,245 @@
+use std::{collections::HashMap, rc::Rc};
+
+use super::stdlib::register_stdlib;
+use crate::{analyzer::typechecker::is_assignable, lexer::Type, vm::Value};
+
+// natives are closures so host programs can capture their own state
+pub type NativeFn = Rc<dyn Fn(Vec<Value>) -> Result<Value, String>>;
+
+// the bytes of string a native is about to build from its arguments, the vm
+// checks them against its string limit before the native allocates
+pub type StringSizeFn = fn(&[Value]) -> Option<usize>;
+
+// the type a native parameter accepts or a native returns
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeType {
+    Exact(Type),
+    Any,     // any value except void
+    Element, // the element type of the array a method is called on
+}
+
+impl NativeType {
+    // checks a value a host function returned, `Element` is only used by methods
+    pub fn accepts(&self, value: &Value) -> bool {
+        match self {
+            NativeType::Exact(t) => is_assignable(t, &value.value_type()),
+            NativeType::Any | NativeType::Element => !matches!(value, Value::Void),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NativeParameter {
+    pub name: &'static str,
+    pub param_type: NativeType,
+    pub optional: bool,
+    pub variadic: bool, // takes every remaining argument, only allowed last
+}
+
+impl NativeParameter {
+    pub fn required(name: &'static str, param_type: NativeType) -> Self {
+        Self {
+            name,
+            param_type,
+            optional: false,
+            variadic: false,
+        }
+    }
+
+    pub fn optional(name: &'static str, param_type: NativeType) -> Self {
+        Self {
+            optional: true,
+            ..Self::required(name, param_type)
+        }
+    }
+
+    pub fn variadic(name: &'static str, param_type: NativeType) -> Self {
+        Self {
+            variadic: true,
+            ..Self::required(name, param_type)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NativeSignature {
+    pub parameters: Vec<NativeParameter>,
+    pub return_type: NativeType,
+}
+
+impl NativeSignature {
+    pub fn required_count(&self) -> usize {
+        self.parameters
+            .iter()
+            .filter(|p| !p.optional && !p.variadic)
+            .count()
+    }
+
+    // `None` when a variadic parameter takes any number of arguments
+    pub fn max_count(&self) -> Option<usize> {
+        match self.parameters.last() {
+            Some(last) if last.variadic => None,
+            _ => Some(self.parameters.len()),
+        }
+    }
+
+    // the parameter receiving the argument at `index`
+    pub fn parameter(&self, index: usize) -> Option<&NativeParameter> {
+        match self.parameters.get(index) {
+            Some(parameter) => Some(parameter),
+            None => self.parameters.last().filter(|p| p.variadic),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Native {
+    pub signature: NativeSignature,
+    pub function: NativeFn,
+    pub string_size: Option<StringSizeFn>,
+    // takes a boo function, natives cannot call one so the vm runs the method
+    // itself and the type checker checks the function it takes
+    pub calls_back: bool,
+}
+
+// the kind of value a native method is called on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Receiver {
+    String,
+    Number,
+    Boolean,
+    Array,
+}
+
+impl Receiver {
+    pub fn of_type(t: &Type) -> Option<Receiver> {
+        match t {
+            Type::Str => Some(Receiver::String),
+            Type::Num => Some(Receiver::Number),
+            Type::Bool => Some(Receiver::Boolean),
+            Type::Array(_) => Some(Receiver::Array),
+            _ => None,
+        }
+    }
+
+    pub fn of_value(value: &Value) -> Option<Receiver> {
+        match value {
+            Value::String(_) => Some(Receiver::String),
+            Value::Number(_) => Some(Receiver::Number),
+            Value::Boolean(_) => Some(Receiver::Boolean),
+            Value::Array(_) => Some(Receiver::Array),
+            _ => None,
+        }
+    }
+}
+
+// every native function and method with its signature, one registration is
+// used by the type checker to check calls and by the vm to run them
+#[derive(Clone, Default)]
+pub struct Natives {
+    functions: HashMap<String, Native>,
+    methods: HashMap<(Receiver, String), Native>,
+}
+
+impl Natives {
+    pub fn new() -> Self {
+        let mut natives = Self::default();
+        register_stdlib(&mut natives);
+        natives
+    }
+
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        parameters: Vec<NativeParameter>,
+        return_type: NativeType,
+        function: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    ) {
+        let signature = NativeSignature {
+            parameters,
+            return_type,
+        };
+        let function = Rc::new(function);
+        self.functions.insert(
+            name.to_string(),
+            Native {
+                signature,
+                function,
+                string_size: None,
+                calls_back: false,
+            },
+        );
+    }
+
+    // methods receive the value they are called on as their first argument,
+    // it is not part of `parameters`
+    pub fn register_method(
+        &mut self,
+        receiver: Receiver,
+        name: &str,
+        parameters: Vec<NativeParameter>,
+        return_type: NativeType,
+        function: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    ) {
+        let signature = NativeSignature {
+            parameters,
+            return_type,
+        };
+        let function = Rc::new(function);
+        self.methods.insert(
+            (receiver, name.to_string()),
+            Native {
+                signature,
+                function,
+                string_size: None,
+                calls_back: false,
+            },
+        );
+    }
+
+    // a method the vm runs itself, registered for its signature
+    pub fn register_callback_method(
+        &mut self,
+        receiver: Receiver,
+        name: &str,
+        parameters: Vec<NativeParameter>,
+        return_type: NativeType,
+    ) {
+        let message = format!("method: {}() is run by the vm", name);
+        self.register_method(receiver, name, parameters, return_type, move |_| {
+            Err(message.clone())
+        });
+        if let Some(native) = self.methods.get_mut(&(receiver, name.to_string())) {
+            native.calls_back = true;
+        }
+    }
+
+    // for methods that can build strings far larger than their arguments
+    pub fn set_method_string_size(&mut self, receiver: Receiver, name: &str, size: StringSizeFn) {
+        if let Some(native) = self.methods.get_mut(&(receiver, name.to_string())) {
+            native.string_size = Some(size);
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Native> {
+        self.functions.get(name)
+    }
+
+    pub fn method(&self, receiver: Receiver, name: &str) -> Option<&Native> {
+        self.methods.get(&(receiver, name.to_string()))
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &Native)> {
+        self.functions
+            .iter()
+            .map(|(name, native)| (name.as_str(), native))
+    }
+
+    // the methods values of `receiver` have
+    pub fn methods(&self, receiver: Receiver) -> impl Iterator<Item = (&str, &Native)> {
+        self.methods
+            .iter()
+            .filter(move |((method_receiver, _), _)| *method_receiver == receiver)
+            .map(|((_, name), native)| (name.as_str(), native))
+    }
+}
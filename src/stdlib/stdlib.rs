@@ -1,12 +1,11 @@
-use crate::{
-    analyzer::TypeChecker,
-    lexer::Type,
-    vm::{Value, VM},
+use super::{
+    math::{format_fixed, register_math},
+    natives::{NativeParameter, NativeType, Natives, Receiver},
+    strings::register_strings,
 };
+use crate::{lexer::Type, vm::Value};
 
-pub type NativeFn = fn(&mut VM, Vec<Value>) -> Result<Value, String>;
-
-pub fn print(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+pub fn print(args: Vec<Value>) -> Result<Value, String> {
     if args.is_empty() {
         println!();
         return Ok(Value::Void);
@@ -17,6 +16,9 @@ pub fn print(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
             Value::Number(num) => println!("{}", num),
             Value::String(s) => println!("{}", s),
             Value::Boolean(b) => println!("{}", b),
+            Value::Array(_) | Value::Struct(_) | Value::Function(_) | Value::None => {
+                println!("{}", arg)
+            }
             Value::Void => println!("void"),
         }
     }
@@ -24,18 +26,38 @@ pub fn print(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
     Ok(Value::Void)
 }
 
-pub fn string_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
-    if args.len() != 1 {
-        return Err("method: len() requires exactly one argument".to_string());
+// strings are quoted so `1` and `"1"` can be told apart
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", s),
+        _ => value.to_string(),
     }
+}
 
-    match &args[0] {
-        Value::String(s) => Ok(Value::Number(s.len() as f64)),
-        _ => Err("method: len() argument must be a string".to_string()),
+pub fn assert(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::Boolean(true), ..] => Ok(Value::Void),
+        [Value::Boolean(false)] => Err("Assertion failed".to_string()),
+        [Value::Boolean(false), Value::String(message)] => {
+            Err(format!("Assertion failed: {}", message))
+        }
+        _ => Err("assert() requires a boolean and an optional message".to_string()),
+    }
+}
+
+pub fn assert_eq(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [left, right] if left == right => Ok(Value::Void),
+        [left, right] => Err(format!(
+            "Assertion failed: values are not equal\n  left: {}\n right: {}",
+            show(left),
+            show(right)
+        )),
+        _ => Err("assert_eq() requires exactly two arguments".to_string()),
     }
 }
 
-pub fn to_string(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+pub fn to_string(args: Vec<Value>) -> Result<Value, String> {
     if args.len() != 1 {
         return Err("method: to_string() requires exactly one argument".to_string());
     }
@@ -48,32 +70,152 @@ pub fn to_string(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
     }
 }
 
-pub fn register_stdlib(vm: &mut VM) {
-    // register native functions
-    vm.register_native_function("print", print);
+// `digits` fixes the number of decimals
+pub fn number_to_string(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::Number(num)] => Ok(Value::String(num.to_string())),
+        [Value::Number(num), Value::Number(digits)] => {
+            format_fixed(*num, *digits, "to_string").map(Value::String)
+        }
+        _ => Err("method: to_string() requires a number and an optional digit count".to_string()),
+    }
+}
 
-    // register string methods
-    vm.register_string_method("len", string_len);
-    vm.register_string_method("to_string", to_string);
+pub fn array_len(args: Vec<Value>) -> Result<Value, String> {
+    if args.len() != 1 {
+        return Err("method: len() requires exactly one argument".to_string());
+    }
 
-    // register number methods
-    vm.register_number_method("to_string", to_string);
+    match &args[0] {
+        Value::Array(elements) => Ok(Value::Number(elements.borrow().len() as f64)),
+        _ => Err("method: len() argument must be an array".to_string()),
+    }
+}
 
-    // register boolean methods
-    vm.register_boolean_method("to_string", to_string);
+pub fn array_push(args: Vec<Value>) -> Result<Value, String> {
+    if args.len() != 2 {
+        return Err("method: push() requires exactly two arguments".to_string());
+    }
+
+    match &args[0] {
+        Value::Array(elements) => {
+            elements.borrow_mut().push(args[1].clone());
+            Ok(Value::Void)
+        }
+        _ => Err("method: push() argument must be an array".to_string()),
+    }
+}
+
+pub fn array_pop(args: Vec<Value>) -> Result<Value, String> {
+    if args.len() != 1 {
+        return Err("method: pop() requires exactly one argument".to_string());
+    }
+
+    match &args[0] {
+        Value::Array(elements) => elements
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| "method: pop() called on an empty array".to_string()),
+        _ => Err("method: pop() argument must be an array".to_string()),
+    }
 }
 
-pub fn register_stdlib_types(checker: &mut TypeChecker) {
+pub fn register_stdlib(natives: &mut Natives) {
     // register native functions
-    checker.register_native_function_type("print", Type::Void);
+    natives.register_function(
+        "print",
+        vec![NativeParameter::variadic("values", NativeType::Any)],
+        NativeType::Exact(Type::Void),
+        print,
+    );
+
+    // checks for `test` blocks, a failed one stops the test with a runtime error
+    natives.register_function(
+        "assert",
+        vec![
+            NativeParameter::required("condition", NativeType::Exact(Type::Bool)),
+            NativeParameter::optional("message", NativeType::Exact(Type::Str)),
+        ],
+        NativeType::Exact(Type::Void),
+        assert,
+    );
+    natives.register_function(
+        "assert_eq",
+        vec![
+            NativeParameter::required("left", NativeType::Any),
+            NativeParameter::required("right", NativeType::Any),
+        ],
+        NativeType::Exact(Type::Void),
+        assert_eq,
+    );
+
+    register_math(natives);
 
     // register string methods
-    checker.register_string_method_type("len", Type::Num);
-    checker.register_string_method_type("to_string", Type::Str);
+    register_strings(natives);
+    natives.register_method(
+        Receiver::String,
+        "to_string",
+        vec![],
+        NativeType::Exact(Type::Str),
+        to_string,
+    );
 
     // register number methods
-    checker.register_number_method_type("to_string", Type::Str);
+    natives.register_method(
+        Receiver::Number,
+        "to_string",
+        vec![NativeParameter::optional(
+            "digits",
+            NativeType::Exact(Type::Num),
+        )],
+        NativeType::Exact(Type::Str),
+        number_to_string,
+    );
 
     // register boolean methods
-    checker.register_boolean_method_type("to_string", Type::Str);
+    natives.register_method(
+        Receiver::Boolean,
+        "to_string",
+        vec![],
+        NativeType::Exact(Type::Str),
+        to_string,
+    );
+
+    // register array methods, generic over the element type
+    natives.register_method(
+        Receiver::Array,
+        "len",
+        vec![],
+        NativeType::Exact(Type::Num),
+        array_len,
+    );
+    natives.register_method(
+        Receiver::Array,
+        "push",
+        vec![NativeParameter::required("value", NativeType::Element)],
+        NativeType::Exact(Type::Void),
+        array_push,
+    );
+    natives.register_method(
+        Receiver::Array,
+        "pop",
+        vec![],
+        NativeType::Element,
+        array_pop,
+    );
+
+    // these call the function they are given for every element
+    let function = || NativeParameter::required("function", NativeType::Any);
+    natives.register_callback_method(Receiver::Array, "map", vec![function()], NativeType::Any);
+    natives.register_callback_method(Receiver::Array, "filter", vec![function()], NativeType::Any);
+    natives.register_callback_method(
+        Receiver::Array,
+        "reduce",
+        vec![
+            function(),
+            NativeParameter::required("initial", NativeType::Any),
+        ],
+        NativeType::Any,
+    );
 }
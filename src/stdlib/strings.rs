@@ -0,0 +1,373 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::natives::{NativeParameter, NativeType, Natives, Receiver};
+use crate::{lexer::Type, vm::Value};
+
+// string positions count characters, not bytes, so they work the same for any text
+
+// checks a character position given to `method`, `length` is the largest allowed
+fn char_position(method: &str, what: &str, value: f64, length: usize) -> Result<usize, String> {
+    if value < 0.0 || value.fract() != 0.0 {
+        return Err(format!(
+            "method: {}() {} must be a whole number of at least 0, got {}",
+            method, what, value
+        ));
+    }
+
+    if value > length as f64 {
+        return Err(format!(
+            "method: {}() {} {} is out of range for string of length {}",
+            method, what, value, length
+        ));
+    }
+
+    Ok(value as usize)
+}
+
+// the byte offset of the character at `position`
+fn byte_offset(s: &str, position: usize) -> usize {
+    s.char_indices()
+        .nth(position)
+        .map(|(offset, _)| offset)
+        .unwrap_or(s.len())
+}
+
+fn string_array(strings: impl Iterator<Item = String>) -> Value {
+    Value::Array(Rc::new(RefCell::new(strings.map(Value::String).collect())))
+}
+
+pub fn string_len(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(Value::Number(s.chars().count() as f64)),
+        _ => Err("method: len() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_byte_len(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(Value::Number(s.len() as f64)),
+        _ => Err("method: byte_len() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_upper(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(Value::String(s.to_uppercase())),
+        _ => Err("method: upper() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_lower(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(Value::String(s.to_lowercase())),
+        _ => Err("method: lower() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_trim(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(Value::String(s.trim().to_string())),
+        _ => Err("method: trim() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_split(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(_), Value::String(separator)] if separator.is_empty() => {
+            Err("method: split() separator must not be empty, use chars() instead".to_string())
+        }
+        [Value::String(s), Value::String(separator)] => Ok(string_array(
+            s.split(separator.as_str()).map(str::to_string),
+        )),
+        _ => Err("method: split() requires a string and a separator".to_string()),
+    }
+}
+
+pub fn string_chars(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => Ok(string_array(s.chars().map(String::from))),
+        _ => Err("method: chars() argument must be a string".to_string()),
+    }
+}
+
+pub fn string_contains(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s), Value::String(part)] => Ok(Value::Boolean(s.contains(part.as_str()))),
+        _ => Err("method: contains() requires two strings".to_string()),
+    }
+}
+
+pub fn string_starts_with(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s), Value::String(prefix)] => {
+            Ok(Value::Boolean(s.starts_with(prefix.as_str())))
+        }
+        _ => Err("method: starts_with() requires two strings".to_string()),
+    }
+}
+
+pub fn string_ends_with(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s), Value::String(suffix)] => {
+            Ok(Value::Boolean(s.ends_with(suffix.as_str())))
+        }
+        _ => Err("method: ends_with() requires two strings".to_string()),
+    }
+}
+
+// the character position of the first occurrence, or -1
+pub fn string_index_of(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s), Value::String(part)] => {
+            let position = match s.find(part.as_str()) {
+                Some(offset) => s[..offset].chars().count() as f64,
+                None => -1.0,
+            };
+            Ok(Value::Number(position))
+        }
+        _ => Err("method: index_of() requires two strings".to_string()),
+    }
+}
+
+pub fn string_replace(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s), Value::String(from), Value::String(to)] => {
+            Ok(Value::String(s.replace(from.as_str(), to)))
+        }
+        _ => Err("method: replace() requires three strings".to_string()),
+    }
+}
+
+// `length` characters from `start`, or every character after it
+pub fn string_substr(args: Vec<Value>) -> Result<Value, String> {
+    let (s, start, length) = match args.as_slice() {
+        [Value::String(s), Value::Number(start)] => (s, *start, None),
+        [Value::String(s), Value::Number(start), Value::Number(length)] => {
+            (s, *start, Some(*length))
+        }
+        _ => {
+            return Err(
+                "method: substr() requires a string, a start and an optional length".to_string(),
+            )
+        }
+    };
+
+    let char_count = s.chars().count();
+    let start = char_position("substr", "start", start, char_count)?;
+    let length = match length {
+        Some(length) if length < 0.0 || length.fract() != 0.0 => {
+            return Err(format!(
+                "method: substr() length must be a whole number of at least 0, got {}",
+                length
+            ))
+        }
+        Some(length) => (length as usize).min(char_count - start),
+        None => char_count - start,
+    };
+
+    Ok(Value::String(s.chars().skip(start).take(length).collect()))
+}
+
+// the characters from `start` up to, but not including, `end`
+pub fn string_slice(args: Vec<Value>) -> Result<Value, String> {
+    let (s, start, end) = match args.as_slice() {
+        [Value::String(s), Value::Number(start)] => (s, *start, None),
+        [Value::String(s), Value::Number(start), Value::Number(end)] => (s, *start, Some(*end)),
+        _ => {
+            return Err(
+                "method: slice() requires a string, a start and an optional end".to_string(),
+            )
+        }
+    };
+
+    let char_count = s.chars().count();
+    let start = char_position("slice", "start", start, char_count)?;
+    let end = match end {
+        Some(end) => char_position("slice", "end", end, char_count)?,
+        None => char_count,
+    };
+
+    if start > end {
+        return Err(format!(
+            "method: slice() start {} is after end {}",
+            start, end
+        ));
+    }
+
+    let (start, end) = (byte_offset(s, start), byte_offset(s, end));
+    Ok(Value::String(s[start..end].to_string()))
+}
+
+pub fn string_repeat(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(_), Value::Number(count)] if *count < 0.0 || count.fract() != 0.0 => {
+            Err(format!(
+                "method: repeat() count must be a whole number of at least 0, got {}",
+                count
+            ))
+        }
+        [Value::String(s), Value::Number(count)] => {
+            // reserved first, `str::repeat` aborts the process when the
+            // allocation fails instead of returning an error
+            let too_large = || format!("method: repeat() count {} is too large", count);
+            let length = repeat_size(&args).ok_or_else(too_large)?;
+            let mut repeated = String::new();
+            repeated
+                .try_reserve_exact(length)
+                .map_err(|_| too_large())?;
+            if !s.is_empty() {
+                for _ in 0..*count as usize {
+                    repeated.push_str(s);
+                }
+            }
+            Ok(Value::String(repeated))
+        }
+        _ => Err("method: repeat() requires a string and a count".to_string()),
+    }
+}
+
+// the length `repeat` will build, `None` when it does not fit in memory
+fn repeat_size(args: &[Value]) -> Option<usize> {
+    match args {
+        [Value::String(s), Value::Number(count)] if *count >= 0.0 => s
+            .len()
+            .checked_mul(*count as usize)
+            .filter(|length| *length <= isize::MAX as usize),
+        _ => None,
+    }
+}
+
+// the bytes `split` and `chars` build, a string per piece in an array slot
+fn split_size(args: &[Value]) -> Option<usize> {
+    match args {
+        [Value::String(s), Value::String(separator)] if !separator.is_empty() => {
+            let pieces = s.matches(separator.as_str()).count() + 1;
+            Some(s.len() + pieces * std::mem::size_of::<Value>())
+        }
+        _ => None,
+    }
+}
+
+fn chars_size(args: &[Value]) -> Option<usize> {
+    match args {
+        [Value::String(s)] => Some(s.len() + s.chars().count() * std::mem::size_of::<Value>()),
+        _ => None,
+    }
+}
+
+// the number the string spells, or NaN when it is not a number (see `is_nan`),
+// "inf" and "nan" are not numbers either so NaN always means it failed
+pub fn string_to_num(args: Vec<Value>) -> Result<Value, String> {
+    match args.as_slice() {
+        [Value::String(s)] => {
+            let number = s.trim().parse().unwrap_or(f64::NAN);
+            Ok(Value::Number(if number.is_finite() {
+                number
+            } else {
+                f64::NAN
+            }))
+        }
+        _ => Err("method: to_num() argument must be a string".to_string()),
+    }
+}
+
+pub fn register_strings(natives: &mut Natives) {
+    let string = || NativeType::Exact(Type::Str);
+    let number = || NativeType::Exact(Type::Num);
+    let boolean = || NativeType::Exact(Type::Bool);
+    let strings = || NativeType::Exact(Type::Array(Box::new(Type::Str)));
+
+    natives.register_method(Receiver::String, "len", vec![], number(), string_len);
+    natives.register_method(
+        Receiver::String,
+        "byte_len",
+        vec![],
+        number(),
+        string_byte_len,
+    );
+
+    natives.register_method(Receiver::String, "upper", vec![], string(), string_upper);
+    natives.register_method(Receiver::String, "lower", vec![], string(), string_lower);
+    natives.register_method(Receiver::String, "trim", vec![], string(), string_trim);
+
+    natives.register_method(
+        Receiver::String,
+        "split",
+        vec![NativeParameter::required("separator", string())],
+        strings(),
+        string_split,
+    );
+    natives.set_method_string_size(Receiver::String, "split", split_size);
+    natives.register_method(Receiver::String, "chars", vec![], strings(), string_chars);
+    natives.set_method_string_size(Receiver::String, "chars", chars_size);
+
+    natives.register_method(
+        Receiver::String,
+        "contains",
+        vec![NativeParameter::required("part", string())],
+        boolean(),
+        string_contains,
+    );
+    natives.register_method(
+        Receiver::String,
+        "starts_with",
+        vec![NativeParameter::required("prefix", string())],
+        boolean(),
+        string_starts_with,
+    );
+    natives.register_method(
+        Receiver::String,
+        "ends_with",
+        vec![NativeParameter::required("suffix", string())],
+        boolean(),
+        string_ends_with,
+    );
+    natives.register_method(
+        Receiver::String,
+        "index_of",
+        vec![NativeParameter::required("part", string())],
+        number(),
+        string_index_of,
+    );
+
+    natives.register_method(
+        Receiver::String,
+        "replace",
+        vec![
+            NativeParameter::required("from", string()),
+            NativeParameter::required("to", string()),
+        ],
+        string(),
+        string_replace,
+    );
+    natives.register_method(
+        Receiver::String,
+        "substr",
+        vec![
+            NativeParameter::required("start", number()),
+            NativeParameter::optional("length", number()),
+        ],
+        string(),
+        string_substr,
+    );
+    natives.register_method(
+        Receiver::String,
+        "slice",
+        vec![
+            NativeParameter::required("start", number()),
+            NativeParameter::optional("end", number()),
+        ],
+        string(),
+        string_slice,
+    );
+    natives.register_method(
+        Receiver::String,
+        "repeat",
+        vec![NativeParameter::required("count", number())],
+        string(),
+        string_repeat,
+    );
+    natives.set_method_string_size(Receiver::String, "repeat", repeat_size);
+
+    natives.register_method(Receiver::String, "to_num", vec![], number(), string_to_num);
+}
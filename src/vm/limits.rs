@@ -0,0 +1,34 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// bounds for running untrusted code, `None` means unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub fuel: Option<u64>,               // instructions executed per run
+    pub max_call_depth: Option<usize>,   // functions running at the same time
+    pub max_stack_size: Option<usize>,   // values on the stack, locals included
+    pub max_string_bytes: Option<usize>, // bytes of strings built per run, with their array slots
+}
+
+// stops a running VM from another thread, the VM notices within a few
+// instructions and fails with an interrupted error
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.interrupted.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,3 +1,5 @@
+mod limits;
 mod vm;
 
+pub use limits::*;
 pub use vm::*;
@@ -1,99 +1,267 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt,
+    rc::Rc,
+};
 
 use crate::{
-    bytecode::Instruction,
-    parser::Parameter,
-    stdlib::stdlib::{register_stdlib, NativeFn},
+    bytecode::{Capture, DebugInfo, Instruction},
+    diagnostics::{Diagnostic, DiagnosticKind, Limit, TraceFrame},
+    lexer::Type,
+    parser::method_function_name,
+    stdlib::natives::{Natives, Receiver},
 };
 
-#[derive(Clone, Debug)]
+use super::{InterruptHandle, Limits};
+
+// instructions run between checks of the interrupt flag
+const INTERRUPT_INTERVAL: u64 = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Number(f64),
     String(String),
     Boolean(bool),
+    Array(Rc<RefCell<Vec<Value>>>), // arrays are shared by reference
+    Struct(Rc<RefCell<StructInstance>>), // so are struct instances
+    Function(Rc<Closure>),
+    None, // the value of `none`, stored in optional types
     Void,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructInstance {
+    pub name: String,
+    pub fields: Vec<(String, Value)>, // in declaration order
+}
+
+// a function value, the variables it captured are shared with the code that
+// declared them
+pub struct Closure {
+    pub name: Rc<str>, // the function it calls
+    pub function_type: Type,
+    upvalues: Vec<Rc<RefCell<Value>>>,
+}
+
+// a closure can capture itself, so only its name is printed
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({})", self.name)
+    }
+}
+
+// closures are equal when they call the same function with the same variables
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.upvalues.len() == other.upvalues.len()
+            && self
+                .upvalues
+                .iter()
+                .zip(&other.upvalues)
+                .all(|(a, b)| Rc::ptr_eq(a, b))
+    }
+}
+
+impl Value {
+    // the type of an array is taken from its first element, empty arrays
+    // have a void element type like `[]` does
+    pub fn value_type(&self) -> Type {
+        match self {
+            Value::Number(_) => Type::Num,
+            Value::String(_) => Type::Str,
+            Value::Boolean(_) => Type::Bool,
+            Value::Array(elements) => {
+                let element_type = match elements.borrow().first() {
+                    Some(element) => element.value_type(),
+                    None => Type::Void,
+                };
+                Type::Array(Box::new(element_type))
+            }
+            Value::Struct(instance) => Type::Struct(instance.borrow().name.clone()),
+            Value::Function(closure) => closure.function_type.clone(),
+            Value::None => Type::Optional(Box::new(Type::Void)),
+            Value::Void => Type::Void,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Boolean(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(elements: Vec<Value>) -> Self {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(num) => write!(f, "{}", num),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match element {
+                        Value::String(s) => write!(f, "{:?}", s)?,
+                        _ => write!(f, "{}", element)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::Struct(instance) => {
+                let instance = instance.borrow();
+                write!(f, "{} {{ ", instance.name)?;
+                for (i, (name, value)) in instance.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match value {
+                        Value::String(s) => write!(f, "{}: {:?}", name, s)?,
+                        _ => write!(f, "{}: {}", name, value)?,
+                    }
+                }
+                write!(f, " }}")
+            }
+            Value::Function(closure) => write!(f, "<fun {}>", closure.name),
+            Value::None => write!(f, "none"),
+            Value::Void => write!(f, "void"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Function {
-    parameters: Vec<Parameter>,
+    name: Rc<str>,
+    required_parameters: usize,
+    parameters: usize,
+    slot_count: usize, // parameters and locals
     address: usize,
 }
 
 #[derive(Clone, Debug)]
 struct CallFrame {
+    function: Rc<str>, // the function this frame belongs to
     return_address: usize,
-    variables: HashMap<String, Value>,
-    scope_index: usize,
+    frame_pointer: usize,                   // of the caller
+    cells: Vec<Option<Rc<RefCell<Value>>>>, // of the caller
+    closure: Option<Rc<Closure>>,           // of the caller
 }
 
 pub struct VM {
     debug: bool,
-    instructions: Vec<Instruction>,
+    instructions: Rc<[Instruction]>,
+    debug_info: DebugInfo,
     pc: usize,
+    // the frame of a running function lives on the stack: its parameters and
+    // locals start at `frame_pointer`, temporaries are pushed above them
     stack: Vec<Value>,
-    scopes: Vec<HashMap<String, Value>>,
+    frame_pointer: usize,
+    cells: Vec<Option<Rc<RefCell<Value>>>>, // captured locals of the running function, by slot
+    closure: Option<Rc<Closure>>,           // the closure of the running function
+    globals: Vec<Value>,
+    global_cells: Vec<Option<Rc<RefCell<Value>>>>, // of globals declared in a block, by index
     call_stack: Vec<CallFrame>,
+    base_depth: usize, // returning below this call depth hands the value back to `call_value`
     functions: HashMap<String, Function>,
-    native_functions: HashMap<String, NativeFn>,
-    string_methods: HashMap<String, NativeFn>,
-    number_methods: HashMap<String, NativeFn>,
-    boolean_methods: HashMap<String, NativeFn>,
+    structs: HashMap<String, Vec<String>>, // field names of every declared struct
+    natives: Rc<Natives>,
+    limits: Limits,
+    interrupt: InterruptHandle,
+    executed: u64,            // instructions run by the current run or call
+    string_bytes: usize,      // bytes of strings built by the current run or call
+    limit_hit: Option<Limit>, // the limit behind the error being returned
 }
 
 impl VM {
-    pub fn new(instructions: Vec<Instruction>) -> Self {
-        let mut vm = Self {
+    // uses the same natives as the type checker that checked the program
+    pub fn with_natives(instructions: Vec<Instruction>, natives: Rc<Natives>) -> Self {
+        Self {
             debug: false,
-            instructions,
+            instructions: instructions.into(),
+            debug_info: DebugInfo::default(),
             pc: 0,
             stack: Vec::new(),
-            scopes: vec![HashMap::new()], // global scope !
+            frame_pointer: 0,
+            cells: Vec::new(),
+            closure: None,
+            globals: Vec::new(),
+            global_cells: Vec::new(),
             call_stack: Vec::new(),
+            base_depth: 0,
             functions: HashMap::new(),
-
-            // stdlib
-            native_functions: HashMap::new(),
-            string_methods: HashMap::new(),
-            number_methods: HashMap::new(),
-            boolean_methods: HashMap::new(),
-        };
-
-        register_stdlib(&mut vm);
-
-        vm
+            structs: HashMap::new(),
+            natives,
+            limits: Limits::default(),
+            interrupt: InterruptHandle::default(),
+            executed: 0,
+            string_bytes: 0,
+            limit_hit: None,
+        }
     }
 
-    pub fn register_native_function(&mut self, name: &str, fun: NativeFn) {
-        self.native_functions.insert(name.to_string(), fun);
+    pub fn set_natives(&mut self, natives: Rc<Natives>) {
+        self.natives = natives;
     }
 
-    pub fn register_string_method(&mut self, name: &str, fun: NativeFn) {
-        self.string_methods.insert(name.to_string(), fun);
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
 
-    pub fn register_number_method(&mut self, name: &str, fun: NativeFn) {
-        self.number_methods.insert(name.to_string(), fun);
+    // a handle other threads can use to stop the program this VM is running
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
     }
 
-    pub fn register_boolean_method(&mut self, name: &str, fun: NativeFn) {
-        self.boolean_methods.insert(name.to_string(), fun);
+    pub fn global(&self, index: u32) -> Option<&Value> {
+        self.globals.get(index as usize)
     }
 
-    fn debug_print(&self, message: String) {
-        if self.debug {
-            println!("{}", message);
+    pub fn set_global(&mut self, index: u32, value: Value) {
+        let index = index as usize;
+        if index >= self.globals.len() {
+            self.globals.resize(index + 1, Value::Void);
         }
+        self.globals[index] = value;
     }
 
-    #[inline]
-    fn current_scope(&mut self) -> &mut HashMap<String, Value> {
-        self.scopes.last_mut().unwrap()
+    // the message is only built when debugging, this runs for every instruction
+    fn debug_print(&self, message: impl FnOnce() -> String) {
+        if self.debug {
+            println!("{}", message());
+        }
     }
 
     #[inline]
     fn push(&mut self, value: Value) {
-        self.debug_print(format!("Pushing value: {:?}", value));
+        self.debug_print(|| format!("Pushing value: {:?}", value));
         self.stack.push(value);
     }
 
@@ -104,49 +272,333 @@ impl VM {
             .ok_or_else(|| "Stack underflow".to_string())
     }
 
+    fn limit_error(&mut self, limit: Limit, message: String) -> String {
+        self.limit_hit = Some(limit);
+        message
+    }
+
+    // counts the instruction about to run against the fuel and checks the
+    // stack size and the interrupt flag
     #[inline]
-    fn get_variable(&mut self, name: &str) -> Result<Value, String> {
-        // fast path -> check the topmost scope first (most common case)
-        if let Some(scope) = self.scopes.last() {
-            if let Some(value) = scope.get(name) {
-                return Ok(value.clone());
+    fn check_limits(&mut self) -> Result<(), String> {
+        self.executed += 1;
+
+        if let Some(fuel) = self.limits.fuel {
+            if self.executed > fuel {
+                let message = format!("Instruction limit of {} exceeded", fuel);
+                return Err(self.limit_error(Limit::Fuel, message));
             }
         }
 
-        // search other scopes from top? to bottom? (like javascript)
-        for scope in self.scopes.iter().rev() {
-            if let Some(value) = scope.get(name) {
-                return Ok(value.clone());
+        if let Some(max) = self.limits.max_stack_size {
+            if self.stack.len() > max {
+                let message = format!("Stack size limit of {} values exceeded", max);
+                return Err(self.limit_error(Limit::StackSize, message));
             }
         }
 
-        Err(format!("Variable '{}' not found", name))
+        if self.executed.is_multiple_of(INTERRUPT_INTERVAL) && self.interrupt.is_interrupted() {
+            self.interrupt.reset();
+            let message = "Execution interrupted".to_string();
+            return Err(self.limit_error(Limit::Interrupted, message));
+        }
+
+        Ok(())
+    }
+
+    // counts a newly built string against the string memory limit, like the
+    // strings of an array a native built
+    fn count_string(&mut self, value: &Value) -> Result<(), String> {
+        self.string_bytes += string_bytes(value);
+        match self.limits.max_string_bytes {
+            Some(max) if self.string_bytes > max => {
+                let message = format!("String memory limit of {} bytes exceeded", max);
+                Err(self.limit_error(Limit::StringBytes, message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // fails before a native builds a string that would not fit in the limit
+    fn check_string_room(&mut self, bytes: usize) -> Result<(), String> {
+        match self.limits.max_string_bytes {
+            Some(max) if self.string_bytes.saturating_add(bytes) > max => {
+                let message = format!("String memory limit of {} bytes exceeded", max);
+                Err(self.limit_error(Limit::StringBytes, message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // limits apply to every run or call on its own
+    fn start(&mut self, address: usize) {
+        self.pc = address;
+        self.executed = 0;
+        self.string_bytes = 0;
+        self.interrupt.reset();
+    }
+
+    // sets up a call frame for a user function whose `arg_count` arguments are
+    // on top of the stack and jumps to its body, `closure` is the function
+    // value it is called through
+    fn call_function(
+        &mut self,
+        name: &str,
+        arg_count: usize,
+        closure: Option<Rc<Closure>>,
+    ) -> Result<(), String> {
+        let function = match self.functions.get(name) {
+            Some(f) => f.clone(),
+            None => return Err(format!("Usage of undeclared function '{}'", name)),
+        };
+
+        // check arg count
+        if arg_count < function.required_parameters || arg_count > function.parameters {
+            return Err(format!(
+                "Function '{}' requires {} arguments, but {} were provided",
+                name, function.required_parameters, arg_count
+            ));
+        }
+
+        if arg_count > self.stack.len() {
+            return Err("Stack underflow".to_string());
+        }
+
+        if let Some(max) = self.limits.max_call_depth {
+            if self.call_stack.len() >= max {
+                let message = format!("Call depth limit of {} exceeded", max);
+                return Err(self.limit_error(Limit::CallDepth, message));
+            }
+        }
+
+        // the arguments become the first slots of the new frame
+        let frame_pointer = self.stack.len() - arg_count;
+
+        // left out parameters are void until the function fills in their
+        // defaults, locals start out void as well
+        self.stack.resize(
+            frame_pointer + function.slot_count.max(arg_count),
+            Value::Void,
+        );
+
+        // save call frame
+        self.call_stack.push(CallFrame {
+            function: function.name,
+            return_address: self.pc + 1,
+            frame_pointer: self.frame_pointer,
+            cells: std::mem::take(&mut self.cells),
+            closure: std::mem::replace(&mut self.closure, closure),
+        });
+        self.frame_pointer = frame_pointer;
+
+        // jump to function body
+        self.pc = function.address;
+        Ok(())
+    }
+
+    // replaces the program while keeping globals and declared functions, the
+    // new instructions must extend the previous ones (see `Bytecode::append`)
+    pub fn load(&mut self, instructions: Vec<Instruction>, debug_info: DebugInfo) {
+        self.instructions = instructions.into();
+        self.debug_info = debug_info;
+    }
+
+    pub fn run_from(&mut self, address: usize) -> Result<Option<Value>, Diagnostic> {
+        self.start(address);
+        self.execute()
+            .map_err(|message| self.runtime_error(message))
+    }
+
+    // calls a declared function with the given arguments, the call returns to
+    // one past the last instruction so execution stops once the function returns
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        self.start(self.instructions.len().saturating_sub(1));
+
+        let arg_count = args.len();
+        self.stack.extend(args);
+
+        self.call_function(name, arg_count, None)
+            .and_then(|()| self.execute())
+            .and_then(|_| self.pop())
+            .map_err(|message| self.runtime_error(message))
+    }
+
+    // calls a function value from inside an instruction and runs it until it
+    // returns, the program continues at the same instruction afterwards
+    fn call_value(&mut self, callee: &Value, args: Vec<Value>) -> Result<Value, String> {
+        let closure = match callee {
+            Value::Function(closure) => closure.clone(),
+            _ => return Err(format!("Cannot call {:?}", callee)),
+        };
+
+        let pc = self.pc;
+        let base_depth = std::mem::replace(&mut self.base_depth, self.call_stack.len() + 1);
+
+        let arg_count = args.len();
+        self.stack.extend(args);
+        let result = self
+            .call_function(&closure.name, arg_count, Some(closure.clone()))
+            .and_then(|()| self.execute());
+        self.base_depth = base_depth;
+
+        // on error the pc is kept for the location of the error
+        let value = result?.unwrap_or(Value::Void);
+        self.pc = pc;
+        Ok(value)
+    }
+
+    // `map`, `filter` and `reduce` of arrays call back into boo code, so they
+    // are run by the VM instead of as natives
+    fn array_function(
+        &mut self,
+        name: &str,
+        elements: &Rc<RefCell<Vec<Value>>>,
+        args: Vec<Value>,
+    ) -> Result<Value, String> {
+        // the function may change the array while it runs
+        let elements = elements.borrow().clone();
+        let mut args = args.into_iter();
+        let function = match args.next() {
+            Some(function) => function,
+            None => return Err(format!("Method '{}' requires a function", name)),
+        };
+
+        match name {
+            "map" => {
+                let mut mapped = Vec::with_capacity(elements.len());
+                for element in elements {
+                    mapped.push(self.call_value(&function, vec![element])?);
+                }
+                Ok(mapped.into())
+            }
+            "filter" => {
+                let mut kept = Vec::new();
+                for element in elements {
+                    match self.call_value(&function, vec![element.clone()])? {
+                        Value::Boolean(true) => kept.push(element),
+                        Value::Boolean(false) => (),
+                        value => return Err(format!("Filter function returned {:?}", value)),
+                    }
+                }
+                Ok(kept.into())
+            }
+            _ => {
+                let mut accumulator = match args.next() {
+                    Some(initial) => initial,
+                    None => return Err("Method 'reduce' requires an initial value".to_string()),
+                };
+                for element in elements {
+                    accumulator = self.call_value(&function, vec![accumulator, element])?;
+                }
+                Ok(accumulator)
+            }
+        }
+    }
+
+    fn cell(&self, slot: u16) -> Result<&Rc<RefCell<Value>>, String> {
+        match self.cells.get(slot as usize) {
+            Some(Some(cell)) => Ok(cell),
+            _ => Err(format!("Invalid cell slot {}", slot)),
+        }
+    }
+
+    fn global_cell(&self, index: u32) -> Result<&Rc<RefCell<Value>>, String> {
+        match self.global_cells.get(index as usize) {
+            Some(Some(cell)) => Ok(cell),
+            _ => Err(format!("Usage of undeclared global {}", index)),
+        }
+    }
+
+    fn upvalue(&self, index: u16) -> Result<&Rc<RefCell<Value>>, String> {
+        self.closure
+            .as_ref()
+            .and_then(|closure| closure.upvalues.get(index as usize))
+            .ok_or_else(|| format!("Invalid upvalue {}", index))
+    }
+
+    fn runtime_error(&mut self, message: String) -> Diagnostic {
+        let kind = match self.limit_hit.take() {
+            Some(limit) => DiagnosticKind::Limit(limit),
+            None => DiagnosticKind::Runtime,
+        };
+        let mut diagnostic = Diagnostic::new(kind, message, self.debug_info.span(self.pc));
+        diagnostic.trace = self.stack_trace();
+
+        // unwind to the top level so the VM can keep running
+        self.stack.clear();
+        self.call_stack.clear();
+        self.frame_pointer = 0;
+        self.cells.clear();
+        self.closure = None;
+        self.base_depth = 0;
+
+        diagnostic
+    }
+
+    // the running functions, innermost first, each at the instruction it was
+    // executing: the current one for the innermost, the call for the others
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        let mut trace = Vec::with_capacity(self.call_stack.len() + 1);
+        let mut address = self.pc;
+
+        for frame in self.call_stack.iter().rev() {
+            trace.push(TraceFrame {
+                function: frame.function.to_string(),
+                span: self.debug_info.span(address),
+            });
+            address = frame.return_address - 1;
+        }
+
+        // functions called by the host return past the end of the program,
+        // there is no top level code below them
+        if address + 1 < self.instructions.len() {
+            trace.push(TraceFrame {
+                function: "<main>".to_string(),
+                span: self.debug_info.span(address),
+            });
+        }
+
+        trace
     }
 
-    pub fn run(&mut self) -> Result<Option<Value>, String> {
-        self.pc = 0;
+    fn execute(&mut self) -> Result<Option<Value>, String> {
+        // a shared handle, so instructions are borrowed instead of cloned every step
+        let instructions = Rc::clone(&self.instructions);
 
-        while self.pc < self.instructions.len() {
-            let ix = self.instructions[self.pc].clone();
-            self.debug_print(format!("Executing instruction: {:?}", ix));
+        while self.pc < instructions.len() {
+            self.check_limits()?;
+
+            let ix = &instructions[self.pc];
+            self.debug_print(|| format!("Executing instruction: {:?}", ix));
 
             match ix {
                 // stack oeprations
                 Instruction::PushNumber(num) => {
-                    self.push(Value::Number(num));
+                    self.push(Value::Number(*num));
                 }
                 Instruction::PushString(string) => {
-                    self.push(Value::String(string));
+                    self.push(Value::String(string.clone()));
                 }
                 Instruction::PushBoolean(boolean) => {
-                    self.push(Value::Boolean(boolean));
+                    self.push(Value::Boolean(*boolean));
                 }
                 Instruction::PushVoid => {
                     self.push(Value::Void);
                 }
+                Instruction::PushNone => {
+                    self.push(Value::None);
+                }
                 Instruction::Pop => {
                     self.pop()?;
                 }
+                Instruction::Duplicate(count) => {
+                    let count = *count;
+                    if count > self.stack.len() {
+                        return Err("Stack underflow".to_string());
+                    }
+                    let start = self.stack.len() - count;
+                    self.stack.extend_from_within(start..);
+                }
                 Instruction::Negate => {
                     let value = self.pop()?;
                     match value {
@@ -165,41 +617,157 @@ impl VM {
                 }
 
                 // variable operations
-                Instruction::LoadVariable(name) => {
-                    let value = self.get_variable(&name)?;
+                Instruction::LoadLocal(slot) => {
+                    let value = match self.stack.get(self.frame_pointer + *slot as usize) {
+                        Some(value) => value.clone(),
+                        None => return Err(format!("Invalid local slot {}", slot)),
+                    };
                     self.push(value);
                 }
-                Instruction::StoreVariable(name) => {
-                    let value = self.pop()?;
+                Instruction::StoreLocal(slot) => {
+                    let index = self.frame_pointer + *slot as usize;
+                    let value = match self.stack.last() {
+                        Some(value) if index < self.stack.len() - 1 => value.clone(),
+                        _ => return Err(format!("Invalid local slot {}", slot)),
+                    };
+                    self.stack[index] = value;
+                }
+                Instruction::LoadGlobal(index) => {
+                    let value = match self.globals.get(*index as usize) {
+                        Some(value) => value.clone(),
+                        None => return Err(format!("Usage of undeclared global {}", index)),
+                    };
+                    self.push(value);
+                }
+                Instruction::StoreGlobal(index) => {
+                    let index = *index as usize;
+                    let value = match self.stack.last() {
+                        Some(value) => value.clone(),
+                        None => return Err("Stack underflow".to_string()),
+                    };
 
-                    // find and update variable in scopes
-                    let mut found = false;
-                    for scope in self.scopes.iter_mut().rev() {
-                        if scope.contains_key(&name) {
-                            scope.insert(name.clone(), value.clone());
-                            found = true;
-                            break;
-                        }
+                    // globals are created by their first store
+                    if index >= self.globals.len() {
+                        self.globals.resize(index + 1, Value::Void);
                     }
+                    self.globals[index] = value;
+                }
 
-                    if !found {
-                        return Err(format!("Assignment to undeclared variable '{}'", name));
+                // arrays
+                Instruction::MakeArray(count) => {
+                    if *count > self.stack.len() {
+                        return Err("Stack underflow".to_string());
+                    }
+                    let mut elements = VecDeque::with_capacity(*count);
+                    for _ in 0..*count {
+                        elements.push_front(self.pop()?);
+                    }
+                    self.push(Value::Array(Rc::new(RefCell::new(elements.into()))));
+                }
+                Instruction::LoadIndex => {
+                    let index = self.pop()?;
+                    let array = self.pop()?;
+
+                    match array {
+                        Value::Array(elements) => {
+                            let elements = elements.borrow();
+                            let i = array_index(&index, elements.len())?;
+                            let value = elements[i].clone();
+                            drop(elements);
+                            self.push(value);
+                        }
+                        _ => return Err(format!("Cannot index into {:?}", array)),
+                    }
+                }
+                Instruction::StoreIndex => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let array = self.pop()?;
+
+                    match array {
+                        Value::Array(elements) => {
+                            let mut elements = elements.borrow_mut();
+                            let i = array_index(&index, elements.len())?;
+                            elements[i] = value.clone();
+                        }
+                        _ => return Err(format!("Cannot index into {:?}", array)),
                     }
 
                     self.push(value);
                 }
-                Instruction::DeclareVariable(name, _type) => {
-                    let current_scope = self.current_scope();
 
-                    if current_scope.contains_key(&name) {
-                        return Err(format!(
-                            "Variable '{}' already declared in this scope",
-                            name
-                        ));
+                // structs
+                Instruction::DeclareStruct(name, fields) => {
+                    self.structs.insert(name.clone(), fields.clone());
+                }
+                Instruction::MakeStruct(name, field_names) => {
+                    let layout = match self.structs.get(name) {
+                        Some(layout) => layout.clone(),
+                        None => return Err(format!("Usage of undeclared struct '{}'", name)),
+                    };
+
+                    let mut values = HashMap::with_capacity(field_names.len());
+                    for field in field_names.iter().rev() {
+                        values.insert(field, self.pop()?);
+                    }
+
+                    // store the fields in declaration order
+                    let mut fields = Vec::with_capacity(layout.len());
+                    for field in layout {
+                        match values.remove(&field) {
+                            Some(value) => fields.push((field, value)),
+                            None => {
+                                return Err(format!(
+                                    "Missing field '{}' in initializer of struct '{}'",
+                                    field, name
+                                ))
+                            }
+                        }
+                    }
+
+                    self.push(Value::Struct(Rc::new(RefCell::new(StructInstance {
+                        name: name.clone(),
+                        fields,
+                    }))));
+                }
+                Instruction::LoadField(field) => {
+                    let object = self.pop()?;
+
+                    let value = match &object {
+                        Value::Struct(instance) => instance
+                            .borrow()
+                            .fields
+                            .iter()
+                            .find(|(name, _)| name == field)
+                            .map(|(_, value)| value.clone()),
+                        _ => None,
+                    };
+
+                    match value {
+                        Some(value) => self.push(value),
+                        None => return Err(format!("{:?} has no field named '{}'", object, field)),
+                    }
+                }
+                Instruction::StoreField(field) => {
+                    let value = self.pop()?;
+                    let object = self.pop()?;
+
+                    let stored = match &object {
+                        Value::Struct(instance) => instance
+                            .borrow_mut()
+                            .fields
+                            .iter_mut()
+                            .find(|(name, _)| name == field)
+                            .map(|(_, slot)| *slot = value.clone())
+                            .is_some(),
+                        _ => false,
+                    };
+
+                    if !stored {
+                        return Err(format!("{:?} has no field named '{}'", object, field));
                     }
 
-                    // this will be overwritten by the StoreVariable ix
-                    current_scope.insert(name, Value::Void);
+                    self.push(value);
                 }
 
                 // math
@@ -276,32 +844,46 @@ impl VM {
                         (Value::String(mut a), Value::String(b)) => {
                             a.reserve(b.len());
                             a.push_str(&b);
-                            self.push(Value::String(a));
+                            let result = Value::String(a);
+                            self.count_string(&result)?;
+                            self.push(result);
                         }
                         (Value::String(mut a), b) => {
                             let b_str = match b {
                                 Value::String(s) => s,
                                 Value::Boolean(b) => b.to_string(),
                                 Value::Number(n) => n.to_string(),
+                                Value::Array(_)
+                                | Value::Struct(_)
+                                | Value::Function(_)
+                                | Value::None => b.to_string(),
                                 _ => {
                                     return Err(format!("Cannot concatenate {:?} to string", b));
                                 }
                             };
                             a.push_str(&b_str);
-                            self.push(Value::String(a));
+                            let result = Value::String(a);
+                            self.count_string(&result)?;
+                            self.push(result);
                         }
                         (a, Value::String(b)) => {
                             let a_str = match a {
                                 Value::String(s) => s,
                                 Value::Boolean(b) => b.to_string(),
                                 Value::Number(n) => n.to_string(),
+                                Value::Array(_)
+                                | Value::Struct(_)
+                                | Value::Function(_)
+                                | Value::None => a.to_string(),
                                 _ => {
                                     return Err(format!("Cannot concatenate {:?} to string", a));
                                 }
                             };
                             let mut result = a_str;
                             result.push_str(&b);
-                            self.push(Value::String(result));
+                            let result = Value::String(result);
+                            self.count_string(&result)?;
+                            self.push(result);
                         }
                         _ => {
                             return Err("Type mismatch in concatenation".to_string());
@@ -324,6 +906,18 @@ impl VM {
                         (Value::Boolean(a), Value::Boolean(b)) => {
                             self.push(Value::Boolean(a == b));
                         }
+                        (Value::Array(a), Value::Array(b)) => {
+                            self.push(Value::Boolean(a == b));
+                        }
+                        (Value::Struct(a), Value::Struct(b)) => {
+                            self.push(Value::Boolean(a == b));
+                        }
+                        (Value::Function(a), Value::Function(b)) => {
+                            self.push(Value::Boolean(a == b));
+                        }
+                        // an optional value is compared with values of its type
+                        (Value::None, Value::None) => self.push(Value::Boolean(true)),
+                        (Value::None, _) | (_, Value::None) => self.push(Value::Boolean(false)),
                         _ => {
                             return Err("Type mismatch in equality comparison".to_string());
                         }
@@ -343,6 +937,17 @@ impl VM {
                         (Value::Boolean(a), Value::Boolean(b)) => {
                             self.push(Value::Boolean(a != b));
                         }
+                        (Value::Array(a), Value::Array(b)) => {
+                            self.push(Value::Boolean(a != b));
+                        }
+                        (Value::Struct(a), Value::Struct(b)) => {
+                            self.push(Value::Boolean(a != b));
+                        }
+                        (Value::Function(a), Value::Function(b)) => {
+                            self.push(Value::Boolean(a != b));
+                        }
+                        (Value::None, Value::None) => self.push(Value::Boolean(false)),
+                        (Value::None, _) | (_, Value::None) => self.push(Value::Boolean(true)),
                         _ => {
                             return Err("Type mismatch in equality comparison".to_string());
                         }
@@ -383,13 +988,13 @@ impl VM {
 
                 // control flow
                 Instruction::Jump(address) => {
-                    self.pc = address;
+                    self.pc = *address;
                     continue;
                 }
                 Instruction::JumpIfFalse(address) => {
                     if let Value::Boolean(condition) = self.pop()? {
                         if !condition {
-                            self.pc = address;
+                            self.pc = *address;
                             continue;
                         }
                     } else {
@@ -399,102 +1004,98 @@ impl VM {
                 Instruction::JumpIfTrue(address) => {
                     if let Value::Boolean(condition) = self.pop()? {
                         if condition {
-                            self.pc = address;
+                            self.pc = *address;
                             continue;
                         }
                     } else {
                         return Err("Non bool value in condition".to_string());
                     }
                 }
+                Instruction::JumpIfNotVoid(address) => {
+                    if !matches!(self.pop()?, Value::Void) {
+                        self.pc = *address;
+                        continue;
+                    }
+                }
 
                 // functions
-                Instruction::DeclareFunction(name, parameters, _return_type) => {
+                Instruction::DeclareFunction(name, parameters, slot_count) => {
                     let mut body_address = self.pc + 1;
-                    if body_address < self.instructions.len() {
-                        if let Instruction::Jump(_) = self.instructions[body_address] {
+                    if body_address < instructions.len() {
+                        if let Instruction::Jump(_) = instructions[body_address] {
                             body_address += 1;
                         }
                     }
 
                     self.functions.insert(
-                        name,
+                        name.clone(),
                         Function {
-                            parameters,
+                            name: name.as_str().into(),
+                            required_parameters: parameters.iter().filter(|p| !p.optional).count(),
+                            parameters: parameters.len(),
+                            slot_count: *slot_count,
                             address: body_address,
                         },
                     );
                 }
                 Instruction::Call(name, arg_count) => {
-                    // check for native functions
-                    if self.native_functions.contains_key(&name) {
-                        let native_fn = self.native_functions.get(&name).unwrap().clone();
+                    let arg_count = *arg_count;
 
-                        let mut args = Vec::with_capacity(arg_count);
-                        for _ in 0..arg_count {
-                            let value = self.pop()?;
-                            args.insert(0, value);
+                    // check for native functions
+                    if let Some(native) = self.natives.function(name) {
+                        if arg_count > self.stack.len() {
+                            return Err("Stack underflow".to_string());
                         }
+                        let args = self.stack.split_off(self.stack.len() - arg_count);
 
                         // call the native function
-                        let result = native_fn(self, args)?;
+                        let result = (native.function)(args)?;
+                        self.count_string(&result)?;
                         self.push(result);
                         self.pc += 1;
                         continue;
                     }
 
-                    let function = match self.functions.get(&name) {
-                        Some(f) => f.clone(),
-                        None => return Err(format!("Usage of undeclared function '{}'", name)),
-                    };
-
-                    // check arg count
-                    let required_args = function.parameters.iter().filter(|p| p.optional).count();
-
-                    if arg_count < required_args || arg_count > function.parameters.len() {
-                        return Err(format!(
-                            "Function '{}' requires {} arguments, but {} were provided",
-                            name, required_args, arg_count
-                        ));
-                    }
-
-                    // create a new scope for the function
-                    self.scopes.push(HashMap::new());
-                    let scope_index = self.scopes.len() - 1;
-
-                    // create new call frame
-                    let mut cf = CallFrame {
-                        return_address: self.pc + 1,
-                        variables: HashMap::new(),
-                        scope_index,
+                    // the arguments stay on the stack as the callee's first slots
+                    self.call_function(name, arg_count, None)?;
+                    continue;
+                }
+                Instruction::CallValue(arg_count) => {
+                    let arg_count = *arg_count;
+                    let callee = match self.stack.len().checked_sub(arg_count + 1) {
+                        Some(index) => self.stack.remove(index),
+                        None => return Err("Stack underflow".to_string()),
                     };
 
-                    // pop arguments in reverse (last arg first)
-                    let mut args = VecDeque::with_capacity(arg_count);
-                    for _ in 0..arg_count {
-                        args.push_front(self.pop()?);
-                    }
-
-                    // bind args to function parameters
-                    for (i, param) in function.parameters.iter().enumerate() {
-                        if i < args.len() {
-                            self.scopes[scope_index].insert(param.name.clone(), args[i].clone());
-                            cf.variables.insert(param.name.clone(), args[i].clone());
-                        } else {
-                            // optional parameters are set to void
-                            self.scopes[scope_index].insert(param.name.clone(), Value::Void);
-                            cf.variables.insert(param.name.clone(), Value::Void);
+                    match callee {
+                        Value::Function(closure) => {
+                            let name = closure.name.clone();
+                            self.call_function(&name, arg_count, Some(closure))?;
+                            continue;
                         }
+                        _ => return Err(format!("Cannot call {:?}", callee)),
                     }
-
-                    // save call frame
-                    self.call_stack.push(cf);
-
-                    // jump to function body
-                    self.pc = function.address;
-                    continue;
                 }
                 Instruction::CallMethod(name, arg_count) => {
+                    let arg_count = *arg_count;
+
+                    // user methods are functions taking the instance as `self`, it
+                    // is already on the stack right below the arguments
+                    if let Some(Value::Struct(instance)) = self
+                        .stack
+                        .len()
+                        .checked_sub(arg_count + 1)
+                        .and_then(|i| self.stack.get(i))
+                    {
+                        let function_name = method_function_name(&instance.borrow().name, name);
+                        self.call_function(&function_name, arg_count + 1, None)?;
+                        continue;
+                    }
+
                     // collect arguments
+                    if arg_count >= self.stack.len() {
+                        return Err("Stack underflow".to_string());
+                    }
                     let mut args = Vec::with_capacity(arg_count);
                     for _ in 0..arg_count {
                         let value = self.pop()?;
@@ -504,39 +1105,33 @@ impl VM {
                     // get the object
                     let object = self.pop()?;
 
-                    // add the object as the first argument for our native method handler
-                    let mut full_args = vec![object.clone()];
-                    full_args.extend(args);
-
-                    match object {
-                        Value::String(_) => {
-                            if self.string_methods.contains_key(&name) {
-                                let native_fn = self.string_methods.get(&name).unwrap().clone();
-                                let result = native_fn(self, full_args)?;
-                                self.push(result);
-                                self.pc += 1;
-                                continue;
-                            }
-                        }
-                        Value::Number(_) => {
-                            if self.number_methods.contains_key(&name) {
-                                let native_fn = self.number_methods.get(&name).unwrap().clone();
-                                let result = native_fn(self, full_args)?;
-                                self.push(result);
-                                self.pc += 1;
-                                continue;
-                            }
+                    let native = Receiver::of_value(&object)
+                        .and_then(|receiver| self.natives.method(receiver, name));
+                    match (native, &object) {
+                        (Some(native), Value::Array(elements)) if native.calls_back => {
+                            let result = self.array_function(name, elements, args)?;
+                            self.push(result);
+                            self.pc += 1;
+                            continue;
                         }
-                        Value::Boolean(_) => {
-                            if self.boolean_methods.contains_key(&name) {
-                                let native_fn = self.boolean_methods.get(&name).unwrap().clone();
-                                let result = native_fn(self, full_args)?;
-                                self.push(result);
-                                self.pc += 1;
-                                continue;
+                        (Some(native), _) => {
+                            // add the object as the first argument for our native method handler
+                            let mut full_args = vec![object.clone()];
+                            full_args.extend(args);
+
+                            let function = native.function.clone();
+                            if let Some(bytes) =
+                                native.string_size.and_then(|size| size(&full_args))
+                            {
+                                self.check_string_room(bytes)?;
                             }
+                            let result = function(full_args)?;
+                            self.count_string(&result)?;
+                            self.push(result);
+                            self.pc += 1;
+                            continue;
                         }
-                        _ => {
+                        (None, _) => {
                             return Err(format!("Cannot call method '{}' on {:?}", name, object));
                         }
                     }
@@ -550,9 +1145,15 @@ impl VM {
 
                     // check if were in a function call frame
                     if let Some(cf) = self.call_stack.pop() {
-                        // make sure we pop exactly the scope associated with this call frame
-                        while self.scopes.len() > cf.scope_index {
-                            self.scopes.pop();
+                        // drop the frame of the function and restore the caller's
+                        self.stack.truncate(self.frame_pointer);
+                        self.frame_pointer = cf.frame_pointer;
+                        self.cells = cf.cells;
+                        self.closure = cf.closure;
+
+                        // the function was called by `call_value`
+                        if self.call_stack.len() < self.base_depth {
+                            return Ok(Some(return_value));
                         }
 
                         // jump back to caller
@@ -568,15 +1169,72 @@ impl VM {
                     }
                 }
 
-                // environment
-                Instruction::EnterScope => {
-                    self.scopes.push(HashMap::new());
+                // closures
+                Instruction::MakeCell(slot) => {
+                    let value = self.pop()?;
+                    let slot = *slot as usize;
+                    if slot >= self.cells.len() {
+                        self.cells.resize(slot + 1, None);
+                    }
+                    self.cells[slot] = Some(Rc::new(RefCell::new(value)));
+                }
+                Instruction::LoadCell(slot) => {
+                    let value = self.cell(*slot)?.borrow().clone();
+                    self.push(value);
                 }
-                Instruction::ExitScope => {
-                    self.scopes.pop();
-                    if self.scopes.is_empty() {
-                        self.scopes.push(HashMap::new()); // keep global scope
+                Instruction::StoreCell(slot) => {
+                    let value = match self.stack.last() {
+                        Some(value) => value.clone(),
+                        None => return Err("Stack underflow".to_string()),
+                    };
+                    *self.cell(*slot)?.borrow_mut() = value;
+                }
+                Instruction::MakeGlobalCell(index) => {
+                    let value = self.pop()?;
+                    let index = *index as usize;
+                    if index >= self.global_cells.len() {
+                        self.global_cells.resize(index + 1, None);
                     }
+                    self.global_cells[index] = Some(Rc::new(RefCell::new(value)));
+                }
+                Instruction::LoadGlobalCell(index) => {
+                    let value = self.global_cell(*index)?.borrow().clone();
+                    self.push(value);
+                }
+                Instruction::StoreGlobalCell(index) => {
+                    let value = match self.stack.last() {
+                        Some(value) => value.clone(),
+                        None => return Err("Stack underflow".to_string()),
+                    };
+                    *self.global_cell(*index)?.borrow_mut() = value;
+                }
+                Instruction::LoadUpvalue(index) => {
+                    let value = self.upvalue(*index)?.borrow().clone();
+                    self.push(value);
+                }
+                Instruction::StoreUpvalue(index) => {
+                    let value = match self.stack.last() {
+                        Some(value) => value.clone(),
+                        None => return Err("Stack underflow".to_string()),
+                    };
+                    *self.upvalue(*index)?.borrow_mut() = value;
+                }
+                Instruction::MakeClosure(name, function_type, captures) => {
+                    let mut upvalues = Vec::with_capacity(captures.len());
+                    for capture in captures {
+                        let cell = match capture {
+                            Capture::Cell(slot) => self.cell(*slot)?,
+                            Capture::Upvalue(index) => self.upvalue(*index)?,
+                            Capture::GlobalCell(index) => self.global_cell(*index)?,
+                        };
+                        upvalues.push(cell.clone());
+                    }
+
+                    self.push(Value::Function(Rc::new(Closure {
+                        name: name.as_str().into(),
+                        function_type: function_type.clone(),
+                        upvalues,
+                    })));
                 }
 
                 // end program
@@ -595,3 +1253,41 @@ impl VM {
         Ok(None)
     }
 }
+
+// the bytes of the strings in `value`, a string in an array also takes its
+// slot in the array
+fn string_bytes(value: &Value) -> usize {
+    match value {
+        Value::String(string) => string.len(),
+        Value::Array(elements) => elements
+            .borrow()
+            .iter()
+            .map(|element| match element {
+                Value::String(string) => string.len() + std::mem::size_of::<Value>(),
+                _ => 0,
+            })
+            .sum(),
+        _ => 0,
+    }
+}
+
+// converts an index value into a position inside an array of length `len`
+fn array_index(index: &Value, len: usize) -> Result<usize, String> {
+    match index {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 => {
+            let i = *n as usize;
+            if i >= len {
+                return Err(format!(
+                    "Index {} out of bounds for array of length {}",
+                    i, len
+                ));
+            }
+            Ok(i)
+        }
+        Value::Number(n) => Err(format!(
+            "Array index must be a non-negative integer, found {}",
+            n
+        )),
+        _ => Err(format!("Array index must be a number, found {:?}", index)),
+    }
+}
@@ -0,0 +1,71 @@
+// runs examples/folding.boo at -O0 and -O1, folding must not change what any
+// of its tests does
+use std::{fs, path::PathBuf};
+
+use boo_lang::{Engine, OptLevel, Program, Value};
+
+fn example() -> (PathBuf, String) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/folding.boo");
+    let source = fs::read_to_string(&path).expect("examples/folding.boo is readable");
+    (path, source)
+}
+
+// the names of the `test "name" { ... }` blocks, in the order they are declared
+fn test_names(source: &str) -> Vec<&str> {
+    source
+        .lines()
+        .filter_map(|line| line.strip_prefix("test \"")?.split('"').next())
+        .collect()
+}
+
+// compiles the file with the test called `name` and runs it
+fn run_test(
+    source: &str,
+    name: &str,
+    opt_level: OptLevel,
+) -> (usize, Result<Option<Value>, String>) {
+    let (path, _) = example();
+    let mut engine = Engine::new();
+    engine.set_opt_level(opt_level);
+    engine.set_test(Some(name));
+
+    let program = engine
+        .compile_file(&path, source)
+        .unwrap_or_else(|error| panic!("{}", error.render("folding.boo", source)));
+    let result = engine
+        .run(&program)
+        .map_err(|error| error.render("folding.boo", source));
+    (instruction_count(&program), result)
+}
+
+// instruction lines start with their address, labels and markers are indented
+fn instruction_count(program: &Program) -> usize {
+    program
+        .disassemble()
+        .lines()
+        .filter(|line| line.starts_with(|c: char| c.is_ascii_digit()))
+        .count()
+}
+
+#[test]
+fn tests_give_the_same_results_at_both_levels() {
+    let (_, source) = example();
+    let names = test_names(&source);
+    assert!(!names.is_empty(), "examples/folding.boo declares no tests");
+
+    for name in names {
+        let (unoptimized_size, unoptimized) = run_test(&source, name, OptLevel::O0);
+        let (optimized_size, optimized) = run_test(&source, name, OptLevel::O1);
+
+        assert_eq!(unoptimized, optimized, "test \"{}\" differs", name);
+        if let Err(error) = optimized {
+            panic!("test \"{}\" failed:\n{}", name, error);
+        }
+        // every test has something to fold
+        assert!(
+            optimized_size < unoptimized_size,
+            "test \"{}\" was not folded",
+            name
+        );
+    }
+}